@@ -0,0 +1,388 @@
+use bincode::{deserialize, serialize};
+use rand::{rngs::OsRng, seq::SliceRandom, Rng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use super::*;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    net::IpAddr,
+    path::Path,
+};
+
+// where known addresses are saved, under the node's data directory
+const PEERS_FILE: &str = "peers.dat";
+
+// addresses heard about but never connected to
+const NEW_BUCKET_COUNT: usize = 64;
+// addresses we had a working session with
+const TRIED_BUCKET_COUNT: usize = 16;
+const BUCKET_SIZE: usize = 32;
+// tried buckets one address group can spread over
+const TRIED_BUCKETS_PER_GROUP: u64 = 4;
+
+// share of the table, with a floor and a cap, returned to a getaddr
+const GETADDR_PERCENT: usize = 23;
+const GETADDR_MIN: usize = 10;
+pub const MAX_ADDR_TO_SEND: usize = 1000;
+
+// seconds after which an address nobody mentioned is dropped
+const HORIZON: i64 = 30 * 24 * 60 * 60;
+// failed attempts before a never reached address is given up
+const RETRIES: u32 = 3;
+// failed attempts before a once good address is given up after MIN_FAIL
+const MAX_FAILURES: u32 = 10;
+const MIN_FAIL: i64 = 7 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddrInfo {
+    pub addr: String,
+    // the peer that told us about it
+    pub source: String,
+    pub last_seen: i64,
+    pub last_success: i64,
+    pub last_attempt: i64,
+    pub attempts: u32,
+    pub tried: bool,
+    bucket: usize,
+}
+
+impl AddrInfo {
+    /// Whether the address isn't worth keeping or handing out any more.
+    fn is_terrible(&self, now: i64) -> bool {
+        // just tried, give it a chance to come up
+        if self.last_attempt >= now - 60 {
+            return false;
+        }
+        if self.last_seen > now + 10 * 60 || now - self.last_seen > HORIZON {
+            return true;
+        }
+        if self.last_success == 0 && self.attempts >= RETRIES {
+            return true;
+        }
+        now - self.last_success > MIN_FAIL && self.attempts >= MAX_FAILURES
+    }
+
+    /// Relative odds of picking the address, lower after failures and
+    /// right after an attempt.
+    fn chance(&self, now: i64) -> f64 {
+        let mut chance = 0.66f64.powi(self.attempts.min(8) as i32);
+        if now - self.last_attempt < 10 * 60 {
+            chance *= 0.01;
+        }
+        chance
+    }
+}
+
+/// Known peer addresses, split into a new and a tried table. Where an
+/// address lands depends on a secret key and its network group, so a single
+/// source can only fill a few buckets of the table.
+pub struct AddrMan {
+    key: u64,
+    addrs: HashMap<String, AddrInfo>,
+    // file `save` writes and `load` reads
+    file: String,
+}
+
+impl AddrMan {
+    /// An empty address table saved under the data directory `dir`.
+    pub fn new(dir: &str) -> AddrMan {
+        AddrMan {
+            key: OsRng.next_u64(),
+            addrs: HashMap::new(),
+            file: format!("{}/{}", dir, PEERS_FILE),
+        }
+    }
+
+    pub fn load(&mut self) -> Result<()> {
+        if !Path::new(&self.file).exists() {
+            return Ok(());
+        }
+        let (key, entries): (u64, Vec<AddrInfo>) = deserialize(&fs::read(&self.file)?)?;
+        self.key = key;
+        for info in entries {
+            self.addrs.insert(info.addr.clone(), info);
+        }
+        info!("load peer addresses: {}", self.addrs.len());
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let entries: Vec<&AddrInfo> = self.addrs.values().collect();
+        fs::write(&self.file, serialize(&(self.key, entries))?)?;
+        Ok(())
+    }
+
+    /// Records addresses announced by `source` at `now` in the new table.
+    pub fn add(&mut self, addrs: &[String], source: &str, now: i64) {
+        for addr in addrs {
+            if !is_valid(addr) {
+                continue;
+            }
+            if let Some(info) = self.addrs.get_mut(addr) {
+                info.last_seen = info.last_seen.max(now);
+                continue;
+            }
+            self.place_new(
+                AddrInfo {
+                    addr: addr.clone(),
+                    source: source.to_string(),
+                    last_seen: now,
+                    last_success: 0,
+                    last_attempt: 0,
+                    attempts: 0,
+                    tried: false,
+                    bucket: 0,
+                },
+                now,
+            );
+        }
+    }
+
+    pub fn attempt(&mut self, addr: &str, now: i64) {
+        if let Some(info) = self.addrs.get_mut(addr) {
+            info.attempts += 1;
+            info.last_attempt = now;
+        }
+    }
+
+    /// Marks a completed handshake, moving the address to the tried table.
+    pub fn good(&mut self, addr: &str, now: i64) {
+        let mut info = match self.addrs.remove(addr) {
+            Some(info) => info,
+            None => return,
+        };
+        info.last_success = now;
+        info.last_seen = now;
+        info.attempts = 0;
+        if info.tried {
+            self.addrs.insert(info.addr.clone(), info);
+            return;
+        }
+
+        let bucket = self.tried_bucket(&info.addr);
+        let members = self.bucket_members(true, bucket);
+        if members.len() >= BUCKET_SIZE {
+            // the longest unused tried entry goes back to the new table
+            let oldest = members
+                .into_iter()
+                .min_by_key(|addr| self.addrs[addr].last_success)
+                .unwrap();
+            let old = self.addrs.remove(&oldest).unwrap();
+            self.place_new(
+                AddrInfo {
+                    tried: false,
+                    ..old
+                },
+                now,
+            );
+        }
+        info.tried = true;
+        info.bucket = bucket;
+        self.addrs.insert(info.addr.clone(), info);
+    }
+
+    /// Updates the last seen time of a peer we were connected to.
+    pub fn seen(&mut self, addr: &str, now: i64) {
+        if let Some(info) = self.addrs.get_mut(addr) {
+            info.last_seen = now;
+        }
+    }
+
+    /// Picks an address to dial that isn't in `exclude`, tried and new
+    /// tables equally likely, favouring addresses that didn't fail lately.
+    pub fn select(&self, exclude: &HashSet<String>, now: i64) -> Option<String> {
+        let (tried, new): (Vec<&AddrInfo>, Vec<&AddrInfo>) = self
+            .addrs
+            .values()
+            .filter(|info| !exclude.contains(&info.addr))
+            .partition(|info| info.tried);
+
+        let mut rng = OsRng;
+        let table = match (tried.is_empty(), new.is_empty()) {
+            (true, true) => return None,
+            (false, true) => tried,
+            (true, false) => new,
+            (false, false) => {
+                if rng.gen_bool(0.5) {
+                    tried
+                } else {
+                    new
+                }
+            }
+        };
+
+        let mut factor = 1.0;
+        loop {
+            let info = table.choose(&mut rng).unwrap();
+            if rng.gen::<f64>() < factor * info.chance(now) {
+                return Some(info.addr.clone());
+            }
+            factor *= 1.2;
+        }
+    }
+
+    /// A random sample of the usable addresses to answer a getaddr with.
+    pub fn get_addr(&self, now: i64) -> Vec<String> {
+        let mut addrs: Vec<String> = self
+            .addrs
+            .values()
+            .filter(|info| !info.is_terrible(now))
+            .map(|info| info.addr.clone())
+            .collect();
+        let count = (addrs.len() * GETADDR_PERCENT / 100)
+            .max(addrs.len().min(GETADDR_MIN))
+            .min(MAX_ADDR_TO_SEND);
+        addrs.shuffle(&mut OsRng);
+        addrs.truncate(count);
+        addrs
+    }
+
+    // --------- getter ---------
+
+    pub fn len(&self) -> usize {
+        self.addrs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addrs.is_empty()
+    }
+
+    /* -------------------- inner function -------------------- */
+
+    /// Puts `info` in its new bucket, evicting the worst entry when the
+    /// bucket is full.
+    fn place_new(&mut self, mut info: AddrInfo, now: i64) {
+        let bucket = self.new_bucket(&info.addr, &info.source);
+        let members = self.bucket_members(false, bucket);
+        if members.len() >= BUCKET_SIZE {
+            let worst = members
+                .into_iter()
+                .max_by_key(|addr| {
+                    let other = &self.addrs[addr];
+                    (other.is_terrible(now), -other.last_seen)
+                })
+                .unwrap();
+            self.addrs.remove(&worst);
+        }
+        info.bucket = bucket;
+        self.addrs.insert(info.addr.clone(), info);
+    }
+
+    fn bucket_members(&self, tried: bool, bucket: usize) -> Vec<String> {
+        self.addrs
+            .values()
+            .filter(|info| info.tried == tried && info.bucket == bucket)
+            .map(|info| info.addr.clone())
+            .collect()
+    }
+
+    fn new_bucket(&self, addr: &str, source: &str) -> usize {
+        (self.hash(&format!("{}/{}", group(source), group(addr))) % NEW_BUCKET_COUNT as u64)
+            as usize
+    }
+
+    fn tried_bucket(&self, addr: &str) -> usize {
+        let slot = self.hash(addr) % TRIED_BUCKETS_PER_GROUP;
+        (self.hash(&format!("{}/{}", group(addr), slot)) % TRIED_BUCKET_COUNT as u64) as usize
+    }
+
+    fn hash(&self, data: &str) -> u64 {
+        let digest = sha256::digest(format!("{}:{}", self.key, data));
+        u64::from_str_radix(&digest[..16], 16).unwrap()
+    }
+}
+
+/// `host:port` with a non-zero port.
+fn is_valid(addr: &str) -> bool {
+    match addr.rsplit_once(':') {
+        Some((host, port)) => !host.is_empty() && matches!(port.parse::<u16>(), Ok(p) if p > 0),
+        None => false,
+    }
+}
+
+/// Network group of an address: the /16 for IPv4, the /32 for IPv6 and the
+/// host name otherwise.
+fn group(addr: &str) -> String {
+    let host = match addr.rsplit_once(':') {
+        Some((host, _)) => host.trim_start_matches('[').trim_end_matches(']'),
+        None => addr,
+    };
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            let octets = ip.octets();
+            format!("{}.{}", octets[0], octets[1])
+        }
+        Ok(IpAddr::V6(ip)) => {
+            let segments = ip.segments();
+            format!("{:x}:{:x}", segments[0], segments[1])
+        }
+        Err(_) => host.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000;
+
+    /// `count` addresses in the 10.1.0.0/16 group.
+    fn one_group(count: usize) -> Vec<String> {
+        (0..count)
+            .map(|i| format!("10.1.{}.{}:8333", i / 250, i % 250 + 1))
+            .collect()
+    }
+
+    #[test]
+    fn invalid_addresses_are_ignored() {
+        let mut addrman = AddrMan::new("unused");
+        let addrs = ["nohost", ":8333", "10.0.0.1:0", "10.0.0.1:8333"].map(String::from);
+        addrman.add(&addrs, "10.9.0.1:8333", NOW);
+        assert_eq!(addrman.len(), 1);
+    }
+
+    #[test]
+    fn one_source_fills_one_new_bucket_per_group() {
+        let mut addrman = AddrMan::new("unused");
+        addrman.add(&one_group(1000), "10.9.0.1:8333", NOW);
+        assert_eq!(addrman.len(), BUCKET_SIZE);
+    }
+
+    #[test]
+    fn one_group_fills_a_few_tried_buckets() {
+        let mut addrman = AddrMan::new("unused");
+        let addrs = one_group(1000);
+        for addr in &addrs {
+            // moved on before the new bucket they share fills up
+            addrman.add(std::slice::from_ref(addr), "10.9.0.1:8333", NOW);
+            addrman.good(addr, NOW);
+        }
+        let tried = addrman.addrs.values().filter(|info| info.tried).count();
+        assert!(tried <= TRIED_BUCKETS_PER_GROUP as usize * BUCKET_SIZE);
+        assert!(addrman.len() > tried);
+    }
+
+    #[test]
+    fn failing_addresses_are_not_handed_out() {
+        let mut addrman = AddrMan::new("unused");
+        let addrs = one_group(2);
+        addrman.add(&addrs, "10.9.0.1:8333", NOW);
+        for _ in 0..RETRIES {
+            addrman.attempt(&addrs[0], NOW - 3600);
+        }
+        assert_eq!(addrman.get_addr(NOW), [addrs[1].clone()]);
+    }
+
+    #[test]
+    fn selection_skips_excluded_addresses() {
+        let mut addrman = AddrMan::new("unused");
+        let addrs = one_group(2);
+        addrman.add(&addrs, "10.9.0.1:8333", NOW);
+
+        let exclude = HashSet::from([addrs[0].clone()]);
+        assert_eq!(addrman.select(&exclude, NOW), Some(addrs[1].clone()));
+        let exclude: HashSet<String> = addrs.into_iter().collect();
+        assert_eq!(addrman.select(&exclude, NOW), None);
+    }
+}
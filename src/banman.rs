@@ -0,0 +1,121 @@
+use bincode::{deserialize, serialize};
+use serde::{Deserialize, Serialize};
+
+use super::*;
+use std::{collections::HashMap, fs, path::Path};
+
+// where the ban list is saved, under the node's data directory
+const BANLIST_FILE: &str = "banlist.dat";
+// seconds a ban lasts unless told otherwise
+pub const DEFAULT_BAN_TIME: i64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BanEntry {
+    pub ip: String,
+    pub created: i64,
+    pub until: i64,
+    pub reason: String,
+}
+
+/// Time-limited bans keyed by peer IP, kept on disk across restarts.
+pub struct BanManager {
+    bans: HashMap<String, BanEntry>,
+    // file `save` writes and `load` reads
+    file: String,
+}
+
+impl BanManager {
+    /// An empty ban list saved under the data directory `dir`.
+    pub fn new(dir: &str) -> BanManager {
+        BanManager {
+            bans: HashMap::new(),
+            file: format!("{}/{}", dir, BANLIST_FILE),
+        }
+    }
+
+    /// Reads the saved bans, dropping those run out by `now`.
+    pub fn load(&mut self, now: i64) -> Result<()> {
+        if !Path::new(&self.file).exists() {
+            return Ok(());
+        }
+        let entries: Vec<BanEntry> = deserialize(&fs::read(&self.file)?)?;
+        for entry in entries {
+            self.bans.insert(entry.ip.clone(), entry);
+        }
+        self.sweep(now);
+        info!("load ban list: {} entries", self.bans.len());
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let entries: Vec<&BanEntry> = self.bans.values().collect();
+        fs::write(&self.file, serialize(&entries)?)?;
+        Ok(())
+    }
+
+    /// Bans `ip` from `now` for `ban_time` seconds.
+    pub fn ban(&mut self, ip: &str, ban_time: i64, reason: &str, now: i64) -> Result<()> {
+        info!("ban {} for {}s: {}", ip, ban_time, reason);
+        self.bans.insert(
+            ip.to_string(),
+            BanEntry {
+                ip: ip.to_string(),
+                created: now,
+                until: now + ban_time,
+                reason: reason.to_string(),
+            },
+        );
+        self.save()
+    }
+
+    pub fn unban(&mut self, ip: &str) -> Result<bool> {
+        let removed = self.bans.remove(ip).is_some();
+        self.save()?;
+        Ok(removed)
+    }
+
+    pub fn clear(&mut self) -> Result<()> {
+        self.bans.clear();
+        self.save()
+    }
+
+    pub fn is_banned(&mut self, ip: &str, now: i64) -> bool {
+        self.sweep(now);
+        self.bans.contains_key(ip)
+    }
+
+    pub fn list(&mut self, now: i64) -> Vec<BanEntry> {
+        self.sweep(now);
+        let mut entries: Vec<BanEntry> = self.bans.values().cloned().collect();
+        entries.sort_by_key(|entry| entry.until);
+        entries
+    }
+
+    /// Drops bans that have run out by `now`.
+    fn sweep(&mut self, now: i64) {
+        self.bans.retain(|_, entry| entry.until > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn ban_runs_out_at_the_time_given() {
+        let dir = env::temp_dir().join(format!("blockchain_rust_ban_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut banman = BanManager::new(dir.to_str().unwrap());
+
+        banman.ban("10.0.0.1", 60, "test", 1000).unwrap();
+        assert!(banman.is_banned("10.0.0.1", 1059));
+        assert!(!banman.is_banned("10.0.0.2", 1059));
+
+        let mut loaded = BanManager::new(dir.to_str().unwrap());
+        loaded.load(1000).unwrap();
+        assert_eq!(loaded.list(1000).len(), 1);
+        assert!(!loaded.is_banned("10.0.0.1", 1060));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -9,6 +9,42 @@ use serde::{Deserialize, Serialize};
 use sha256;
 
 const TARGET_HEXS: usize = 4;
+// nonces tried between two checks whether mining should stop
+const STOP_CHECK_INTERVAL: i32 = 1000;
+
+/// The part of a block its proof of work covers, with the transactions
+/// committed to through `tx_root`, so the chain can be checked before the
+/// bodies are downloaded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub timestamp: u128,
+    pub prev_block_hash: String,
+    pub tx_root: String,
+    pub hash: String,
+    pub nonce: i32,
+    pub height: i32,
+}
+
+impl BlockHeader {
+    pub fn prepare_hash_data(&self) -> Result<Vec<u8>> {
+        let content = (
+            self.prev_block_hash.clone(),
+            self.tx_root.clone(),
+            self.timestamp,
+            TARGET_HEXS,
+            self.nonce,
+            self.height,
+        );
+        let data = serialize(&content)?;
+        Ok(data)
+    }
+
+    /// Checks the proof of work and that `hash` matches the header.
+    pub fn verify(&self) -> Result<bool> {
+        let hash = sha256::digest(self.prepare_hash_data()?);
+        Ok(hash[..TARGET_HEXS] == "0".repeat(TARGET_HEXS) && hash == self.hash)
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
@@ -26,17 +62,38 @@ impl Block {
         prev_block_hash: String,
         height: i32,
     ) -> Result<Block> {
-        let timestamp = Utc::now().timestamp_millis() as u128;
-        let mut block = Block {
-            timestamp,
+        let mut block = Block::new_unsolved(transactions, prev_block_hash, height);
+        block.proof_of_work()?;
+        Ok(block)
+    }
+
+    /// A block without its proof of work yet.
+    pub fn new_unsolved(
+        transactions: Vec<Transaction>,
+        prev_block_hash: String,
+        height: i32,
+    ) -> Block {
+        Block {
+            timestamp: Utc::now().timestamp_millis() as u128,
             transactions,
             prev_block_hash,
             hash: String::new(),
             nonce: 0,
             height,
-        };
-        block.proof_of_work()?;
-        Ok(block)
+        }
+    }
+
+    /// Puts a block back together from its header and transactions, as
+    /// received in pieces. `verify` tells whether they match.
+    pub fn from_header(header: &BlockHeader, transactions: Vec<Transaction>) -> Block {
+        Block {
+            timestamp: header.timestamp,
+            transactions,
+            prev_block_hash: header.prev_block_hash.clone(),
+            hash: header.hash.clone(),
+            nonce: header.nonce,
+            height: header.height,
+        }
     }
 
     pub fn new_genesis_block(coinbase: Transaction) -> Block {
@@ -44,29 +101,36 @@ impl Block {
     }
 
     pub fn proof_of_work(&mut self) -> Result<()> {
+        self.proof_of_work_until(|| false)?;
+        Ok(())
+    }
+
+    /// Like `proof_of_work`, but gives up once `stop` returns true. Returns
+    /// whether the block was solved.
+    pub fn proof_of_work_until<F: Fn() -> bool>(&mut self, stop: F) -> Result<bool> {
         info!("Mining the block containing \"{:#?}\"\n", self.transactions);
 
-        while !self.validate()? {
-            self.nonce += 1;
+        // the transactions are fixed, only the nonce changes
+        let mut header = self.header()?;
+        loop {
+            if header.nonce % STOP_CHECK_INTERVAL == 0 && stop() {
+                return Ok(false);
+            }
+            header.hash = sha256::digest(header.prepare_hash_data()?);
+            if header.hash[..TARGET_HEXS] == "0".repeat(TARGET_HEXS) {
+                break;
+            }
+            header.nonce += 1;
         }
 
-        let data = self.prepare_hash_data()?;
-        let hash = sha256::digest(data);
-        self.hash = hash;
+        self.nonce = header.nonce;
+        self.hash = header.hash;
 
-        Ok(())
+        Ok(true)
     }
 
     pub fn prepare_hash_data(&self) -> Result<Vec<u8>> {
-        let content = (
-            self.prev_block_hash.clone(),
-            self.transactions.clone(),
-            self.timestamp,
-            TARGET_HEXS,
-            self.nonce,
-        );
-        let data = serialize(&content)?;
-        Ok(data)
+        self.header()?.prepare_hash_data()
     }
 
     pub fn validate(&self) -> Result<bool> {
@@ -77,6 +141,40 @@ impl Block {
         Ok(&sha256::digest(data)[..TARGET_HEXS] == String::from_utf8(vec)?)
     }
 
+    /// Checks the proof of work and that the stored hash matches the content.
+    pub fn verify(&self) -> Result<bool> {
+        self.header()?.verify()
+    }
+
+    pub fn header(&self) -> Result<BlockHeader> {
+        Ok(BlockHeader {
+            timestamp: self.timestamp,
+            prev_block_hash: self.prev_block_hash.clone(),
+            tx_root: self.tx_root()?,
+            hash: self.hash.clone(),
+            nonce: self.nonce,
+            height: self.height,
+        })
+    }
+
+    /// Merkle root over the hashes of the serialized transactions.
+    pub fn tx_root(&self) -> Result<String> {
+        let mut level = Vec::new();
+        for tx in &self.transactions {
+            level.push(sha256::digest(serialize(tx)?));
+        }
+        while level.len() > 1 {
+            if level.len() % 2 == 1 {
+                level.push(level.last().unwrap().clone());
+            }
+            level = level
+                .chunks(2)
+                .map(|pair| sha256::digest(format!("{}{}", pair[0], pair[1])))
+                .collect();
+        }
+        Ok(level.pop().unwrap_or_default())
+    }
+
     // --------- getter ---------
 
     pub fn get_hash(&self) -> String {
@@ -95,3 +193,22 @@ impl Block {
         self.height
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallets::Wallet;
+
+    #[test]
+    fn proof_of_work_covers_the_height() {
+        let cbtx =
+            Transaction::new_coinbase(Wallet::new().get_address(), String::from("pow")).unwrap();
+        let mut header = Block::new_block(vec![cbtx], String::from("parent"), 1)
+            .unwrap()
+            .header()
+            .unwrap();
+        assert!(header.verify().unwrap());
+        header.height = 2;
+        assert!(!header.verify().unwrap());
+    }
+}
@@ -6,17 +6,36 @@ use bincode::{deserialize, serialize};
 use sled;
 
 use super::*;
-use crate::block::Block;
-use crate::transaction::{TXOutput, TXOutputs, Transaction};
-use std::collections::HashMap;
+use crate::block::{Block, BlockHeader};
+use crate::transaction::{AssetIssuance, TXOutput, TXOutputs, Transaction, SUBSIDY};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
 
 const GENESIS_COINBASE_DATA: &str =
     "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
+pub const DATA_DIR: &str = "./db";
+
+/// A block that breaks the chain rules, as opposed to one we can't place
+/// yet.
+#[derive(Debug)]
+pub struct InvalidBlock(pub String);
+
+impl fmt::Display for InvalidBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidBlock {}
 
 #[derive(Debug, Clone)]
 pub struct Blockchain {
     tip: String,
     db: sled::Db,
+    // where the chain and the UTXO set are stored
+    dir: String,
 }
 
 pub struct BlockchainIterator<'a> {
@@ -26,8 +45,13 @@ pub struct BlockchainIterator<'a> {
 
 impl Blockchain {
     pub fn new() -> Result<Blockchain> {
+        Blockchain::open(DATA_DIR)
+    }
+
+    /// Opens the chain stored under `dir`.
+    pub fn open(dir: &str) -> Result<Blockchain> {
         info!("open blockchain");
-        let db = sled::open("./db/bc")?;
+        let db = sled::open(format!("{}/bc", dir))?;
         let hash = db
             .get("LAST")?
             .expect("Must create a new blockchain database first");
@@ -40,23 +64,29 @@ impl Blockchain {
         Ok(Blockchain {
             tip: last_hash.clone(),
             db: db,
+            dir: dir.to_string(),
         })
     }
 
     pub fn create_blockchain(address: String) -> Result<Blockchain> {
         info!("Create new blockchain");
+        let cbtx = Transaction::new_coinbase(address, String::from(GENESIS_COINBASE_DATA))?;
+        Blockchain::create_with_genesis(DATA_DIR, Block::new_genesis_block(cbtx))
+    }
 
-        let db = sled::open("./db/bc")?;
+    /// Starts a chain under `dir` from `genesis`, so several chains can
+    /// share one.
+    pub fn create_with_genesis(dir: &str, genesis: Block) -> Result<Blockchain> {
+        let db = sled::open(format!("{}/bc", dir))?;
 
         debug!("Creating new block database");
 
-        let cbtx = Transaction::new_coinbase(address, String::from(GENESIS_COINBASE_DATA))?;
-        let genesis: Block = Block::new_genesis_block(cbtx);
         db.insert(genesis.get_hash(), serialize(&genesis)?)?;
         db.insert("LAST", genesis.get_hash().as_bytes())?;
         let bc = Blockchain {
             tip: genesis.get_hash(),
             db,
+            dir: dir.to_string(),
         };
         bc.db.flush()?;
         Ok(bc)
@@ -72,10 +102,8 @@ impl Blockchain {
     pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
         info!("mine a new block");
 
-        for tx in &transactions {
-            if !self.verify_transaction(&tx)? {
-                return Err(format_err!("ERROR: Invalid transaction"));
-            }
+        if !self.verify_block_transactions(&self.tip, &transactions)? {
+            return Err(format_err!("ERROR: Invalid transaction"));
         }
 
         let last_hash = self.db.get("LAST")?.expect("Could not get last hash ");
@@ -110,7 +138,67 @@ impl Blockchain {
         Ok(())
     }
 
-    fn get_prev_TXs(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
+    /// Verifies the transactions of a block on top of `prev_hash`: exactly
+    /// one coinbase, first, claiming at most the subsidy plus the fees of the
+    /// block, and the others spending only outputs left unspent by the chain
+    /// and the block so far.
+    pub fn verify_block_transactions(
+        &self,
+        prev_hash: &str,
+        transactions: &[Transaction],
+    ) -> Result<bool> {
+        let (coinbase, transactions) = match transactions.split_first() {
+            Some((coinbase, rest)) if coinbase.is_coinbase() => (coinbase, rest),
+            _ => return Ok(false),
+        };
+        if !self.verify_transaction_with(coinbase, HashMap::new())? {
+            return Ok(false);
+        }
+
+        let mut unspent = self.find_unspent_at(prev_hash);
+        let mut fees: i32 = 0;
+        for tx in transactions {
+            if tx.is_coinbase() || unspent.contains_key(&tx.id) {
+                return Ok(false);
+            }
+            let mut prev_TXs = HashMap::new();
+            for vin in &tx.vin {
+                let (prev_tx, outs) = match unspent.get_mut(&vin.txid) {
+                    Some(entry) => entry,
+                    None => return Ok(false),
+                };
+                // taken out, so no later input of the block spends it again
+                if !outs.remove(&vin.vout) {
+                    return Ok(false);
+                }
+                prev_TXs.insert(prev_tx.id.clone(), prev_tx.clone());
+            }
+            if !self.verify_transaction_with(tx, prev_TXs.clone())? {
+                return Ok(false);
+            }
+            fees = match tx.fee(&prev_TXs).and_then(|fee| fees.checked_add(fee)) {
+                Some(fees) => fees,
+                None => return Ok(false),
+            };
+            let outs = (0..tx.vout.len() as i32).collect();
+            unspent.insert(tx.id.clone(), (tx.clone(), outs));
+        }
+
+        let reward = coinbase
+            .output_sums()
+            .and_then(|sums| sums.get("").cloned())
+            .unwrap_or(0);
+        Ok(SUBSIDY
+            .checked_add(fees)
+            .is_some_and(|limit| reward <= limit))
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    pub fn get_prev_TXs(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
         let mut prev_TXs: HashMap<String, Transaction> = HashMap::new();
         for vin in &tx.vin {
             let prev_tx = self.find_transaction(&vin.txid)?;
@@ -129,13 +217,75 @@ impl Blockchain {
 
     pub fn verify_transaction(&self, tx: &Transaction) -> Result<bool> {
         if tx.is_coinbase() {
-            return Ok(true);
+            return self.verify_transaction_with(tx, HashMap::new());
         }
 
         let prev_TXs = self.get_prev_TXs(tx)?;
+        self.verify_transaction_with(tx, prev_TXs)
+    }
+
+    /// Like `verify_transaction`, but with the previous transactions supplied
+    /// by the caller (e.g. when some of them are still in the mempool).
+    pub fn verify_transaction_with(
+        &self,
+        tx: &Transaction,
+        prev_TXs: HashMap<String, Transaction>,
+    ) -> Result<bool> {
+        // a coinbase only mints the native coin, its amount is checked
+        // against the block
+        if tx.is_coinbase() {
+            return Ok(tx.issuance.is_none()
+                && tx.vout.iter().all(|out| out.is_native())
+                && tx.output_sums().is_some());
+        }
+
+        if let Some(issuance) = &tx.issuance {
+            if !self.verify_issuance(tx, issuance)? {
+                return Ok(false);
+            }
+        }
+
         tx.verify(prev_TXs)
     }
 
+    fn verify_issuance(&self, tx: &Transaction, issuance: &AssetIssuance) -> Result<bool> {
+        // the issuer key has to sign at least one of the inputs
+        if !tx
+            .vin
+            .iter()
+            .any(|vin| vin.pub_key == issuance.issuer_pub_key)
+        {
+            return Ok(false);
+        }
+
+        // a fresh asset id commits to the spent outpoint, so it can't collide
+        if issuance.is_original(tx) {
+            return Ok(true);
+        }
+
+        match self.find_issuance(&issuance.asset_id)? {
+            Some(original) => Ok(original.reissuable
+                && original.name == issuance.name
+                && original.issuer_pub_key == issuance.issuer_pub_key),
+            None => Ok(false),
+        }
+    }
+
+    /// Returns the issuance that originally created `asset_id`.
+    pub fn find_issuance(&self, asset_id: &str) -> Result<Option<AssetIssuance>> {
+        let mut original = None;
+        for block in self.iter() {
+            for tx in block.get_transaction() {
+                if let Some(issuance) = &tx.issuance {
+                    if issuance.asset_id == asset_id && issuance.is_original(tx) {
+                        original = Some(issuance.clone());
+                    }
+                }
+            }
+        }
+        Ok(original)
+    }
+
     pub fn find_transaction(&self, id: &str) -> Result<Transaction> {
         for block in self.iter() {
             for tx in block.get_transaction() {
@@ -161,12 +311,16 @@ impl Blockchain {
                     }
 
                     match utxos.get_mut(&tx.id) {
-                        Some(v) => v.outputs.push(tx.vout[index].clone()),
+                        Some(v) => {
+                            v.outputs.push(tx.vout[index].clone());
+                            v.indexes.push(index as i32);
+                        }
                         None => {
                             utxos.insert(
                                 tx.id.clone(),
                                 TXOutputs {
                                     outputs: vec![tx.vout[index].clone()],
+                                    indexes: vec![index as i32],
                                 },
                             );
                         }
@@ -191,8 +345,65 @@ impl Blockchain {
         utxos
     }
 
+    /// Transactions of the chain ending at `hash` with outputs left unspent,
+    /// together with the indexes of those outputs.
+    fn find_unspent_at(&self, hash: &str) -> HashMap<String, (Transaction, HashSet<i32>)> {
+        let mut unspent = HashMap::new();
+        let mut spent: HashSet<(String, i32)> = HashSet::new();
+        let blocks = BlockchainIterator {
+            current_hash: hash.to_string(),
+            bc: self,
+        };
+        for block in blocks {
+            // newest first, so every spend is seen before what it spends
+            for tx in block.get_transaction().iter().rev() {
+                let outs: HashSet<i32> = (0..tx.vout.len() as i32)
+                    .filter(|index| !spent.contains(&(tx.id.clone(), *index)))
+                    .collect();
+                if !outs.is_empty() {
+                    unspent.insert(tx.id.clone(), (tx.clone(), outs));
+                }
+                if !tx.is_coinbase() {
+                    for vin in &tx.vin {
+                        spent.insert((vin.txid.clone(), vin.vout));
+                    }
+                }
+            }
+        }
+        unspent
+    }
+
+    /// The blocks only on the chain ending at `old_tip` and those only on
+    /// the chain ending at `new_tip`, each newest first.
+    pub fn find_fork(&self, old_tip: &str, new_tip: &str) -> Result<(Vec<Block>, Vec<Block>)> {
+        let mut old = self.get_block(old_tip)?;
+        let mut new = self.get_block(new_tip)?;
+        let mut disconnected = Vec::new();
+        let mut connected = Vec::new();
+        while old.get_hash() != new.get_hash() {
+            if old.get_height() >= new.get_height() {
+                let prev = self.get_block(&old.get_prev_hash())?;
+                disconnected.push(old);
+                old = prev;
+            } else {
+                let prev = self.get_block(&new.get_prev_hash())?;
+                connected.push(new);
+                new = prev;
+            }
+        }
+        Ok((disconnected, connected))
+    }
+
     // --------- getter ---------
 
+    pub fn get_tip(&self) -> String {
+        self.tip.clone()
+    }
+
+    pub fn get_dir(&self) -> &str {
+        &self.dir
+    }
+
     pub fn get_best_height(&self) -> Result<i32> {
         let last_hash = if let Some(h) = self.db.get("LAST")? {
             h
@@ -213,10 +424,74 @@ impl Blockchain {
     }
 
     pub fn get_block(&self, block_hash: &str) -> Result<Block> {
-        let data = self.db.get(block_hash)?.unwrap();
+        let data = match self.db.get(block_hash)? {
+            Some(data) => data,
+            None => return Err(format_err!("Block {} not found", block_hash)),
+        };
         let block = deserialize(&data.to_vec())?;
         Ok(block)
     }
+
+    pub fn has_block(&self, block_hash: &str) -> Result<bool> {
+        Ok(self.db.contains_key(block_hash)?)
+    }
+
+    /// Hashes from the tip back to genesis, one by one for the last ten
+    /// blocks and doubling the step after that, so a peer can find where
+    /// our chains fork.
+    pub fn get_block_locator(&self) -> Vec<String> {
+        let hashs = self.get_block_hashs();
+        let mut locator = Vec::new();
+        let mut step = 1;
+        let mut i = 0;
+        while i < hashs.len() {
+            locator.push(hashs[i].clone());
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            i += step;
+        }
+        if let Some(genesis) = hashs.last() {
+            if locator.last() != Some(genesis) {
+                locator.push(genesis.clone());
+            }
+        }
+        locator
+    }
+
+    /// Hashes of our chain after the first `locator` hash we have, oldest
+    /// first, up to `max` of them and ending early at `stop_hash`. Starts at
+    /// genesis when no locator hash is known.
+    pub fn get_hashs_after(&self, locator: &[String], stop_hash: &str, max: usize) -> Vec<String> {
+        let hashs = self.get_block_hashs();
+        let fork = locator
+            .iter()
+            .find_map(|hash| hashs.iter().position(|h| h == hash))
+            .unwrap_or(hashs.len());
+
+        let mut after = Vec::new();
+        for hash in hashs[..fork].iter().rev().take(max) {
+            after.push(hash.clone());
+            if hash == stop_hash {
+                break;
+            }
+        }
+        after
+    }
+
+    /// Headers of the blocks `get_hashs_after` returns.
+    pub fn get_headers_after(
+        &self,
+        locator: &[String],
+        stop_hash: &str,
+        max: usize,
+    ) -> Result<Vec<BlockHeader>> {
+        let mut headers = Vec::new();
+        for hash in self.get_hashs_after(locator, stop_hash, max) {
+            headers.push(self.get_block(&hash)?.header()?);
+        }
+        Ok(headers)
+    }
 }
 
 impl<'a> Iterator for BlockchainIterator<'a> {
@@ -239,3 +514,186 @@ impl<'a> Iterator for BlockchainIterator<'a> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        transaction::{AssetIssuance, TXInput, SEQUENCE_FINAL},
+        wallets::Wallet,
+    };
+    use std::{env, fs, process};
+
+    /// A chain of only its genesis block, in a directory of its own.
+    fn chain(name: &str) -> Blockchain {
+        funded_chain(name).0
+    }
+
+    /// Like `chain`, with the wallet the genesis coinbase pays and that
+    /// coinbase.
+    fn funded_chain(name: &str) -> (Blockchain, Wallet, Transaction) {
+        let dir = env::temp_dir().join(format!("blockchain_rust_bc_{}_{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        let wallet = Wallet::new();
+        let cbtx =
+            Transaction::new_coinbase(wallet.get_address(), String::from("genesis")).unwrap();
+        let bc = Blockchain::create_with_genesis(
+            dir.to_str().unwrap(),
+            Block::new_genesis_block(cbtx.clone()),
+        )
+        .unwrap();
+        (bc, wallet, cbtx)
+    }
+
+    /// Signed by `wallet`, spends output 0 of `prev` into `value` to a new
+    /// address.
+    fn spend(wallet: &Wallet, prev: &Transaction, value: i32) -> Transaction {
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: prev.id.clone(),
+                vout: 0,
+                signature: Vec::new(),
+                pub_key: wallet.public_key.clone(),
+                sequence: SEQUENCE_FINAL,
+            }],
+            vout: vec![TXOutput::new(value, Wallet::new().get_address()).unwrap()],
+            issuance: None,
+        };
+        tx.set_id().unwrap();
+        let key = <&[u8; 32]>::try_from(wallet.secret_key.as_slice()).unwrap();
+        tx.sign(key, HashMap::from([(prev.id.clone(), prev.clone())]))
+            .unwrap();
+        tx
+    }
+
+    fn coinbase(value: i32) -> Transaction {
+        let mut cbtx =
+            Transaction::new_coinbase(Wallet::new().get_address(), String::from("reward")).unwrap();
+        cbtx.vout[0].value = value;
+        cbtx
+    }
+
+    #[test]
+    fn coinbase_claims_at_most_the_subsidy() {
+        let bc = chain("subsidy");
+        assert!(bc
+            .verify_block_transactions(&bc.get_tip(), &[coinbase(SUBSIDY)])
+            .unwrap());
+        assert!(!bc
+            .verify_block_transactions(&bc.get_tip(), &[coinbase(SUBSIDY + 1)])
+            .unwrap());
+        assert!(!bc
+            .verify_block_transactions(&bc.get_tip(), &[coinbase(SUBSIDY), coinbase(1)])
+            .unwrap());
+        let _ = fs::remove_dir_all(bc.get_dir());
+    }
+
+    #[test]
+    fn coinbase_only_mints_the_native_coin() {
+        let bc = chain("native");
+        let mut cbtx = coinbase(1);
+        cbtx.vout[0].asset_id = String::from("gold");
+        assert!(!bc.verify_transaction(&cbtx).unwrap());
+
+        let mut cbtx = coinbase(1);
+        cbtx.issuance = Some(AssetIssuance {
+            asset_id: String::from("gold"),
+            name: String::from("gold"),
+            amount: 100,
+            reissuable: true,
+            issuer_pub_key: Vec::new(),
+        });
+        assert!(!bc.verify_transaction(&cbtx).unwrap());
+        let _ = fs::remove_dir_all(bc.get_dir());
+    }
+
+    #[test]
+    fn coinbase_comes_first_and_once() {
+        let (bc, wallet, genesis) = funded_chain("coinbase_first");
+        let tx = spend(&wallet, &genesis, SUBSIDY);
+        let tip = bc.get_tip();
+        assert!(bc
+            .verify_block_transactions(&tip, &[coinbase(SUBSIDY), tx.clone()])
+            .unwrap());
+        assert!(!bc
+            .verify_block_transactions(&tip, &[tx.clone(), coinbase(SUBSIDY)])
+            .unwrap());
+        assert!(!bc.verify_block_transactions(&tip, &[tx]).unwrap());
+        assert!(!bc.verify_block_transactions(&tip, &[]).unwrap());
+        let _ = fs::remove_dir_all(bc.get_dir());
+    }
+
+    #[test]
+    fn coinbase_may_claim_the_fees() {
+        let (bc, wallet, genesis) = funded_chain("fees");
+        let tx = spend(&wallet, &genesis, SUBSIDY - 3);
+        let tip = bc.get_tip();
+        assert!(bc
+            .verify_block_transactions(&tip, &[coinbase(SUBSIDY + 3), tx.clone()])
+            .unwrap());
+        assert!(!bc
+            .verify_block_transactions(&tip, &[coinbase(SUBSIDY + 4), tx])
+            .unwrap());
+        let _ = fs::remove_dir_all(bc.get_dir());
+    }
+
+    #[test]
+    fn output_is_spent_by_its_key_only() {
+        let (bc, wallet, genesis) = funded_chain("foreign_key");
+        assert!(bc
+            .verify_transaction(&spend(&wallet, &genesis, SUBSIDY))
+            .unwrap());
+        // validly signed, but by a key the output isn't locked to
+        let thief = spend(&Wallet::new(), &genesis, SUBSIDY);
+        assert!(!bc.verify_transaction(&thief).unwrap());
+        assert!(!bc
+            .verify_block_transactions(&bc.get_tip(), &[coinbase(SUBSIDY), thief])
+            .unwrap());
+        let _ = fs::remove_dir_all(bc.get_dir());
+    }
+
+    #[test]
+    fn output_is_spent_once() {
+        let (bc, wallet, genesis) = funded_chain("double_spend");
+        let first = spend(&wallet, &genesis, SUBSIDY);
+        let second = spend(&wallet, &genesis, SUBSIDY - 1);
+        let tip = bc.get_tip();
+        assert!(!bc
+            .verify_block_transactions(&tip, &[coinbase(SUBSIDY), first.clone(), second.clone()])
+            .unwrap());
+
+        let mut bc = bc;
+        bc.mine_block(vec![coinbase(SUBSIDY), first]).unwrap();
+        assert!(!bc
+            .verify_block_transactions(&bc.get_tip(), &[coinbase(SUBSIDY), second.clone()])
+            .unwrap());
+        // still unspent on the chain the block was mined on
+        assert!(bc
+            .verify_block_transactions(&tip, &[coinbase(SUBSIDY), second])
+            .unwrap());
+        let _ = fs::remove_dir_all(bc.get_dir());
+    }
+
+    #[test]
+    fn fork_splits_the_branches() {
+        let mut bc = chain("fork");
+        let genesis = bc.get_tip();
+        let left = bc.mine_block(vec![coinbase(SUBSIDY)]).unwrap();
+        let right = Block::new_block(vec![coinbase(1)], genesis.clone(), 1).unwrap();
+        let right_tip = Block::new_block(vec![coinbase(2)], right.get_hash(), 2).unwrap();
+        bc.add_block(right.clone()).unwrap();
+        bc.add_block(right_tip.clone()).unwrap();
+        assert_eq!(bc.get_tip(), right_tip.get_hash());
+
+        let hashs = |blocks: Vec<Block>| -> Vec<String> {
+            blocks.iter().map(|block| block.get_hash()).collect()
+        };
+        let (disconnected, connected) = bc.find_fork(&left.get_hash(), &bc.get_tip()).unwrap();
+        assert_eq!(hashs(disconnected), [left.get_hash()]);
+        assert_eq!(hashs(connected), [right_tip.get_hash(), right.get_hash()]);
+        let (disconnected, connected) = bc.find_fork(&genesis, &genesis).unwrap();
+        assert!(disconnected.is_empty() && connected.is_empty());
+        let _ = fs::remove_dir_all(bc.get_dir());
+    }
+}
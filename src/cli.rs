@@ -2,9 +2,13 @@
 
 use anyhow::format_err;
 use bitcoincash_addr::Address;
-use clap::{arg, command, ArgAction, Command};
+use chrono::prelude::*;
+use clap::{arg, command, ArgAction, ArgMatches, Command};
 
 use super::*;
+use crate::banman::DEFAULT_BAN_TIME;
+use crate::blockchain::DATA_DIR;
+use crate::noise::Identity;
 use crate::server::*;
 use crate::transaction::Transaction;
 use crate::utxoset::UTXOSet;
@@ -26,16 +30,59 @@ impl Cli {
             .arg_required_else_help(true)
             .subcommand(Command::new("printchain").about("print all the chain blocks"))
             .subcommand(Command::new("reindex").about("reindex unspent-transaction-output set"))
-            .subcommand(
+            .subcommand(node_args(
                 Command::new("startnode")
                     .about("start the node server")
                     .arg(arg!([port]).help("the port server bind to locally")),
-            )
-            .subcommand(
+            ))
+            .subcommand(node_args(
                 Command::new("minernode")
                     .about("start the miner node")
                     .arg(arg!([port]).help("the port server bind to locally"))
                     .arg(arg!([address]).help("the wallet address to accept mining reward")),
+            ))
+            .subcommand(
+                Command::new("savemempool")
+                    .about("ask a running node to write its mempool to disk")
+                    .arg(arg!([port]).help("the port the node is bound to locally")),
+            )
+            .subcommand(
+                Command::new("stop")
+                    .about("shut a running node down")
+                    .arg(arg!([port]).help("the port the node is bound to locally")),
+            )
+            .subcommand(Command::new("nodekey").about("print the key peers pin this node by"))
+            .subcommand(
+                Command::new("getpeerinfo")
+                    .about("list the peers of a running node")
+                    .arg(arg!([port]).help("the port the node is bound to locally")),
+            )
+            .subcommand(
+                Command::new("setban")
+                    .about("ban or unban an ip on a running node")
+                    .arg(arg!([port]).help("the port the node is bound to locally"))
+                    .arg(arg!([ip]).help("the ip to ban or unban"))
+                    .arg(arg!([command]).help("add or remove"))
+                    .arg(
+                        arg!(--bantime <SECONDS>)
+                            .required(false)
+                            .help("How long the ban lasts, defaults to 24 hours"),
+                    ),
+            )
+            .subcommand(
+                Command::new("listbanned")
+                    .about("list the banned ips of a running node")
+                    .arg(arg!([port]).help("the port the node is bound to locally")),
+            )
+            .subcommand(
+                Command::new("getrejects")
+                    .about("list the rejects and notfounds peers sent a running node")
+                    .arg(arg!([port]).help("the port the node is bound to locally")),
+            )
+            .subcommand(
+                Command::new("clearbanned")
+                    .about("lift every ban on a running node")
+                    .arg(arg!([port]).help("the port the node is bound to locally")),
             )
             .subcommand(
                 Command::new("createblockchain")
@@ -58,6 +105,40 @@ impl Cli {
                     .arg(arg!([from]).help("Source wallet address"))
                     .arg(arg!([to]).help("Destination wallet address"))
                     .arg(arg!([amount]).help("Amount to send"))
+                    .arg(
+                        arg!(--asset <ASSET_ID>)
+                            .required(false)
+                            .help("Send an issued asset instead of the native coin"),
+                    )
+                    .arg(
+                        arg!(--fee <FEE>)
+                            .required(false)
+                            .help("Fee paid to the miner in native coin"),
+                    )
+                    .arg(
+                        arg!(--rbf).action(ArgAction::SetTrue).help(
+                            "allow the transaction to be replaced by one paying a higher fee",
+                        ),
+                    )
+                    .arg(
+                        arg!([mine])
+                            .short('m')
+                            .long("mine")
+                            .action(ArgAction::SetFalse)
+                            .help("if mined immediately in the same node when sending transaction"),
+                    ),
+            )
+            .subcommand(
+                Command::new("issueasset")
+                    .about("issue a new asset controlled by the issuer wallet")
+                    .arg(arg!([from]).help("Issuer wallet address"))
+                    .arg(arg!([name]).help("Asset name"))
+                    .arg(arg!([amount]).help("Amount to mint"))
+                    .arg(
+                        arg!(--reissuable)
+                            .action(ArgAction::SetTrue)
+                            .help("allow the issuer to mint more of the asset later"),
+                    )
                     .arg(
                         arg!([mine])
                             .short('m')
@@ -66,6 +147,40 @@ impl Cli {
                             .help("if mined immediately in the same node when sending transaction"),
                     ),
             )
+            .subcommand(
+                Command::new("reissueasset")
+                    .about("mint more of a reissuable asset")
+                    .arg(arg!([from]).help("Issuer wallet address"))
+                    .arg(arg!([asset_id]).help("Asset id"))
+                    .arg(arg!([amount]).help("Amount to mint"))
+                    .arg(
+                        arg!([mine])
+                            .short('m')
+                            .long("mine")
+                            .action(ArgAction::SetFalse)
+                            .help("if mined immediately in the same node when sending transaction"),
+                    ),
+            )
+            .subcommand(
+                Command::new("bumpfee")
+                    .about("replace a pending transaction with one paying a higher fee")
+                    .arg(arg!([txid]).help("Id of the pending transaction"))
+                    .arg(
+                        arg!(--fee <FEE>)
+                            .required(false)
+                            .help("New total fee, defaults to the current fee plus one"),
+                    ),
+            )
+            .subcommand(
+                Command::new("canceltx")
+                    .about("replace a pending transaction with one paying back to the sender")
+                    .arg(arg!([txid]).help("Id of the pending transaction"))
+                    .arg(
+                        arg!(--fee <FEE>)
+                            .required(false)
+                            .help("New total fee, defaults to the current fee plus one"),
+                    ),
+            )
             .get_matches();
 
         match matches.subcommand() {
@@ -87,7 +202,7 @@ impl Cli {
                     println!("Start node...");
                     let bc = Blockchain::new()?;
                     let utxo_set = UTXOSet { blockchain: bc };
-                    let server = Server::new(port, "", utxo_set)?;
+                    let server = Server::new(node_config(port, sub_matches), "", utxo_set)?;
                     server.start_server()?;
                 }
             }
@@ -108,9 +223,109 @@ impl Cli {
                 println!("Start miner node...");
                 let bc = Blockchain::new()?;
                 let utxo_set = UTXOSet { blockchain: bc };
-                let server = Server::new(port, address, utxo_set)?;
+                let server = Server::new(node_config(port, sub_matches), address, utxo_set)?;
                 server.start_server()?;
             }
+            Some(("savemempool", sub_matches)) => {
+                if let Some(port) = sub_matches.get_one::<String>("port") {
+                    Server::request_save_mempool(port)?;
+                    println!("Save mempool request sent!");
+                }
+            }
+            Some(("stop", sub_matches)) => {
+                if let Some(port) = sub_matches.get_one::<String>("port") {
+                    Server::request_stop(port)?;
+                    println!("Stop request sent!");
+                }
+            }
+            Some(("nodekey", _)) => {
+                println!("{}", Identity::load_or_create(DATA_DIR)?.public_hex());
+            }
+            Some(("getpeerinfo", sub_matches)) => {
+                if let Some(port) = sub_matches.get_one::<String>("port") {
+                    for peer in Server::request_peer_info(port)? {
+                        let latency = match peer.latency_ms {
+                            Some(ms) => format!("{}ms", ms),
+                            None => String::from("-"),
+                        };
+                        let key = match &peer.remote_key {
+                            Some(key) => format!("encrypted, key {}", key),
+                            None => String::from("plaintext"),
+                        };
+                        println!(
+                            "{} {} {} version: {} {} latency: {} best height: {} sent: {} recv: {} misbehavior: {} {}",
+                            peer.id,
+                            peer.addr,
+                            if peer.inbound { "inbound" } else { "outbound" },
+                            peer.version,
+                            peer.user_agent,
+                            latency,
+                            peer.best_height,
+                            peer.bytes_sent,
+                            peer.bytes_recv,
+                            peer.misbehavior,
+                            key
+                        );
+                    }
+                }
+            }
+            Some(("setban", sub_matches)) => {
+                let (port, ip, command) = match (
+                    sub_matches.get_one::<String>("port"),
+                    sub_matches.get_one::<String>("ip"),
+                    sub_matches.get_one::<String>("command"),
+                ) {
+                    (Some(port), Some(ip), Some(command)) => (port, ip, command),
+                    _ => {
+                        println!("setban need <port> <ip> <add|remove> arguments");
+                        exit(1);
+                    }
+                };
+                let add = match command.as_str() {
+                    "add" => true,
+                    "remove" => false,
+                    _ => {
+                        println!("setban command must be add or remove");
+                        exit(1);
+                    }
+                };
+                let ban_time = match sub_matches.get_one::<String>("bantime") {
+                    Some(ban_time) => ban_time.parse()?,
+                    None => DEFAULT_BAN_TIME,
+                };
+                Server::request_set_ban(port, ip, add, ban_time)?;
+                println!("Set ban request sent!");
+            }
+            Some(("listbanned", sub_matches)) => {
+                if let Some(port) = sub_matches.get_one::<String>("port") {
+                    for ban in Server::request_list_banned(port)? {
+                        let until = Utc.timestamp_opt(ban.until, 0).unwrap();
+                        println!("{} until {}: {}", ban.ip, until, ban.reason);
+                    }
+                }
+            }
+            Some(("getrejects", sub_matches)) => {
+                if let Some(port) = sub_matches.get_one::<String>("port") {
+                    for reject in Server::request_rejects(port)? {
+                        let time = Utc.timestamp_opt(reject.time, 0).unwrap();
+                        println!(
+                            "{} {} {} {} {}: {}",
+                            time,
+                            reject.peer,
+                            reject.item.kind,
+                            reject.item.hash,
+                            reject.code,
+                            reject.reason
+                        );
+                    }
+                }
+            }
+            Some(("clearbanned", sub_matches)) => {
+                if let Some(port) = sub_matches.get_one::<String>("port") {
+                    Server::request_clear_banned(port)?;
+                    println!("Clear banned request sent!");
+                }
+            }
             Some(("createblockchain", sub_matches)) => {
                 match sub_matches.get_one::<String>("address") {
                     Some(address) => {
@@ -130,13 +345,14 @@ impl Cli {
                     let pub_key_hash = Address::decode(&address).unwrap().body;
                     let bc = Blockchain::new()?;
                     let utxo_set = UTXOSet { blockchain: bc };
-                    let utxos = utxo_set.find_UTXO(&pub_key_hash)?;
+                    let mut balances = utxo_set.get_balances(&pub_key_hash)?;
 
-                    let mut balance = 0;
-                    for out in utxos.outputs {
-                        balance += out.value;
+                    let balance = balances.remove("").unwrap_or(0);
+                    println!("Balance of '{}': {}", address, balance);
+                    for (asset_id, amount) in balances {
+                        println!("  asset {}: {}", asset_id, amount);
                     }
-                    println!("Balance of '{}': {}\n", address, balance);
+                    println!();
                 }
                 None => {
                     return Err(format_err!("Need <address> argument"));
@@ -176,19 +392,86 @@ impl Cli {
                     exit(1);
                 };
 
+                let asset_id = match sub_matches.get_one::<String>("asset") {
+                    Some(asset_id) => asset_id.clone(),
+                    None => String::new(),
+                };
+                let fee: i32 = match sub_matches.get_one::<String>("fee") {
+                    Some(fee) => fee.parse()?,
+                    None => 0,
+                };
+                let rbf = sub_matches.get_flag("rbf");
+
                 let mut bc = Blockchain::new()?;
                 let mut utxo_set = UTXOSet { blockchain: bc };
-                let tx = Transaction::new_UTXO(from, to, amount, &utxo_set)?;
+                let tx = Transaction::new_UTXO(from, to, &asset_id, amount, fee, rbf, &utxo_set)?;
+
+                submit_transaction(tx, from, sub_matches.get_flag("mine"), utxo_set)?;
+            }
+            Some(("issueasset", sub_matches)) => {
+                let (from, name, amount) = match (
+                    sub_matches.get_one::<String>("from"),
+                    sub_matches.get_one::<String>("name"),
+                    sub_matches.get_one::<String>("amount"),
+                ) {
+                    (Some(from), Some(name), Some(amount)) => (from, name, amount.parse()?),
+                    _ => {
+                        println!("Issue asset need <from> <name> <amount> arguments");
+                        exit(1);
+                    }
+                };
+
+                let bc = Blockchain::new()?;
+                let utxo_set = UTXOSet { blockchain: bc };
+                let reissuable = sub_matches.get_flag("reissuable");
+                let tx = Transaction::new_issuance(from, name, amount, reissuable, &utxo_set)?;
+                println!("Issue asset: {}", tx.issuance.as_ref().unwrap().asset_id);
+
+                submit_transaction(tx, from, sub_matches.get_flag("mine"), utxo_set)?;
+            }
+            Some(("reissueasset", sub_matches)) => {
+                let (from, asset_id, amount) = match (
+                    sub_matches.get_one::<String>("from"),
+                    sub_matches.get_one::<String>("asset_id"),
+                    sub_matches.get_one::<String>("amount"),
+                ) {
+                    (Some(from), Some(asset_id), Some(amount)) => (from, asset_id, amount.parse()?),
+                    _ => {
+                        println!("Reissue asset need <from> <asset_id> <amount> arguments");
+                        exit(1);
+                    }
+                };
 
-                if sub_matches.get_flag("mine") {
-                    let cbtx = Transaction::new_coinbase(from.to_string(), String::from("reward"))?;
-                    let new_block = utxo_set.blockchain.mine_block(vec![cbtx, tx])?;
-                    utxo_set.update(&new_block)?;
-                    println!("Send transaction and wait to add in next block!");
+                let bc = Blockchain::new()?;
+                let utxo_set = UTXOSet { blockchain: bc };
+                let tx = Transaction::new_reissuance(from, asset_id, amount, &utxo_set)?;
+
+                submit_transaction(tx, from, sub_matches.get_flag("mine"), utxo_set)?;
+            }
+            Some((cmd @ ("bumpfee" | "canceltx"), sub_matches)) => {
+                let txid = if let Some(txid) = sub_matches.get_one::<String>("txid") {
+                    txid
                 } else {
-                    Server::send_transaction(&tx, utxo_set)?;
-                    println!("Send transaction success and add in new mine block!");
-                }
+                    println!("Replace transaction need <txid> argument");
+                    exit(1);
+                };
+                let fee: Option<i32> = match sub_matches.get_one::<String>("fee") {
+                    Some(fee) => Some(fee.parse()?),
+                    None => None,
+                };
+
+                let ws = Wallets::new()?;
+                let orig = match ws.get_transaction(txid)? {
+                    Some(tx) => tx,
+                    None => return Err(format_err!("Transaction {} is not in the wallet", txid)),
+                };
+
+                let bc = Blockchain::new()?;
+                let utxo_set = UTXOSet { blockchain: bc };
+                let tx = Transaction::new_replacement(&orig, fee, cmd == "canceltx", &utxo_set)?;
+                ws.save_transaction(&tx)?;
+                Server::send_transaction(&tx)?;
+                println!("Replace transaction {} by {}", txid, tx.id);
             }
             _ => {
                 return Err(format_err!("Invalid Command"));
@@ -198,3 +481,100 @@ impl Cli {
         Ok(())
     }
 }
+
+fn submit_transaction(
+    tx: Transaction,
+    from: &str,
+    mine: bool,
+    mut utxo_set: UTXOSet,
+) -> Result<()> {
+    Wallets::new()?.save_transaction(&tx)?;
+    if mine {
+        let cbtx = Transaction::new_coinbase(from.to_string(), String::from("reward"))?;
+        let new_block = utxo_set.blockchain.mine_block(vec![cbtx, tx])?;
+        utxo_set.update(&new_block)?;
+        println!("Send transaction and wait to add in next block!");
+    } else {
+        Server::send_transaction(&tx)?;
+        println!("Send transaction success and add in new mine block!");
+    }
+    Ok(())
+}
+
+/// Network options shared by `startnode` and `minernode`.
+fn node_args(cmd: Command) -> Command {
+    cmd.arg(
+        arg!(--bind <ADDR>)
+            .required(false)
+            .help("Address to listen on, defaults to localhost:<port>"),
+    )
+    .arg(
+        arg!(--advertise <ADDR>)
+            .required(false)
+            .help("Address announced to peers, defaults to localhost:<port>"),
+    )
+    .arg(
+        arg!(--connect <ADDR>)
+            .required(false)
+            .action(ArgAction::Append)
+            .help("Only connect to this node, can be repeated"),
+    )
+    .arg(
+        arg!(--addnode <ADDR>)
+            .required(false)
+            .action(ArgAction::Append)
+            .help("Keep a connection to this node, can be repeated"),
+    )
+    .arg(
+        arg!(--seednode <ADDR>)
+            .required(false)
+            .action(ArgAction::Append)
+            .help("Bootstrap from this node while no other is known, can be repeated"),
+    )
+    .arg(
+        arg!(--encrypt)
+            .action(ArgAction::SetTrue)
+            .help("Dial peers encrypted and refuse plaintext from other machines"),
+    )
+    .arg(
+        arg!(--peerkey <PIN>)
+            .required(false)
+            .action(ArgAction::Append)
+            .help("<addr>=<key>: dial this node encrypted and expect its key, can be repeated"),
+    )
+}
+
+fn node_config(port: &str, sub_matches: &ArgMatches) -> NodeConfig {
+    let mut config = NodeConfig::new(port);
+    if let Some(bind) = sub_matches.get_one::<String>("bind") {
+        config.bind = bind.clone();
+    }
+    if let Some(advertise) = sub_matches.get_one::<String>("advertise") {
+        config.advertise = advertise.clone();
+    }
+    let list = |name: &str| -> Vec<String> {
+        match sub_matches.get_many::<String>(name) {
+            Some(values) => values.cloned().collect(),
+            None => Vec::new(),
+        }
+    };
+    config.connect = list("connect");
+    config.addnode = list("addnode");
+    // the default seed only applies when no node is given at all
+    if !config.connect.is_empty() || !config.addnode.is_empty() {
+        config.seednode.clear();
+    }
+    if sub_matches.contains_id("seednode") {
+        config.seednode = list("seednode");
+    }
+    config.encrypt = sub_matches.get_flag("encrypt");
+    for pin in list("peerkey") {
+        match pin.split_once('=') {
+            Some((addr, key)) => {
+                config.pinned.insert(addr.to_string(), key.to_string());
+            }
+            None => println!("ignoring --peerkey {}: expected <addr>=<key>", pin),
+        }
+    }
+    config
+}
@@ -0,0 +1,243 @@
+use anyhow::format_err;
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use super::*;
+use crate::{
+    block::{Block, BlockHeader},
+    mempool::MAX_BLOCK_TX_SIZE,
+    transaction::Transaction,
+};
+use std::collections::HashMap;
+
+// serialized bytes of the smallest transaction that verifies, one signed
+// input under its id
+const MIN_TX_SIZE: usize = 200;
+// most transactions a block fits, the coinbase included
+pub const MAX_BLOCK_TXS: usize = MAX_BLOCK_TX_SIZE / MIN_TX_SIZE + 1;
+
+/// A block announced by its header, with short ids standing in for the
+/// transactions the receiver most likely has in its mempool.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompactBlock {
+    pub header: BlockHeader,
+    // salt of the short ids, so colliding ids differ from block to block
+    pub nonce: u64,
+    // ids of the transactions not prefilled, in block order
+    pub short_ids: Vec<u64>,
+    // transactions sent in full with their index in the block
+    pub prefilled: Vec<(usize, Transaction)>,
+}
+
+impl CompactBlock {
+    /// Prefills the coinbase, which no mempool has.
+    pub fn new(block: &Block) -> Result<CompactBlock> {
+        let mut compact = CompactBlock {
+            header: block.header()?,
+            nonce: OsRng.next_u64(),
+            short_ids: Vec::new(),
+            prefilled: Vec::new(),
+        };
+        for (index, tx) in block.get_transaction().iter().enumerate() {
+            if tx.is_coinbase() {
+                compact.prefilled.push((index, tx.clone()));
+            } else {
+                compact.short_ids.push(compact.short_id(&tx.id));
+            }
+        }
+        Ok(compact)
+    }
+
+    /// First 48 bits of the salted hash of `txid`.
+    pub fn short_id(&self, txid: &str) -> u64 {
+        let digest = sha256::digest(format!("{}:{}:{}", self.nonce, self.header.hash, txid));
+        u64::from_str_radix(&digest[..12], 16).unwrap()
+    }
+
+    pub fn tx_count(&self) -> usize {
+        self.short_ids.len() + self.prefilled.len()
+    }
+}
+
+/// A compact block being put back together, waiting for the transactions
+/// the mempool didn't have.
+pub struct PartialBlock {
+    pub header: BlockHeader,
+    // the peer that announced it and is asked for the rest
+    pub peer_id: u64,
+    slots: Vec<Option<Transaction>>,
+}
+
+impl PartialBlock {
+    /// Places the prefilled transactions and those of `available` matching a
+    /// short id. Ids shared by two candidates are left for the peer to fill.
+    /// Fails when the block has more than `MAX_BLOCK_TXS` transactions or
+    /// the prefilled indexes don't fit it.
+    pub fn new<'a, I>(compact: &CompactBlock, available: I, peer_id: u64) -> Result<PartialBlock>
+    where
+        I: Iterator<Item = &'a Transaction>,
+    {
+        if compact.tx_count() > MAX_BLOCK_TXS {
+            return Err(format_err!(
+                "Compact block {} has {} transactions",
+                compact.header.hash,
+                compact.tx_count()
+            ));
+        }
+        let mut slots: Vec<Option<Transaction>> = vec![None; compact.tx_count()];
+        for (index, tx) in &compact.prefilled {
+            match slots.get_mut(*index) {
+                Some(slot) if slot.is_none() => *slot = Some(tx.clone()),
+                _ => {
+                    return Err(format_err!(
+                        "Compact block {} has a bad prefilled index {}",
+                        compact.header.hash,
+                        index
+                    ))
+                }
+            }
+        }
+
+        let mut candidates: HashMap<u64, Option<&Transaction>> = HashMap::new();
+        for tx in available {
+            candidates
+                .entry(compact.short_id(&tx.id))
+                .and_modify(|found| *found = None)
+                .or_insert(Some(tx));
+        }
+        let mut short_ids = compact.short_ids.iter();
+        for slot in slots.iter_mut().filter(|slot| slot.is_none()) {
+            let short_id = short_ids.next().unwrap();
+            if let Some(Some(tx)) = candidates.get(short_id) {
+                *slot = Some((*tx).clone());
+            }
+        }
+
+        Ok(PartialBlock {
+            header: compact.header.clone(),
+            peer_id,
+            slots,
+        })
+    }
+
+    /// Block indexes of the transactions still missing.
+    pub fn missing(&self) -> Vec<usize> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.is_none())
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Fills the missing slots with `txs`, in index order.
+    pub fn fill(&mut self, txs: Vec<Transaction>) -> Result<()> {
+        let missing = self.missing();
+        if txs.len() != missing.len() {
+            return Err(format_err!(
+                "Got {} transactions for {} missing in block {}",
+                txs.len(),
+                missing.len(),
+                self.header.hash
+            ));
+        }
+        for (index, tx) in missing.into_iter().zip(txs) {
+            self.slots[index] = Some(tx);
+        }
+        Ok(())
+    }
+
+    /// The block once every transaction is in. A short id collision shows
+    /// as a block that fails `Block::verify`.
+    pub fn block(&self) -> Option<Block> {
+        let txs: Option<Vec<Transaction>> = self.slots.iter().cloned().collect();
+        Some(Block::from_header(&self.header, txs?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallets::Wallet;
+
+    fn tx(id: &str) -> Transaction {
+        let mut tx =
+            Transaction::new_coinbase(Wallet::new().get_address(), String::from("spend")).unwrap();
+        tx.vin[0].txid = String::from("parent");
+        tx.vin[0].vout = 0;
+        tx.id = id.to_string();
+        tx
+    }
+
+    /// A coinbase, then transactions `a` and `b`.
+    fn block() -> Block {
+        let cbtx = Transaction::new_coinbase(Wallet::new().get_address(), String::from("compact"))
+            .unwrap();
+        Block::new_unsolved(vec![cbtx, tx("a"), tx("b")], String::from("parent"), 1)
+    }
+
+    fn ids(block: &Block) -> Vec<String> {
+        block
+            .get_transaction()
+            .iter()
+            .map(|tx| tx.id.clone())
+            .collect()
+    }
+
+    #[test]
+    fn block_is_rebuilt_from_the_mempool() {
+        let block = block();
+        let compact = CompactBlock::new(&block).unwrap();
+        assert_eq!(compact.prefilled.len(), 1);
+        assert_eq!(compact.short_ids.len(), 2);
+
+        let mempool = [tx("b"), tx("unrelated"), tx("a")];
+        let partial = PartialBlock::new(&compact, mempool.iter(), 1).unwrap();
+        assert!(partial.missing().is_empty());
+        assert_eq!(ids(&partial.block().unwrap()), ids(&block));
+    }
+
+    #[test]
+    fn missing_transactions_are_filled_in() {
+        let block = block();
+        let compact = CompactBlock::new(&block).unwrap();
+        let mempool = [tx("a")];
+        let mut partial = PartialBlock::new(&compact, mempool.iter(), 1).unwrap();
+        assert_eq!(partial.missing(), [2]);
+        assert!(partial.block().is_none());
+
+        assert!(partial.fill(Vec::new()).is_err());
+        partial.fill(vec![tx("b")]).unwrap();
+        assert_eq!(ids(&partial.block().unwrap()), ids(&block));
+    }
+
+    #[test]
+    fn shared_short_id_is_left_for_the_peer() {
+        let compact = CompactBlock::new(&block()).unwrap();
+        let mempool = [tx("a"), tx("a"), tx("b")];
+        let partial = PartialBlock::new(&compact, mempool.iter(), 1).unwrap();
+        assert_eq!(partial.missing(), [1]);
+    }
+
+    #[test]
+    fn oversized_block_is_rejected() {
+        let mut compact = CompactBlock::new(&block()).unwrap();
+        compact.short_ids = vec![0; MAX_BLOCK_TXS - compact.prefilled.len()];
+        assert!(PartialBlock::new(&compact, [].iter(), 1).is_ok());
+        compact.short_ids.push(0);
+        assert!(PartialBlock::new(&compact, [].iter(), 1).is_err());
+    }
+
+    #[test]
+    fn prefilled_index_has_to_fit_the_block() {
+        let mut compact = CompactBlock::new(&block()).unwrap();
+        compact.prefilled[0].0 = 3;
+        assert!(PartialBlock::new(&compact, [].iter(), 1).is_err());
+
+        let mut compact = CompactBlock::new(&block()).unwrap();
+        let coinbase = compact.prefilled[0].clone();
+        compact.prefilled.push(coinbase);
+        compact.short_ids.pop();
+        assert!(PartialBlock::new(&compact, [].iter(), 1).is_err());
+    }
+}
@@ -1,10 +1,24 @@
 #![allow(unused)]
 
+mod addrman;
+mod banman;
 mod block;
 mod blockchain;
 mod cli;
+mod compact;
+mod mempool;
+mod miner;
+mod noise;
+mod orphan;
+mod peer;
+mod protocol;
+mod ratelimit;
 mod server;
+#[cfg(test)]
+mod simnet;
+mod sync;
 mod transaction;
+mod transport;
 mod utxoset;
 mod wallets;
 
@@ -0,0 +1,948 @@
+#![allow(non_snake_case)]
+
+use anyhow::format_err;
+use bincode::{deserialize, serialize};
+use serde::{Deserialize, Serialize};
+
+use super::*;
+use crate::{block::Block, protocol::RejectCode, transaction::Transaction, utxoset::UTXOSet};
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap, HashSet},
+    fmt, fs,
+    path::Path,
+};
+
+// where the mempool is saved, under the node's data directory
+const MEMPOOL_FILE: &str = "mempool.dat";
+
+// largest transaction accepted into the mempool, in serialized bytes
+const MAX_TX_SIZE: usize = 100_000;
+// total serialized size of all pending transactions
+const MAX_MEMPOOL_SIZE: usize = 5_000_000;
+// outputs below this value are rejected as dust
+const DUST_LIMIT: i32 = 1;
+// seconds a transaction may stay pending before it is dropped
+const MEMPOOL_EXPIRY: i64 = 24 * 60 * 60;
+// most transactions (conflicts plus their descendants) one replacement may evict
+const MAX_REPLACEMENTS: usize = 100;
+// longest chain of unconfirmed ancestors or descendants a transaction may have
+const MAX_PACKAGE_COUNT: usize = 25;
+// serialized bytes of the transactions one block template takes
+pub const MAX_BLOCK_TX_SIZE: usize = 1_000_000;
+
+/// Rejection of a transaction that breaks consensus rules rather than local
+/// policy, so whoever relayed it can be penalised.
+#[derive(Debug)]
+pub struct InvalidTransaction(pub String);
+
+impl fmt::Display for InvalidTransaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidTransaction {}
+
+/// Rejection of a transaction spending outputs of transactions we have never
+/// seen, which may still arrive. Holds the ids of those parents.
+#[derive(Debug)]
+pub struct MissingInputs(pub Vec<String>);
+
+impl fmt::Display for MissingInputs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Missing parent transactions {}", self.0.join(", "))
+    }
+}
+
+impl std::error::Error for MissingInputs {}
+
+/// Rejection of a transaction other nodes may still take, or that we
+/// already have, with the code its sender is told.
+#[derive(Debug)]
+pub struct Rejected(pub RejectCode, pub String);
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.1)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// The code to tell the sender of a transaction `accept` failed with `e`,
+/// none when it failed for reasons of our own or may still be accepted.
+pub fn reject_code(e: &anyhow::Error) -> Option<RejectCode> {
+    if e.is::<InvalidTransaction>() {
+        return Some(RejectCode::Invalid);
+    }
+    e.downcast_ref::<Rejected>().map(|Rejected(code, _)| *code)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MempoolEntry {
+    pub tx: Transaction,
+    pub fee: i32,
+    pub size: usize,
+    pub time: i64,
+}
+
+impl MempoolEntry {
+    pub fn fee_rate(&self) -> f64 {
+        self.fee as f64 / self.size as f64
+    }
+}
+
+pub struct Mempool {
+    entries: HashMap<String, MempoolEntry>,
+    // spent outpoint (txid, vout) -> id of the pending transaction spending it
+    spends: HashMap<(String, i32), String>,
+    total_size: usize,
+    // file `save` writes and `load` reads
+    file: String,
+}
+
+impl Mempool {
+    /// An empty pool saved under the data directory `dir`.
+    pub fn new(dir: &str) -> Mempool {
+        Mempool {
+            entries: HashMap::new(),
+            spends: HashMap::new(),
+            total_size: 0,
+            file: format!("{}/{}", dir, MEMPOOL_FILE),
+        }
+    }
+
+    /// Validates `tx` against the UTXO set and the other pending transactions
+    /// and adds it to the pool as received at `time`.
+    pub fn accept(&mut self, tx: Transaction, utxo: &UTXOSet, time: i64) -> Result<()> {
+        if tx.is_coinbase() {
+            return Err(InvalidTransaction(format!(
+                "Coinbase transaction {} is not relayed",
+                tx.id
+            ))
+            .into());
+        }
+        if tx.compute_id()? != tx.id {
+            return Err(
+                InvalidTransaction(format!("Transaction {} does not match its id", tx.id)).into(),
+            );
+        }
+        if tx.has_duplicate_inputs() {
+            return Err(InvalidTransaction(format!(
+                "Transaction {} spends an output twice",
+                tx.id
+            ))
+            .into());
+        }
+        if self.entries.contains_key(&tx.id) {
+            return Err(Rejected(
+                RejectCode::Duplicate,
+                format!("Transaction {} is already in the mempool", tx.id),
+            )
+            .into());
+        }
+
+        let size = serialize(&tx)?.len();
+        if size > MAX_TX_SIZE {
+            return Err(Rejected(
+                RejectCode::NonStandard,
+                format!("Transaction {} is too large: {} bytes", tx.id, size),
+            )
+            .into());
+        }
+        if tx.vout.iter().any(|out| out.value < DUST_LIMIT) {
+            return Err(Rejected(
+                RejectCode::NonStandard,
+                format!("Transaction {} has a dust output", tx.id),
+            )
+            .into());
+        }
+
+        let mut conflicts = HashSet::new();
+        for vin in &tx.vin {
+            if let Some(other) = self.spends.get(&(vin.txid.clone(), vin.vout)) {
+                if !self.entries[other].tx.signals_rbf() {
+                    return Err(Rejected(
+                        RejectCode::Conflict,
+                        format!(
+                            "Transaction {} double spends {}:{} already spent by {}",
+                            tx.id, vin.txid, vin.vout, other
+                        ),
+                    )
+                    .into());
+                }
+                conflicts.insert(other.clone());
+            }
+        }
+
+        let prev_TXs = self.get_prev_TXs(&tx, utxo)?;
+        if !utxo
+            .blockchain
+            .verify_transaction_with(&tx, prev_TXs.clone())?
+        {
+            return Err(InvalidTransaction(format!("Transaction {} is invalid", tx.id)).into());
+        }
+
+        let fee = match tx.fee(&prev_TXs) {
+            Some(fee) => fee,
+            None => {
+                let reason = format!("Transaction {} amounts are out of range", tx.id);
+                return Err(InvalidTransaction(reason).into());
+            }
+        };
+
+        self.check_package_limits(&tx)?;
+
+        if !conflicts.is_empty() {
+            self.check_replacement(&tx, fee, size, &conflicts)?;
+            for txid in &conflicts {
+                info!("mempool replace transaction: {} by {}", txid, tx.id);
+                self.remove_with_descendants(txid);
+            }
+        }
+
+        let txid = tx.id.clone();
+        self.insert(MempoolEntry {
+            tx,
+            fee,
+            size,
+            time,
+        });
+        self.trim();
+
+        if !self.entries.contains_key(&txid) {
+            return Err(Rejected(
+                RejectCode::InsufficientFee,
+                format!("Mempool is full, fee rate of {} is too low", txid),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Removes the transactions confirmed by `block` and everything that
+    /// conflicts with them.
+    pub fn remove_block(&mut self, block: &Block) {
+        for tx in block.get_transaction() {
+            // children of a confirmed transaction stay valid
+            self.remove(&tx.id);
+            if tx.is_coinbase() {
+                continue;
+            }
+            for vin in &tx.vin {
+                if let Some(txid) = self.spends.get(&(vin.txid.clone(), vin.vout)).cloned() {
+                    self.remove_with_descendants(&txid);
+                }
+            }
+        }
+    }
+
+    /// Drops transactions that have been pending for longer than
+    /// `MEMPOOL_EXPIRY` by `now`.
+    pub fn expire(&mut self, now: i64) {
+        let deadline = now - MEMPOOL_EXPIRY;
+        let expired: Vec<String> = self
+            .entries
+            .values()
+            .filter(|entry| entry.time < deadline)
+            .map(|entry| entry.tx.id.clone())
+            .collect();
+        for txid in expired {
+            info!("mempool expire transaction: {}", txid);
+            self.remove_with_descendants(&txid);
+        }
+    }
+
+    /// Writes every pending transaction to the data directory.
+    pub fn save(&self) -> Result<()> {
+        let entries: Vec<&MempoolEntry> = self.entries.values().collect();
+        fs::write(&self.file, serialize(&entries)?)?;
+        info!("save mempool: {} transactions", entries.len());
+        Ok(())
+    }
+
+    /// Re-validates the transactions saved by `save` against the current
+    /// chain, dropping whatever was confirmed, became invalid or expired by
+    /// `now` meanwhile.
+    pub fn load(&mut self, utxo: &UTXOSet, now: i64) -> Result<()> {
+        if !Path::new(&self.file).exists() {
+            return Ok(());
+        }
+        let mut pending: Vec<MempoolEntry> = deserialize(&fs::read(&self.file)?)?;
+        let saved = pending.len();
+
+        // parents may come after their children, so retry until nothing else fits
+        loop {
+            let before = pending.len();
+            let mut rejected = Vec::new();
+            for entry in pending {
+                let txid = entry.tx.id.clone();
+                if let Err(e) = self.accept(entry.tx.clone(), utxo, entry.time) {
+                    debug!("mempool load skip {}: {}", txid, e);
+                    rejected.push(entry);
+                }
+            }
+            pending = rejected;
+            if pending.is_empty() || pending.len() == before {
+                break;
+            }
+        }
+        self.expire(now);
+
+        info!("load mempool: {} of {} transactions", self.len(), saved);
+        Ok(())
+    }
+
+    /// Pending transactions for the next block, at most `MAX_BLOCK_TX_SIZE`
+    /// bytes of them. Packages (a transaction plus its unconfirmed ancestors)
+    /// are picked by aggregate fee rate, so a child paying a high fee pulls
+    /// its parents in, and parents always come first.
+    pub fn block_template(&self) -> Vec<Transaction> {
+        let children = self.children();
+        let mut packages: HashMap<String, Package> = self
+            .entries
+            .keys()
+            .map(|txid| (txid.clone(), self.package(txid)))
+            .collect();
+        let mut candidates: BinaryHeap<Candidate> = packages
+            .iter()
+            .map(|(txid, package)| Candidate::new(txid, package))
+            .collect();
+
+        let mut template = Vec::new();
+        let mut included: HashSet<String> = HashSet::new();
+        let mut budget = MAX_BLOCK_TX_SIZE;
+        while let Some(candidate) = candidates.pop() {
+            let package = &packages[&candidate.txid];
+            // included with a package picked earlier, or pushed again since
+            if included.contains(&candidate.txid)
+                || (candidate.fee, candidate.size) != (package.fee, package.size)
+            {
+                continue;
+            }
+            // its descendants include it, so they don't fit either
+            if package.size > budget {
+                continue;
+            }
+            budget -= package.size;
+
+            let mut txids: Vec<String> = package.ancestors.iter().cloned().collect();
+            txids.push(candidate.txid.clone());
+            // an ancestor always has fewer unconfirmed ancestors than its descendants
+            txids.sort_by_key(|txid| packages[txid].depth);
+            for txid in &txids {
+                template.push(self.entries[txid].tx.clone());
+                included.insert(txid.clone());
+            }
+
+            // what is left of the packages the picked transactions were part of
+            let mut changed: HashSet<String> = HashSet::new();
+            for txid in &txids {
+                let entry = &self.entries[txid];
+                for child in descendants_in(&children, txid) {
+                    if included.contains(&child) {
+                        continue;
+                    }
+                    let package = packages.get_mut(&child).unwrap();
+                    if package.ancestors.remove(txid) {
+                        package.fee -= entry.fee as i64;
+                        package.size -= entry.size;
+                        changed.insert(child);
+                    }
+                }
+            }
+            for txid in changed {
+                candidates.push(Candidate::new(&txid, &packages[&txid]));
+            }
+        }
+
+        template
+    }
+
+    /* -------------------- inner function -------------------- */
+
+    fn get_prev_TXs(
+        &self,
+        tx: &Transaction,
+        utxo: &UTXOSet,
+    ) -> Result<HashMap<String, Transaction>> {
+        let mut prev_TXs: HashMap<String, Transaction> = HashMap::new();
+        let mut missing: Vec<String> = Vec::new();
+        for vin in &tx.vin {
+            let prev_tx = match self.entries.get(&vin.txid) {
+                Some(entry) => entry.tx.clone(),
+                None => {
+                    if utxo.find_output(&vin.txid, vin.vout)?.is_none() {
+                        // a parent we never saw, rather than a spent output
+                        if utxo.blockchain.find_transaction(&vin.txid).is_err() {
+                            if !missing.contains(&vin.txid) {
+                                missing.push(vin.txid.clone());
+                            }
+                            continue;
+                        }
+                        return Err(Rejected(
+                            RejectCode::Conflict,
+                            format!(
+                                "Transaction {} spends missing or spent output {}:{}",
+                                tx.id, vin.txid, vin.vout
+                            ),
+                        )
+                        .into());
+                    }
+                    utxo.blockchain.find_transaction(&vin.txid)?
+                }
+            };
+            if prev_tx.vout.get(vin.vout as usize).is_none() {
+                return Err(InvalidTransaction(format!(
+                    "Transaction {} spends unknown output {}:{}",
+                    tx.id, vin.txid, vin.vout
+                ))
+                .into());
+            }
+            prev_TXs.insert(prev_tx.id.clone(), prev_tx);
+        }
+        if !missing.is_empty() {
+            return Err(MissingInputs(missing).into());
+        }
+        Ok(prev_TXs)
+    }
+
+    /// Keeps unconfirmed chains short enough for package selection.
+    fn check_package_limits(&self, tx: &Transaction) -> Result<()> {
+        let mut ancestors = HashSet::new();
+        for vin in &tx.vin {
+            if self.entries.contains_key(&vin.txid) {
+                ancestors.insert(vin.txid.clone());
+                ancestors.extend(self.ancestors(&vin.txid));
+            }
+        }
+        if ancestors.len() + 1 > MAX_PACKAGE_COUNT {
+            return Err(Rejected(
+                RejectCode::NonStandard,
+                format!(
+                    "Transaction {} has too many unconfirmed ancestors: {}",
+                    tx.id,
+                    ancestors.len()
+                ),
+            )
+            .into());
+        }
+        for txid in &ancestors {
+            if self.descendants(txid).len() + 1 >= MAX_PACKAGE_COUNT {
+                return Err(Rejected(
+                    RejectCode::NonStandard,
+                    format!(
+                        "Transaction {} would exceed the descendant limit of {}",
+                        tx.id, txid
+                    ),
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace-by-fee rules: the replacement has to pay more in total than
+    /// everything it evicts, beat the fee rate of each direct conflict, stay
+    /// under `MAX_REPLACEMENTS` and must not spend any of the evicted outputs.
+    fn check_replacement(
+        &self,
+        tx: &Transaction,
+        fee: i32,
+        size: usize,
+        conflicts: &HashSet<String>,
+    ) -> Result<()> {
+        let mut replaced = HashSet::new();
+        for txid in conflicts {
+            replaced.insert(txid.clone());
+            replaced.extend(self.descendants(txid));
+        }
+
+        if replaced.len() > MAX_REPLACEMENTS {
+            return Err(Rejected(
+                RejectCode::InsufficientFee,
+                format!(
+                    "Transaction {} would replace {} transactions, limit is {}",
+                    tx.id,
+                    replaced.len(),
+                    MAX_REPLACEMENTS
+                ),
+            )
+            .into());
+        }
+        if tx.vin.iter().any(|vin| replaced.contains(&vin.txid)) {
+            return Err(Rejected(
+                RejectCode::Invalid,
+                format!(
+                    "Transaction {} spends an output of a transaction it replaces",
+                    tx.id
+                ),
+            )
+            .into());
+        }
+
+        // summed as i64, as many replaced fees can add up past i32::MAX
+        let replaced_fee: i64 = replaced
+            .iter()
+            .map(|txid| self.entries[txid].fee as i64)
+            .sum();
+        if fee as i64 <= replaced_fee {
+            return Err(Rejected(
+                RejectCode::InsufficientFee,
+                format!(
+                    "Transaction {} fee {} is not higher than the replaced fee {}",
+                    tx.id, fee, replaced_fee
+                ),
+            )
+            .into());
+        }
+
+        let fee_rate = fee as f64 / size as f64;
+        for txid in conflicts {
+            if fee_rate <= self.entries[txid].fee_rate() {
+                return Err(Rejected(
+                    RejectCode::InsufficientFee,
+                    format!("Transaction {} fee rate is not higher than {}", tx.id, txid),
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Every pending transaction whose outputs `txid` spends, directly or not.
+    fn ancestors(&self, txid: &str) -> HashSet<String> {
+        let mut found = HashSet::new();
+        let mut queue = vec![txid.to_string()];
+        while let Some(child) = queue.pop() {
+            if let Some(entry) = self.entries.get(&child) {
+                for vin in &entry.tx.vin {
+                    if self.entries.contains_key(&vin.txid) && found.insert(vin.txid.clone()) {
+                        queue.push(vin.txid.clone());
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    /// `txid` and its unconfirmed ancestors.
+    fn package(&self, txid: &str) -> Package {
+        let ancestors = self.ancestors(txid);
+        let entry = &self.entries[txid];
+        let mut fee = entry.fee as i64;
+        let mut size = entry.size;
+        for ancestor in &ancestors {
+            fee += self.entries[ancestor].fee as i64;
+            size += self.entries[ancestor].size;
+        }
+        Package {
+            depth: ancestors.len(),
+            ancestors,
+            fee,
+            size,
+        }
+    }
+
+    /// Pending transactions by id of the pending transactions they spend.
+    fn children(&self) -> HashMap<String, Vec<String>> {
+        let mut children: HashMap<String, Vec<String>> = HashMap::new();
+        for entry in self.entries.values() {
+            let parents: HashSet<&String> = entry
+                .tx
+                .vin
+                .iter()
+                .map(|vin| &vin.txid)
+                .filter(|txid| self.entries.contains_key(*txid))
+                .collect();
+            for parent in parents {
+                children
+                    .entry(parent.clone())
+                    .or_default()
+                    .push(entry.tx.id.clone());
+            }
+        }
+        children
+    }
+
+    /// Every pending transaction that spends, directly or not, an output of `txid`.
+    fn descendants(&self, txid: &str) -> HashSet<String> {
+        let mut found = HashSet::new();
+        let mut queue = vec![txid.to_string()];
+        while let Some(parent) = queue.pop() {
+            for entry in self.entries.values() {
+                if entry.tx.vin.iter().any(|vin| vin.txid == parent)
+                    && found.insert(entry.tx.id.clone())
+                {
+                    queue.push(entry.tx.id.clone());
+                }
+            }
+        }
+        found
+    }
+
+    fn insert(&mut self, entry: MempoolEntry) {
+        for vin in &entry.tx.vin {
+            self.spends
+                .insert((vin.txid.clone(), vin.vout), entry.tx.id.clone());
+        }
+        self.total_size += entry.size;
+        self.entries.insert(entry.tx.id.clone(), entry);
+    }
+
+    fn remove(&mut self, txid: &str) -> Option<MempoolEntry> {
+        let entry = self.entries.remove(txid)?;
+        for vin in &entry.tx.vin {
+            self.spends.remove(&(vin.txid.clone(), vin.vout));
+        }
+        self.total_size -= entry.size;
+        Some(entry)
+    }
+
+    /// Removes `txid` together with anything spending its outputs, which is
+    /// no longer valid once the parent is gone.
+    fn remove_with_descendants(&mut self, txid: &str) {
+        for child in self.descendants(txid) {
+            self.remove(&child);
+        }
+        self.remove(txid);
+    }
+
+    /// Evicts the lowest fee rate transactions until the pool fits `MAX_MEMPOOL_SIZE`.
+    fn trim(&mut self) {
+        while self.total_size > MAX_MEMPOOL_SIZE {
+            let lowest = self
+                .entries
+                .values()
+                .min_by(|a, b| a.fee_rate().total_cmp(&b.fee_rate()))
+                .map(|entry| entry.tx.id.clone());
+            match lowest {
+                Some(txid) => {
+                    info!("mempool full, evict transaction: {}", txid);
+                    self.remove_with_descendants(&txid);
+                }
+                None => break,
+            }
+        }
+    }
+
+    // --------- getter ---------
+
+    pub fn get(&self, txid: &str) -> Option<&MempoolEntry> {
+        self.entries.get(txid)
+    }
+
+    pub fn transactions(&self) -> impl Iterator<Item = &Transaction> {
+        self.entries.values().map(|entry| &entry.tx)
+    }
+
+    pub fn contains(&self, txid: &str) -> bool {
+        self.entries.contains_key(txid)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// The ancestors of a transaction not yet in the block template, with their
+/// fee and size added to its own.
+struct Package {
+    ancestors: HashSet<String>,
+    // number of unconfirmed ancestors before any was picked
+    depth: usize,
+    fee: i64,
+    size: usize,
+}
+
+/// A package in the selection heap, ordered by fee rate.
+struct Candidate {
+    txid: String,
+    fee: i64,
+    size: usize,
+}
+
+impl Candidate {
+    fn new(txid: &str, package: &Package) -> Candidate {
+        Candidate {
+            txid: txid.to_string(),
+            fee: package.fee,
+            size: package.size,
+        }
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // fee / size against other.fee / other.size, without rounding
+        let rate = self.fee as i128 * other.size as i128;
+        let other_rate = other.fee as i128 * self.size as i128;
+        rate.cmp(&other_rate)
+            .then_with(|| other.txid.cmp(&self.txid))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+/// Every transaction below `txid` in `children`.
+fn descendants_in(children: &HashMap<String, Vec<String>>, txid: &str) -> HashSet<String> {
+    let mut found = HashSet::new();
+    let mut queue = vec![txid.to_string()];
+    while let Some(parent) = queue.pop() {
+        for child in children.get(&parent).into_iter().flatten() {
+            if found.insert(child.clone()) {
+                queue.push(child.clone());
+            }
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        transaction::{TXInput, TXOutput, SEQUENCE_FINAL},
+        wallets::Wallet,
+    };
+    use std::{env, process};
+
+    /// A pending transaction spending output 0 of each of `parents`.
+    fn pending(txid: &str, parents: &[&str], fee: i32, size: usize) -> MempoolEntry {
+        let vin = parents
+            .iter()
+            .map(|parent| TXInput {
+                txid: parent.to_string(),
+                vout: 0,
+                signature: Vec::new(),
+                pub_key: Vec::new(),
+                sequence: SEQUENCE_FINAL,
+            })
+            .collect();
+        let tx = Transaction {
+            id: txid.to_string(),
+            vin,
+            vout: vec![TXOutput {
+                value: 1,
+                pub_key_hash: vec![1; 20],
+                asset_id: String::new(),
+            }],
+            issuance: None,
+        };
+        MempoolEntry {
+            tx,
+            fee,
+            size,
+            time: 0,
+        }
+    }
+
+    fn template_ids(mempool: &Mempool) -> Vec<String> {
+        mempool
+            .block_template()
+            .into_iter()
+            .map(|tx| tx.id)
+            .collect()
+    }
+
+    #[test]
+    fn confirmed_conflicts_are_evicted_with_their_descendants() {
+        let mut mempool = Mempool::new("unused");
+        mempool.insert(pending("a", &["x"], 10, 100));
+        mempool.insert(pending("a_child", &["a"], 10, 100));
+        mempool.insert(pending("b", &["y"], 10, 100));
+        mempool.insert(pending("b_child", &["b"], 10, 100));
+
+        let double_spend = pending("c", &["x"], 10, 100).tx;
+        let confirmed = mempool.get("b").unwrap().tx.clone();
+        let block = Block::new_unsolved(vec![double_spend, confirmed], String::new(), 1);
+        mempool.remove_block(&block);
+
+        assert!(!mempool.contains("a"));
+        assert!(!mempool.contains("a_child"));
+        assert!(!mempool.contains("b"));
+        assert!(mempool.contains("b_child"));
+    }
+
+    #[test]
+    fn conflict_without_rbf_is_rejected() {
+        let dir = env::temp_dir().join(format!("blockchain_rust_mempool_{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let cbtx = Transaction::new_coinbase(Wallet::new().get_address(), String::from("genesis"))
+            .unwrap();
+        let utxo = UTXOSet {
+            blockchain: Blockchain::create_with_genesis(
+                dir.to_str().unwrap(),
+                Block::new_genesis_block(cbtx),
+            )
+            .unwrap(),
+        };
+
+        let mut mempool = Mempool::new(dir.to_str().unwrap());
+        mempool.insert(pending("old", &["x"], 10, 100));
+        let mut tx = pending("", &["x"], 0, 0).tx;
+        tx.id = tx.compute_id().unwrap();
+        let e = mempool.accept(tx, &utxo, 0).unwrap_err();
+
+        assert_eq!(reject_code(&e), Some(RejectCode::Conflict));
+        assert!(mempool.contains("old"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn ancestors_are_limited() {
+        let mut mempool = Mempool::new("unused");
+        let parents: Vec<String> = (0..MAX_PACKAGE_COUNT).map(|i| format!("p{}", i)).collect();
+        for parent in &parents {
+            mempool.insert(pending(parent, &["confirmed"], 10, 100));
+        }
+        let parents: Vec<&str> = parents.iter().map(|parent| parent.as_str()).collect();
+
+        let fits = pending("fits", &parents[1..], 10, 100).tx;
+        assert!(mempool.check_package_limits(&fits).is_ok());
+        let too_many = pending("too_many", &parents, 10, 100).tx;
+        assert!(mempool.check_package_limits(&too_many).is_err());
+    }
+
+    #[test]
+    fn descendants_are_limited() {
+        let mut mempool = Mempool::new("unused");
+        mempool.insert(pending("parent", &["confirmed"], 10, 100));
+        for i in 0..MAX_PACKAGE_COUNT - 2 {
+            mempool.insert(pending(&format!("child{}", i), &["parent"], 10, 100));
+        }
+        let last = pending("last", &["parent"], 10, 100).tx;
+        assert!(mempool.check_package_limits(&last).is_ok());
+
+        mempool.insert(pending("last", &["parent"], 10, 100));
+        let one_more = pending("one_more", &["parent"], 10, 100).tx;
+        assert!(mempool.check_package_limits(&one_more).is_err());
+    }
+
+    /// The pool with `old`, spending `x`, and its child `old_child`.
+    fn replaceable() -> (Mempool, HashSet<String>) {
+        let mut mempool = Mempool::new("unused");
+        mempool.insert(pending("old", &["x"], 100, 100));
+        mempool.insert(pending("old_child", &["old"], 50, 100));
+        (mempool, HashSet::from([String::from("old")]))
+    }
+
+    #[test]
+    fn replacement_pays_more_than_everything_it_evicts() {
+        let (mempool, conflicts) = replaceable();
+        let tx = pending("new", &["x"], 0, 0).tx;
+
+        let e = mempool
+            .check_replacement(&tx, 150, 100, &conflicts)
+            .unwrap_err();
+        assert_eq!(reject_code(&e), Some(RejectCode::InsufficientFee));
+        assert!(mempool.check_replacement(&tx, 151, 100, &conflicts).is_ok());
+    }
+
+    #[test]
+    fn replaced_fees_dont_overflow() {
+        let mut mempool = Mempool::new("unused");
+        mempool.insert(pending("old", &["x"], i32::MAX, 100));
+        mempool.insert(pending("old_child", &["old"], i32::MAX, 100));
+        let conflicts = HashSet::from([String::from("old")]);
+        let tx = pending("new", &["x"], 0, 0).tx;
+
+        let e = mempool
+            .check_replacement(&tx, i32::MAX, 1, &conflicts)
+            .unwrap_err();
+        assert_eq!(reject_code(&e), Some(RejectCode::InsufficientFee));
+    }
+
+    #[test]
+    fn replacement_beats_the_conflict_fee_rate() {
+        let (mempool, conflicts) = replaceable();
+        let tx = pending("new", &["x"], 0, 0).tx;
+
+        // more in total, but a lower rate than the 1 per byte of `old`
+        let e = mempool
+            .check_replacement(&tx, 190, 200, &conflicts)
+            .unwrap_err();
+        assert_eq!(reject_code(&e), Some(RejectCode::InsufficientFee));
+        assert!(mempool.check_replacement(&tx, 190, 180, &conflicts).is_ok());
+    }
+
+    #[test]
+    fn replacement_may_not_spend_what_it_evicts() {
+        let (mempool, conflicts) = replaceable();
+        let tx = pending("new", &["x", "old_child"], 0, 0).tx;
+
+        let e = mempool
+            .check_replacement(&tx, 1000, 100, &conflicts)
+            .unwrap_err();
+        assert_eq!(reject_code(&e), Some(RejectCode::Invalid));
+    }
+
+    #[test]
+    fn replacement_evicts_a_limited_number() {
+        let (mut mempool, conflicts) = replaceable();
+        for i in 1..MAX_REPLACEMENTS {
+            mempool.insert(pending(&format!("child{}", i), &["old"], 1, 100));
+        }
+        let tx = pending("new", &["x"], 0, 0).tx;
+
+        let e = mempool
+            .check_replacement(&tx, 100_000, 100, &conflicts)
+            .unwrap_err();
+        assert_eq!(reject_code(&e), Some(RejectCode::InsufficientFee));
+        mempool.remove("child1");
+        assert!(mempool
+            .check_replacement(&tx, 100_000, 100, &conflicts)
+            .is_ok());
+    }
+
+    #[test]
+    fn child_pays_for_its_parent() {
+        let mut mempool = Mempool::new("unused");
+        mempool.insert(pending("parent", &["confirmed"], 1, 100));
+        mempool.insert(pending("child", &["parent"], 100, 100));
+        mempool.insert(pending("other", &["confirmed2"], 30, 100));
+
+        assert_eq!(template_ids(&mempool), ["parent", "child", "other"]);
+    }
+
+    #[test]
+    fn ancestors_come_before_their_descendants() {
+        let mut mempool = Mempool::new("unused");
+        mempool.insert(pending("a", &["confirmed"], 1, 100));
+        mempool.insert(pending("b", &["a"], 50, 100));
+        mempool.insert(pending("c", &["b"], 500, 100));
+        mempool.insert(pending("d", &["a", "c"], 5000, 100));
+
+        assert_eq!(template_ids(&mempool), ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn template_stops_at_the_size_budget() {
+        let half = MAX_BLOCK_TX_SIZE / 2 + 1;
+        let mut mempool = Mempool::new("unused");
+        mempool.insert(pending("large", &["confirmed"], 2000, half));
+        mempool.insert(pending("larger_fee", &["confirmed2"], 3000, half));
+        // pulls in `large`, so doesn't fit either
+        mempool.insert(pending("child", &["large"], 1, 100));
+        mempool.insert(pending("small", &["confirmed3"], 0, 100));
+
+        assert_eq!(template_ids(&mempool), ["larger_fee", "small"]);
+    }
+}
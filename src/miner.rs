@@ -0,0 +1,138 @@
+use super::*;
+use crate::{block::Block, transaction::Transaction};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+};
+
+/// The transactions to mine on top of `prev_block_hash`, coinbase included.
+#[derive(Debug, Clone)]
+pub struct BlockTemplate {
+    pub prev_block_hash: String,
+    pub height: i32,
+    pub transactions: Vec<Transaction>,
+    // fees paid by the transactions, what a newer template has to beat
+    pub fees: i32,
+}
+
+/// Proof of work on the latest template, in a thread of its own. A new
+/// template abandons the block being mined, solved blocks are handed to the
+/// callback given to `start`.
+#[derive(Clone)]
+pub struct Miner {
+    work: Arc<Work>,
+}
+
+struct Work {
+    template: Mutex<Option<BlockTemplate>>,
+    changed: Condvar,
+    // bumped with every new template, polled by the running proof of work
+    generation: AtomicU64,
+    stopped: AtomicBool,
+}
+
+impl Miner {
+    pub fn new() -> Miner {
+        Miner {
+            work: Arc::new(Work {
+                template: Mutex::new(None),
+                changed: Condvar::new(),
+                generation: AtomicU64::new(0),
+                stopped: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    pub fn start<F>(&self, submit: F)
+    where
+        F: Fn(Block) + Send + 'static,
+    {
+        let work = Arc::clone(&self.work);
+        thread::spawn(move || mine_loop(work, submit));
+    }
+
+    /// Replaces the work, `None` leaves the miner idle.
+    pub fn update(&self, template: Option<BlockTemplate>) {
+        let mut current = self.work.template.lock().unwrap();
+        *current = template;
+        self.work.generation.fetch_add(1, Ordering::SeqCst);
+        self.work.changed.notify_all();
+    }
+
+    /// Abandons the block being mined and ends the mining thread.
+    pub fn stop(&self) {
+        self.work.stopped.store(true, Ordering::SeqCst);
+        self.update(None);
+    }
+
+    // --------- getter ---------
+
+    /// Fees of the block being mined, `None` while idle.
+    pub fn current_fees(&self) -> Option<i32> {
+        self.work
+            .template
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|template| template.fees)
+    }
+}
+
+fn mine_loop<F: Fn(Block)>(work: Arc<Work>, submit: F) {
+    loop {
+        let (template, generation) = {
+            let mut current = work.template.lock().unwrap();
+            loop {
+                if work.stopped.load(Ordering::SeqCst) {
+                    return;
+                }
+                if current.is_some() {
+                    break;
+                }
+                current = work.changed.wait(current).unwrap();
+            }
+            let template = current.clone().unwrap();
+            (template, work.generation.load(Ordering::SeqCst))
+        };
+        info!(
+            "mine block {} on {} with {} transactions",
+            template.height,
+            template.prev_block_hash,
+            template.transactions.len()
+        );
+
+        let mut block = Block::new_unsolved(
+            template.transactions,
+            template.prev_block_hash,
+            template.height,
+        );
+        let solved =
+            block.proof_of_work_until(|| work.generation.load(Ordering::SeqCst) != generation);
+
+        let done = match solved {
+            Ok(true) => true,
+            Ok(false) => {
+                debug!("mining of block {} abandoned", template.height);
+                continue;
+            }
+            Err(e) => {
+                error!("mine block {} failed: {}", template.height, e);
+                false
+            }
+        };
+        // the template is used up, unless a newer one came in meanwhile
+        {
+            let mut current = work.template.lock().unwrap();
+            if work.generation.load(Ordering::SeqCst) == generation {
+                *current = None;
+            }
+        }
+        if done {
+            info!("mined block {}", block.get_hash());
+            submit(block);
+        }
+    }
+}
@@ -0,0 +1,553 @@
+use anyhow::format_err;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::*;
+use crate::protocol::*;
+use std::{fs, io::ErrorKind, path::Path};
+
+// where the node key is kept, under the node's data directory
+const NODE_KEY_FILE: &str = "node.key";
+// opens an encrypted session, where a plaintext one starts with NETWORK_MAGIC
+pub const NOISE_MAGIC: [u8; 4] = *b"BCRN";
+// handshake pattern and primitives, hashed into the transcript
+const PROTOCOL_NAME: &[u8] = b"Noise_XX_25519_ChaChaPoly_SHA256";
+const KEY_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+// largest encrypted frame, a full protocol frame plus its tag
+const MAX_FRAME_LEN: usize = HEADER_LEN + MAX_MESSAGE_SIZE + TAG_LEN;
+// handshake messages are a few keys long
+const MAX_HANDSHAKE_LEN: usize = 1024;
+
+/// Static X25519 key pair a node is known by across encrypted sessions.
+#[derive(Clone)]
+pub struct Identity {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl Identity {
+    /// Loads the node key from the data directory `dir`, creating it on
+    /// first use.
+    pub fn load_or_create(dir: &str) -> Result<Identity> {
+        let file = format!("{}/{}", dir, NODE_KEY_FILE);
+        if Path::new(&file).exists() {
+            let data = fs::read(&file)?;
+            let bytes: [u8; KEY_LEN] = data
+                .as_slice()
+                .try_into()
+                .map_err(|_| format_err!("Node key {} is corrupt", file))?;
+            return Ok(Identity::from_secret(StaticSecret::from(bytes)));
+        }
+
+        let identity = Identity::generate();
+        fs::create_dir_all(dir)?;
+        write_secret(&file, &identity.secret.to_bytes())?;
+        info!("created node key {}", identity.public_hex());
+        Ok(identity)
+    }
+
+    /// A fresh key that isn't stored anywhere.
+    pub fn generate() -> Identity {
+        Identity::from_secret(StaticSecret::random_from_rng(OsRng))
+    }
+
+    fn from_secret(secret: StaticSecret) -> Identity {
+        let public = PublicKey::from(&secret);
+        Identity { secret, public }
+    }
+
+    /// The public key, what peers pin.
+    pub fn public_hex(&self) -> String {
+        to_hex(self.public.as_bytes())
+    }
+}
+
+/// One direction of an established session: frames are sealed with the
+/// key and a counter nonce, so replayed or reordered frames fail to open.
+pub struct CipherState {
+    cipher: ChaCha20Poly1305,
+    nonce: u64,
+}
+
+impl CipherState {
+    fn new(key: &[u8; KEY_LEN]) -> CipherState {
+        CipherState {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+            nonce: 0,
+        }
+    }
+
+    fn encrypt(&mut self, ad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.next_nonce()?;
+        self.cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: ad,
+                },
+            )
+            .map_err(|_| format_err!("Encryption failed"))
+    }
+
+    fn decrypt(&mut self, ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.next_nonce()?;
+        self.cipher
+            .decrypt(
+                &nonce,
+                Payload {
+                    msg: ciphertext,
+                    aad: ad,
+                },
+            )
+            .map_err(|_| format_err!("Decryption failed"))
+    }
+
+    /// Four zero bytes then the little endian counter.
+    fn next_nonce(&mut self) -> Result<Nonce> {
+        if self.nonce == u64::MAX {
+            return Err(format_err!("Session nonces exhausted"));
+        }
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.nonce.to_le_bytes());
+        self.nonce += 1;
+        Ok(*Nonce::from_slice(&nonce))
+    }
+}
+
+/// The two ciphers of a session and the key the remote node proved it
+/// holds.
+pub struct Session {
+    pub send: CipherState,
+    pub recv: CipherState,
+    pub remote_key: String,
+}
+
+impl Session {
+    /// Whether the remote node proved it holds `key`, given in hex.
+    pub fn has_key(&self, key: &str) -> bool {
+        self.remote_key == key.to_lowercase()
+    }
+}
+
+/// Runs the handshake as the dialling side, `NOISE_MAGIC` first.
+///
+///   -> e
+///   <- e, ee, s, es
+///   -> s, se
+pub async fn initiate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    identity: &Identity,
+) -> Result<Session> {
+    let mut state = HandshakeState::new(identity);
+    stream.write_all(&NOISE_MAGIC).await?;
+
+    let mut msg = state.write_ephemeral();
+    msg.extend(state.symmetric.encrypt_and_hash(&[])?);
+    write_handshake(stream, &msg).await?;
+
+    let msg = read_handshake(stream).await?;
+    let rest = state.read_ephemeral(&msg)?;
+    state.mix_dh(&state.e.diffie_hellman(state.re()?))?;
+    let rest = state.read_static(rest)?;
+    state.mix_dh(&state.e.diffie_hellman(state.rs()?))?;
+    state.symmetric.decrypt_and_hash(rest)?;
+
+    let mut msg = state.write_static()?;
+    state.mix_dh(&identity.secret.diffie_hellman(state.re()?))?;
+    msg.extend(state.symmetric.encrypt_and_hash(&[])?);
+    write_handshake(stream, &msg).await?;
+
+    state.split(true)
+}
+
+/// Runs the handshake as the accepting side, once `NOISE_MAGIC` was read.
+pub async fn respond<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    identity: &Identity,
+) -> Result<Session> {
+    let mut state = HandshakeState::new(identity);
+
+    let msg = read_handshake(stream).await?;
+    let rest = state.read_ephemeral(&msg)?;
+    state.symmetric.decrypt_and_hash(rest)?;
+
+    let mut msg = state.write_ephemeral();
+    state.mix_dh(&state.e.diffie_hellman(state.re()?))?;
+    msg.extend(state.write_static()?);
+    state.mix_dh(&identity.secret.diffie_hellman(state.re()?))?;
+    msg.extend(state.symmetric.encrypt_and_hash(&[])?);
+    write_handshake(stream, &msg).await?;
+
+    let msg = read_handshake(stream).await?;
+    let rest = state.read_static(&msg)?;
+    state.mix_dh(&state.e.diffie_hellman(state.rs()?))?;
+    state.symmetric.decrypt_and_hash(rest)?;
+
+    state.split(false)
+}
+
+/// Like `write_message_async`, sealed with `cipher` and length prefixed.
+pub async fn write_encrypted<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    cipher: &mut CipherState,
+    msg: &Message,
+) -> Result<usize> {
+    let data = cipher.encrypt(&[], &encode_message(msg)?)?;
+    writer.write_all(&(data.len() as u32).to_le_bytes()).await?;
+    writer.write_all(&data).await?;
+    writer.flush().await?;
+    Ok(4 + data.len())
+}
+
+/// Like `read_message_async` for frames written by `write_encrypted`. A
+/// frame that fails to open ends the session.
+pub async fn read_encrypted<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    cipher: &mut CipherState,
+) -> Result<Option<(Message, usize)>> {
+    let mut len = [0u8; 4];
+    if let Err(e) = reader.read_exact(&mut len).await {
+        if e.kind() == ErrorKind::UnexpectedEof {
+            return Ok(None);
+        }
+        return Err(e.into());
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(FrameError::Oversize(len).into());
+    }
+
+    let data = read_payload_async(reader, len).await?;
+    let frame = cipher.decrypt(&[], &data)?;
+    match read_message(&mut frame.as_slice())? {
+        Some(msg) => Ok(Some((msg, 4 + len))),
+        None => Err(FrameError::Malformed(String::from("empty encrypted frame")).into()),
+    }
+}
+
+/* -------------------- inner function -------------------- */
+
+/// Chaining key and transcript hash of a handshake in progress.
+struct SymmetricState {
+    ck: [u8; 32],
+    h: [u8; 32],
+    cipher: Option<CipherState>,
+}
+
+impl SymmetricState {
+    fn new() -> SymmetricState {
+        let mut h = [0u8; 32];
+        h.copy_from_slice(PROTOCOL_NAME);
+        let mut state = SymmetricState {
+            ck: h,
+            h,
+            cipher: None,
+        };
+        // binds the transcript to this network
+        state.mix_hash(&NETWORK_MAGIC);
+        state
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut hasher = Sha256::new();
+        hasher.update(self.h);
+        hasher.update(data);
+        self.h = hasher.finalize().into();
+    }
+
+    fn mix_key(&mut self, input: &[u8]) -> Result<()> {
+        let (ck, key) = hkdf2(&self.ck, input)?;
+        self.ck = ck;
+        self.cipher = Some(CipherState::new(&key));
+        Ok(())
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let h = self.h;
+        let ciphertext = match &mut self.cipher {
+            Some(cipher) => cipher.encrypt(&h, plaintext)?,
+            None => plaintext.to_vec(),
+        };
+        self.mix_hash(&ciphertext);
+        Ok(ciphertext)
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let h = self.h;
+        let plaintext = match &mut self.cipher {
+            Some(cipher) => cipher.decrypt(&h, ciphertext)?,
+            None => ciphertext.to_vec(),
+        };
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+}
+
+struct HandshakeState {
+    symmetric: SymmetricState,
+    s: PublicKey,
+    e: StaticSecret,
+    re: Option<PublicKey>,
+    rs: Option<PublicKey>,
+}
+
+impl HandshakeState {
+    fn new(identity: &Identity) -> HandshakeState {
+        HandshakeState {
+            symmetric: SymmetricState::new(),
+            s: identity.public,
+            e: StaticSecret::random_from_rng(OsRng),
+            re: None,
+            rs: None,
+        }
+    }
+
+    fn write_ephemeral(&mut self) -> Vec<u8> {
+        let e = PublicKey::from(&self.e);
+        self.symmetric.mix_hash(e.as_bytes());
+        e.as_bytes().to_vec()
+    }
+
+    /// Takes the remote ephemeral key off `msg`, returning the rest.
+    fn read_ephemeral<'a>(&mut self, msg: &'a [u8]) -> Result<&'a [u8]> {
+        if msg.len() < KEY_LEN {
+            return Err(format_err!("Handshake message too short"));
+        }
+        let (key, rest) = msg.split_at(KEY_LEN);
+        self.symmetric.mix_hash(key);
+        self.re = Some(PublicKey::from(to_key(key)));
+        Ok(rest)
+    }
+
+    fn write_static(&mut self) -> Result<Vec<u8>> {
+        let s = self.s;
+        self.symmetric.encrypt_and_hash(s.as_bytes())
+    }
+
+    /// Opens the remote static key off `msg`, returning the rest.
+    fn read_static<'a>(&mut self, msg: &'a [u8]) -> Result<&'a [u8]> {
+        if msg.len() < KEY_LEN + TAG_LEN {
+            return Err(format_err!("Handshake message too short"));
+        }
+        let (key, rest) = msg.split_at(KEY_LEN + TAG_LEN);
+        let key = self.symmetric.decrypt_and_hash(key)?;
+        self.rs = Some(PublicKey::from(to_key(&key)));
+        Ok(rest)
+    }
+
+    fn mix_dh(&mut self, shared: &x25519_dalek::SharedSecret) -> Result<()> {
+        if !shared.was_contributory() {
+            return Err(format_err!("Handshake with a low order key"));
+        }
+        self.symmetric.mix_key(shared.as_bytes())
+    }
+
+    fn re(&self) -> Result<&PublicKey> {
+        self.re
+            .as_ref()
+            .ok_or_else(|| format_err!("No remote ephemeral key"))
+    }
+
+    fn rs(&self) -> Result<&PublicKey> {
+        self.rs
+            .as_ref()
+            .ok_or_else(|| format_err!("No remote static key"))
+    }
+
+    /// Derives the transport keys, the first one for what the dialling side
+    /// sends.
+    fn split(self, initiator: bool) -> Result<Session> {
+        let remote_key = to_hex(self.rs()?.as_bytes());
+        let (first, second) = hkdf2(&self.symmetric.ck, &[])?;
+        let (send, recv) = if initiator {
+            (first, second)
+        } else {
+            (second, first)
+        };
+        Ok(Session {
+            send: CipherState::new(&send),
+            recv: CipherState::new(&recv),
+            remote_key,
+        })
+    }
+}
+
+/// The two outputs of HKDF-SHA256 keyed with `ck`.
+fn hkdf2(ck: &[u8; 32], input: &[u8]) -> Result<([u8; 32], [u8; 32])> {
+    let mut okm = [0u8; 64];
+    Hkdf::<Sha256>::new(Some(ck), input)
+        .expand(&[], &mut okm)
+        .map_err(|_| format_err!("Key derivation failed"))?;
+    let mut first = [0u8; 32];
+    let mut second = [0u8; 32];
+    first.copy_from_slice(&okm[..32]);
+    second.copy_from_slice(&okm[32..]);
+    Ok((first, second))
+}
+
+async fn write_handshake<W: AsyncWrite + Unpin>(writer: &mut W, msg: &[u8]) -> Result<()> {
+    writer.write_all(&(msg.len() as u16).to_le_bytes()).await?;
+    writer.write_all(msg).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn read_handshake<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut len = [0u8; 2];
+    reader.read_exact(&mut len).await?;
+    let len = u16::from_le_bytes(len) as usize;
+    if len > MAX_HANDSHAKE_LEN {
+        return Err(format_err!("Handshake message too long: {} bytes", len));
+    }
+    let mut msg = vec![0u8; len];
+    reader.read_exact(&mut msg).await?;
+    Ok(msg)
+}
+
+fn to_key(bytes: &[u8]) -> [u8; KEY_LEN] {
+    let mut key = [0u8; KEY_LEN];
+    key.copy_from_slice(&bytes[..KEY_LEN]);
+    key
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Writes a new file only its owner can read.
+#[cfg(unix)]
+fn write_secret(file: &str, data: &[u8]) -> Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+    let mut f = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(file)?;
+    f.write_all(data)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_secret(file: &str, data: &[u8]) -> Result<()> {
+    fs::write(file, data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+    use tokio::io::duplex;
+
+    /// Runs the handshake between `a` dialling and `b` accepting, with
+    /// `tamper` applied to the reply of `b` on its way.
+    async fn handshake<F: Fn(&mut Vec<u8>)>(
+        a: &Identity,
+        b: &Identity,
+        tamper: F,
+    ) -> (Result<Session>, Result<Session>) {
+        let (mut a_side, mut a_relay) = duplex(4096);
+        let (mut b_relay, mut b_side) = duplex(4096);
+        let relay = async move {
+            let mut magic = [0u8; 4];
+            a_relay.read_exact(&mut magic).await?;
+            let msg = read_handshake(&mut a_relay).await?;
+            write_handshake(&mut b_relay, &msg).await?;
+            let mut msg = read_handshake(&mut b_relay).await?;
+            tamper(&mut msg);
+            write_handshake(&mut a_relay, &msg).await?;
+            let msg = read_handshake(&mut a_relay).await?;
+            write_handshake(&mut b_relay, &msg).await
+        };
+        // each side hangs up once done, so a failed one doesn't stall the other
+        let (initiated, responded, _) = tokio::join!(
+            async move { initiate(&mut a_side, a).await },
+            async move { respond(&mut b_side, b).await },
+            relay
+        );
+        (initiated, responded)
+    }
+
+    #[tokio::test]
+    async fn handshake_proves_both_keys() {
+        let (a, b) = (Identity::generate(), Identity::generate());
+        let (initiated, responded) = handshake(&a, &b, |_| {}).await;
+        let (mut initiated, mut responded) = (initiated.unwrap(), responded.unwrap());
+        assert!(initiated.has_key(&b.public_hex()));
+        assert!(initiated.has_key(&b.public_hex().to_uppercase()));
+        assert!(responded.has_key(&a.public_hex()));
+
+        let mut frame = Vec::new();
+        write_encrypted(&mut frame, &mut initiated.send, &Message::Ping(7))
+            .await
+            .unwrap();
+        let msg = read_encrypted(&mut frame.as_slice(), &mut responded.recv)
+            .await
+            .unwrap();
+        assert!(matches!(msg, Some((Message::Ping(7), _))));
+    }
+
+    #[tokio::test]
+    async fn pinned_key_has_to_match() {
+        let (a, b) = (Identity::generate(), Identity::generate());
+        let (initiated, _) = handshake(&a, &b, |_| {}).await;
+        assert!(!initiated
+            .unwrap()
+            .has_key(&Identity::generate().public_hex()));
+    }
+
+    #[tokio::test]
+    async fn tampered_handshake_fails() {
+        let (a, b) = (Identity::generate(), Identity::generate());
+        let (initiated, responded) = handshake(&a, &b, |msg| {
+            *msg.last_mut().unwrap() ^= 1;
+        })
+        .await;
+        assert!(initiated.is_err());
+        assert!(responded.is_err());
+    }
+
+    #[tokio::test]
+    async fn tampered_frame_fails_to_open() {
+        let (a, b) = (Identity::generate(), Identity::generate());
+        let (initiated, responded) = handshake(&a, &b, |_| {}).await;
+        let (mut initiated, mut responded) = (initiated.unwrap(), responded.unwrap());
+
+        let mut frame = Vec::new();
+        write_encrypted(&mut frame, &mut initiated.send, &Message::Ping(7))
+            .await
+            .unwrap();
+        frame[10] ^= 1;
+        assert!(read_encrypted(&mut frame.as_slice(), &mut responded.recv)
+            .await
+            .is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn node_key_is_only_readable_by_its_owner() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = env::temp_dir().join(format!("blockchain_rust_key_{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let dir = dir.to_str().unwrap();
+
+        let identity = Identity::load_or_create(dir).unwrap();
+        let file = format!("{}/{}", dir, NODE_KEY_FILE);
+        let mode = fs::metadata(&file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(
+            Identity::load_or_create(dir).unwrap().public_hex(),
+            identity.public_hex()
+        );
+        let _ = fs::remove_dir_all(dir);
+    }
+}
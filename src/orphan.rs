@@ -0,0 +1,203 @@
+use super::*;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// A block or transaction waiting for its parents.
+pub struct Orphan<T> {
+    pub id: String,
+    pub item: T,
+    // hashes of the parents that were missing
+    pub parents: Vec<String>,
+    // the peer that sent it and is asked for the parents
+    pub peer_id: u64,
+    pub size: usize,
+    received: Instant,
+}
+
+/// Blocks or transactions whose parents we haven't seen yet, kept until the
+/// parents arrive. Bounded in count and total size, the oldest entries are
+/// evicted first and those older than `expiry` dropped.
+pub struct OrphanPool<T> {
+    orphans: HashMap<String, Orphan<T>>,
+    total_size: usize,
+    max_count: usize,
+    max_size: usize,
+    expiry: Duration,
+}
+
+impl<T> OrphanPool<T> {
+    pub fn new(max_count: usize, max_size: usize, expiry: Duration) -> OrphanPool<T> {
+        OrphanPool {
+            orphans: HashMap::new(),
+            total_size: 0,
+            max_count,
+            max_size,
+            expiry,
+        }
+    }
+
+    /// Keeps `item` received at `now`, returns false when it is too large.
+    pub fn add(
+        &mut self,
+        id: &str,
+        item: T,
+        parents: Vec<String>,
+        size: usize,
+        peer_id: u64,
+        now: Instant,
+    ) -> bool {
+        self.restore(
+            Orphan {
+                id: id.to_string(),
+                item,
+                parents,
+                peer_id,
+                size,
+                received: now,
+            },
+            now,
+        )
+    }
+
+    /// Puts back an orphan taken out too early, keeping its age.
+    pub fn restore(&mut self, orphan: Orphan<T>, now: Instant) -> bool {
+        if orphan.size > self.max_size {
+            return false;
+        }
+        if self.orphans.contains_key(&orphan.id) {
+            return true;
+        }
+        self.expire(now);
+        while self.orphans.len() >= self.max_count || self.total_size + orphan.size > self.max_size
+        {
+            let oldest = match self.orphans.values().min_by_key(|orphan| orphan.received) {
+                Some(oldest) => oldest.id.clone(),
+                None => break,
+            };
+            debug!("evict orphan {}", oldest);
+            self.remove(&oldest);
+        }
+        self.total_size += orphan.size;
+        self.orphans.insert(orphan.id.clone(), orphan);
+        true
+    }
+
+    /// Takes out the orphans waiting for `parent`.
+    pub fn take_children(&mut self, parent: &str) -> Vec<Orphan<T>> {
+        let children: Vec<String> = self
+            .orphans
+            .values()
+            .filter(|orphan| orphan.parents.iter().any(|p| p == parent))
+            .map(|orphan| orphan.id.clone())
+            .collect();
+        children.iter().filter_map(|id| self.remove(id)).collect()
+    }
+
+    pub fn take_all(&mut self) -> Vec<Orphan<T>> {
+        self.total_size = 0;
+        self.orphans.drain().map(|(_, orphan)| orphan).collect()
+    }
+
+    // --------- getter ---------
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.orphans.contains_key(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+
+    /* -------------------- inner function -------------------- */
+
+    fn remove(&mut self, id: &str) -> Option<Orphan<T>> {
+        let orphan = self.orphans.remove(id)?;
+        self.total_size -= orphan.size;
+        Some(orphan)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let expiry = self.expiry;
+        let expired: Vec<String> = self
+            .orphans
+            .values()
+            .filter(|orphan| now.saturating_duration_since(orphan.received) > expiry)
+            .map(|orphan| orphan.id.clone())
+            .collect();
+        for id in expired {
+            debug!("expire orphan {}", id);
+            self.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPIRY: Duration = Duration::from_secs(60);
+
+    fn add(pool: &mut OrphanPool<()>, id: &str, size: usize, now: Instant) -> bool {
+        pool.add(id, (), vec![format!("{}_parent", id)], size, 1, now)
+    }
+
+    #[test]
+    fn oldest_is_evicted_past_the_count() {
+        let start = Instant::now();
+        let mut pool = OrphanPool::new(2, 1000, EXPIRY);
+        add(&mut pool, "a", 10, start);
+        add(&mut pool, "b", 10, start + Duration::from_secs(1));
+        add(&mut pool, "c", 10, start + Duration::from_secs(2));
+
+        assert_eq!(pool.len(), 2);
+        assert!(!pool.contains("a"));
+        assert!(pool.contains("b") && pool.contains("c"));
+    }
+
+    #[test]
+    fn oldest_are_evicted_past_the_size() {
+        let start = Instant::now();
+        let mut pool = OrphanPool::new(10, 100, EXPIRY);
+        add(&mut pool, "a", 40, start);
+        add(&mut pool, "b", 40, start + Duration::from_secs(1));
+        add(&mut pool, "c", 40, start + Duration::from_secs(2));
+
+        assert!(!pool.contains("a"));
+        assert!(pool.contains("b") && pool.contains("c"));
+        // bigger than the whole pool
+        assert!(!add(&mut pool, "d", 101, start + Duration::from_secs(3)));
+        assert_eq!(pool.len(), 2);
+    }
+
+    #[test]
+    fn old_orphans_expire() {
+        let start = Instant::now();
+        let mut pool = OrphanPool::new(10, 1000, EXPIRY);
+        add(&mut pool, "a", 10, start);
+        add(&mut pool, "b", 10, start + EXPIRY + Duration::from_secs(1));
+
+        assert!(!pool.contains("a"));
+        assert!(pool.contains("b"));
+    }
+
+    #[test]
+    fn children_are_taken_out_with_their_size() {
+        let start = Instant::now();
+        let mut pool = OrphanPool::new(10, 100, EXPIRY);
+        add(&mut pool, "a", 60, start);
+        let children = pool.take_children("a_parent");
+
+        assert_eq!(children.len(), 1);
+        assert!(pool.is_empty());
+        // its size is free again
+        add(&mut pool, "b", 60, start);
+        add(&mut pool, "c", 40, start);
+        assert_eq!(pool.len(), 2);
+    }
+}
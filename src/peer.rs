@@ -0,0 +1,267 @@
+use anyhow::format_err;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt, BufWriter},
+    sync::mpsc::{self, error::TrySendError},
+};
+use tokio_util::sync::CancellationToken;
+
+use super::*;
+use crate::{noise::*, protocol::*, ratelimit::PeerLimits};
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+// messages queued for a peer before it is considered too slow and dropped
+const SEND_QUEUE_LEN: usize = 1000;
+// block and transaction hashes remembered per peer to avoid echoing them back
+const MAX_KNOWN_INVENTORY: usize = 5000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Handle to a live peer session. The socket is written by `write_loop` fed
+/// through a bounded queue, so sending never blocks the caller.
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub id: u64,
+    // the address the peer listens on, only claimed by inbound peers and
+    // empty for one-shot clients
+    pub addr: String,
+    pub direction: Direction,
+    // remote ip of the socket, what bans apply to
+    pub ip: String,
+    // filled in from the peer's version message
+    pub version: i32,
+    pub services: u64,
+    pub user_agent: String,
+    pub start_height: i32,
+    // set once both sides have exchanged version and verack
+    pub handshaked: bool,
+    // static key the peer proved in an encrypted session, none in plaintext
+    pub remote_key: Option<String>,
+    pub best_height: i32,
+    pub connected_at: Instant,
+    pub last_recv: Instant,
+    pub last_ping: Instant,
+    // nonce and send time of the ping still waiting for its pong
+    pub ping: Option<(u64, Instant)>,
+    pub latency: Option<Duration>,
+    // accumulated penalty for invalid data, the peer is banned at BAN_SCORE
+    pub misbehavior: i32,
+    pub bytes_sent: Arc<AtomicU64>,
+    pub bytes_recv: Arc<AtomicU64>,
+    // hashes the peer sent us or we announced to it, shared by all clones
+    known_inventory: Arc<Mutex<KnownInventory>>,
+    // budgets of what the peer sends us, shared by all clones
+    limits: Arc<Mutex<PeerLimits>>,
+    sender: mpsc::Sender<Message>,
+    // cancelled to end the session, stops both its read and write task
+    cancel: CancellationToken,
+}
+
+impl Peer {
+    /// Returns the handle to a new session from `ip` opened at `now` and the
+    /// receiving end of its send queue, to be drained by `write_loop`.
+    pub fn new(
+        id: u64,
+        addr: &str,
+        direction: Direction,
+        ip: &str,
+        cancel: CancellationToken,
+        now: Instant,
+    ) -> (Peer, mpsc::Receiver<Message>) {
+        let (sender, receiver) = mpsc::channel(SEND_QUEUE_LEN);
+        let peer = Peer {
+            id,
+            addr: addr.to_string(),
+            direction,
+            ip: ip.to_string(),
+            version: 0,
+            services: 0,
+            user_agent: String::new(),
+            start_height: -1,
+            handshaked: false,
+            remote_key: None,
+            best_height: -1,
+            connected_at: now,
+            last_recv: now,
+            last_ping: now,
+            ping: None,
+            latency: None,
+            misbehavior: 0,
+            bytes_sent: Arc::new(AtomicU64::new(0)),
+            bytes_recv: Arc::new(AtomicU64::new(0)),
+            known_inventory: Arc::new(Mutex::new(KnownInventory::new())),
+            limits: Arc::new(Mutex::new(PeerLimits::new(now))),
+            sender,
+            cancel,
+        };
+        (peer, receiver)
+    }
+
+    pub fn send(&self, msg: Message) -> Result<()> {
+        match self.sender.try_send(msg) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.disconnect();
+                Err(format_err!("Peer {} send queue is full", self.id))
+            }
+            Err(TrySendError::Closed(_)) => Err(format_err!("Peer {} is disconnected", self.id)),
+        }
+    }
+
+    pub fn add_known(&self, hash: &str) {
+        self.known_inventory.lock().unwrap().insert(hash);
+    }
+
+    pub fn knows(&self, hash: &str) -> bool {
+        self.known_inventory.lock().unwrap().contains(hash)
+    }
+
+    /// Charges a received `msg` of `len` bytes to the peer's budgets.
+    pub fn charge(&self, msg: &Message, len: usize, now: Instant) -> Result<()> {
+        self.limits.lock().unwrap().charge(msg.command(), len, now)
+    }
+
+    pub fn disconnect(&self) {
+        self.cancel.cancel();
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// Resolves once the session is being torn down.
+    pub async fn disconnected(&self) {
+        self.cancel.cancelled().await
+    }
+
+    pub fn info(&self) -> PeerInfo {
+        PeerInfo {
+            id: self.id,
+            addr: self.addr.clone(),
+            inbound: self.direction == Direction::Inbound,
+            version: self.version,
+            user_agent: self.user_agent.clone(),
+            latency_ms: self.latency.map(|latency| latency.as_millis() as u64),
+            best_height: self.best_height,
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_recv: self.bytes_recv.load(Ordering::Relaxed),
+            misbehavior: self.misbehavior,
+            remote_key: self.remote_key.clone(),
+        }
+    }
+}
+
+/// Handshake progress of a session on its receiving side: version first,
+/// then verack, then only messages of the negotiated version.
+#[derive(Debug, Default)]
+pub struct SessionState {
+    got_version: bool,
+    got_verack: bool,
+    version: i32,
+}
+
+impl SessionState {
+    /// Fails on a message out of turn, which ends the session.
+    pub fn receive(&mut self, msg: &Message) -> Result<()> {
+        let allowed = match msg {
+            Message::Version(_) => !self.got_version,
+            Message::Verack => self.got_version && !self.got_verack,
+            _ => self.got_verack && msg.min_version() <= self.version,
+        };
+        if !allowed {
+            return Err(format_err!("unexpected {} message", msg.command()));
+        }
+        match msg {
+            Message::Version(data) => {
+                self.version = data.version.min(PROTOCOL_VERSION);
+                self.got_version = true;
+            }
+            Message::Verack => self.got_verack = true,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Set of hashes that forgets the oldest once `MAX_KNOWN_INVENTORY` is reached.
+#[derive(Debug)]
+struct KnownInventory {
+    hashs: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl KnownInventory {
+    fn new() -> KnownInventory {
+        KnownInventory {
+            hashs: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn insert(&mut self, hash: &str) {
+        if !self.hashs.insert(hash.to_string()) {
+            return;
+        }
+        self.order.push_back(hash.to_string());
+        if self.order.len() > MAX_KNOWN_INVENTORY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.hashs.remove(&oldest);
+            }
+        }
+    }
+
+    fn contains(&self, hash: &str) -> bool {
+        self.hashs.contains(hash)
+    }
+}
+
+/// Writes the queued messages of `peer` to its socket until the session is
+/// cancelled, sealed with `cipher` in an encrypted session. A failed write
+/// ends the whole session.
+pub async fn write_loop<W: AsyncWrite + Unpin>(
+    writer: W,
+    mut receiver: mpsc::Receiver<Message>,
+    peer: Peer,
+    mut cipher: Option<CipherState>,
+) {
+    let mut writer = BufWriter::new(writer);
+    loop {
+        let msg = tokio::select! {
+            _ = peer.disconnected() => break,
+            msg = receiver.recv() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+        };
+        let written = tokio::select! {
+            _ = peer.disconnected() => break,
+            written = async {
+                match &mut cipher {
+                    Some(cipher) => write_encrypted(&mut writer, cipher, &msg).await,
+                    None => write_message_async(&mut writer, &msg).await,
+                }
+            } => written,
+        };
+        match written {
+            Ok(len) => {
+                peer.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+            }
+            Err(e) => {
+                info!("write {} failed: {}", msg.command(), e);
+                break;
+            }
+        }
+    }
+    peer.disconnect();
+    let _ = writer.shutdown().await;
+}
@@ -0,0 +1,714 @@
+use anyhow::format_err;
+use bincode::{deserialize, serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::*;
+use crate::{
+    banman::BanEntry,
+    block::{Block, BlockHeader},
+    compact::CompactBlock,
+    transaction::Transaction,
+};
+use std::{
+    fmt,
+    io::{ErrorKind, Read, Write},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const NETWORK_MAGIC: [u8; 4] = *b"BCRS";
+pub const CMD_LEN: usize = 12;
+// magic + command + payload length + payload checksum
+pub const HEADER_LEN: usize = 4 + CMD_LEN + 4 + 4;
+pub const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+// most headers sent in reply to one getheaders
+pub const MAX_HEADERS_RESULTS: usize = 2000;
+// most block hashes sent in reply to one getblocks
+pub const MAX_BLOCKS_INV: usize = 500;
+// most items an inv message may carry
+pub const MAX_INV_SIZE: usize = 1000;
+
+// protocol version spoken by this node, peers negotiate down to the lower one
+pub const PROTOCOL_VERSION: i32 = 9;
+// oldest peer version we still talk to, older ones can't read our inventory
+pub const MIN_PEER_VERSION: i32 = INVENTORY_VERSION;
+// first version with ping/pong keepalive and peer info
+pub const PING_VERSION: i32 = 3;
+// first version with the ban list commands
+pub const BAN_VERSION: i32 = 4;
+// first version that answers getaddr
+pub const ADDR_VERSION: i32 = 5;
+// first version with headers-first sync
+pub const HEADERS_VERSION: i32 = 6;
+// first version with compact block relay
+pub const COMPACT_VERSION: i32 = 7;
+// first version with typed inventory items and notfound
+pub const INVENTORY_VERSION: i32 = 8;
+// first version that tells why it rejected a transaction or block
+pub const REJECT_VERSION: i32 = 9;
+
+// services bitmap: the node stores and serves the full chain
+pub const NODE_NETWORK: u64 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Message {
+    Addr(Vec<String>),
+    GetAddr,
+    Version(Versionmsg),
+    Tx(Txmsg),
+    GetData(GetDatamsg),
+    GetBlock(GetBlocksmsg),
+    Inv(Invmsg),
+    NotFound(Vec<InvItem>),
+    Reject(Rejectmsg),
+    Block(Blockmsg),
+    GetHeaders(GetHeadersmsg),
+    Headers(Vec<BlockHeader>),
+    CmpctBlock(CompactBlock),
+    GetBlockTxn(GetBlockTxnmsg),
+    BlockTxn(BlockTxnmsg),
+    Verack,
+    Ping(u64),
+    Pong(u64),
+    SaveMempool,
+    Stop,
+    GetPeerInfo,
+    PeerInfo(Vec<PeerInfo>),
+    SetBan(SetBanmsg),
+    ListBanned,
+    Banned(Vec<BanEntry>),
+    ClearBanned,
+    GetRejects,
+    Rejects(Vec<RejectEntry>),
+}
+
+/// A frame that can't be decoded. After `BadMagic` or `Oversize` the stream
+/// is out of step, the other errors consume the whole frame.
+#[derive(Debug)]
+pub enum FrameError {
+    BadMagic,
+    Oversize(usize),
+    BadChecksum,
+    UnknownCommand(String),
+    Malformed(String),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::BadMagic => write!(f, "Invalid network magic"),
+            FrameError::Oversize(len) => write!(f, "Message is too large: {} bytes", len),
+            FrameError::BadChecksum => write!(f, "Message checksum mismatch"),
+            FrameError::UnknownCommand(cmd) => write!(f, "Unknown command: {}", cmd),
+            FrameError::Malformed(e) => write!(f, "Malformed message: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Blockmsg {
+    pub addr_from: String,
+    pub block: Block,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetBlocksmsg {
+    pub addr_from: String,
+    // our chain from the tip back, see Blockchain::get_block_locator
+    pub locator: Vec<String>,
+    // last block wanted, empty for as many as allowed
+    pub stop_hash: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetHeadersmsg {
+    // our chain from the tip back, see Blockchain::get_block_locator
+    pub locator: Vec<String>,
+    // last header wanted, empty for as many as allowed
+    pub stop_hash: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetBlockTxnmsg {
+    pub block_hash: String,
+    // positions in the block of the transactions wanted
+    pub indexes: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockTxnmsg {
+    pub block_hash: String,
+    // in the order they were asked for
+    pub transactions: Vec<Transaction>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetDatamsg {
+    pub addr_from: String,
+    pub items: Vec<InvItem>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Invmsg {
+    pub addr_from: String,
+    pub items: Vec<InvItem>,
+}
+
+/// A block hash or transaction id, sent as its 32 bytes rather than hex.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Hash256(pub [u8; 32]);
+
+impl Hash256 {
+    pub fn from_hex(hex: &str) -> Result<Hash256> {
+        if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(format_err!("Invalid hash {:?}", hex));
+        }
+        let mut hash = [0u8; 32];
+        for (i, byte) in hash.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+                .map_err(|_| format_err!("Invalid hash {:?}", hex))?;
+        }
+        Ok(Hash256(hash))
+    }
+
+    pub fn to_hex(self) -> String {
+        self.0.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+impl fmt::Display for Hash256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
+
+impl fmt::Debug for Hash256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
+
+/// What an inventory item names, a `u32` code on the wire. Codes this node
+/// doesn't know decode as `Unknown`, so the message is still read and the
+/// sender scored for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InvKind {
+    Tx,
+    Block,
+    Unknown(u32),
+}
+
+impl InvKind {
+    pub fn code(self) -> u32 {
+        match self {
+            InvKind::Tx => 1,
+            InvKind::Block => 2,
+            InvKind::Unknown(code) => code,
+        }
+    }
+
+    pub fn from_code(code: u32) -> InvKind {
+        match code {
+            1 => InvKind::Tx,
+            2 => InvKind::Block,
+            code => InvKind::Unknown(code),
+        }
+    }
+}
+
+impl fmt::Display for InvKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvKind::Tx => write!(f, "tx"),
+            InvKind::Block => write!(f, "block"),
+            InvKind::Unknown(code) => write!(f, "unknown kind {}", code),
+        }
+    }
+}
+
+impl Serialize for InvKind {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_u32(self.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for InvKind {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        u32::deserialize(deserializer).map(InvKind::from_code)
+    }
+}
+
+/// Why a transaction or block was turned down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RejectCode {
+    // breaks consensus rules
+    Invalid,
+    // already known
+    Duplicate,
+    // spends outputs already spent in the chain or the mempool
+    Conflict,
+    // breaks local policy: size, dust or unconfirmed chain limits
+    NonStandard,
+    // pays too little to enter the mempool or replace what it conflicts with
+    InsufficientFee,
+    // the peer doesn't have it, what a notfound item is recorded as
+    NotFound,
+}
+
+impl fmt::Display for RejectCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = match self {
+            RejectCode::Invalid => "invalid",
+            RejectCode::Duplicate => "duplicate",
+            RejectCode::Conflict => "conflict",
+            RejectCode::NonStandard => "nonstandard",
+            RejectCode::InsufficientFee => "insufficient-fee",
+            RejectCode::NotFound => "notfound",
+        };
+        write!(f, "{}", code)
+    }
+}
+
+/// Sent back to the peer whose transaction or block we turned down.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rejectmsg {
+    pub item: InvItem,
+    pub code: RejectCode,
+    pub reason: String,
+}
+
+/// A reject or notfound a peer sent us, as listed by getrejects.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RejectEntry {
+    pub time: i64,
+    // address of the peer, its ip when it doesn't listen
+    pub peer: String,
+    pub item: InvItem,
+    pub code: RejectCode,
+    pub reason: String,
+}
+
+/// A block or transaction announced in an inv, asked for in a getdata or
+/// missing in a notfound.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct InvItem {
+    pub kind: InvKind,
+    pub hash: Hash256,
+}
+
+impl InvItem {
+    /// Item of `kind` named by the hex `hash` our chain and mempool use.
+    pub fn new(kind: InvKind, hash: &str) -> Result<InvItem> {
+        Ok(InvItem {
+            kind,
+            hash: Hash256::from_hex(hash)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Txmsg {
+    pub addr_from: String,
+    pub transaction: Transaction,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetBanmsg {
+    pub ip: String,
+    // false lifts an existing ban
+    pub add: bool,
+    // seconds, only used when adding
+    pub ban_time: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub id: u64,
+    pub addr: String,
+    pub inbound: bool,
+    pub version: i32,
+    pub user_agent: String,
+    pub latency_ms: Option<u64>,
+    pub best_height: i32,
+    pub bytes_sent: u64,
+    pub bytes_recv: u64,
+    pub misbehavior: i32,
+    pub remote_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Versionmsg {
+    pub addr_from: String,
+    pub version: i32,
+    pub services: u64,
+    pub user_agent: String,
+    // random per node, lets a node notice it connected to itself
+    pub nonce: u64,
+    pub timestamp: i64,
+    pub best_height: i32,
+}
+
+impl Message {
+    pub fn command(&self) -> &'static str {
+        match self {
+            Message::Addr(_) => "addr",
+            Message::GetAddr => "getaddr",
+            Message::Version(_) => "version",
+            Message::Tx(_) => "tx",
+            Message::GetData(_) => "getdata",
+            Message::GetBlock(_) => "getblocks",
+            Message::Inv(_) => "inv",
+            Message::NotFound(_) => "notfound",
+            Message::Reject(_) => "reject",
+            Message::Block(_) => "block",
+            Message::GetHeaders(_) => "getheaders",
+            Message::Headers(_) => "headers",
+            Message::CmpctBlock(_) => "cmpctblock",
+            Message::GetBlockTxn(_) => "getblocktxn",
+            Message::BlockTxn(_) => "blocktxn",
+            Message::Verack => "verack",
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+            Message::SaveMempool => "savemempool",
+            Message::Stop => "stop",
+            Message::GetPeerInfo => "getpeerinfo",
+            Message::PeerInfo(_) => "peerinfo",
+            Message::SetBan(_) => "setban",
+            Message::ListBanned => "listbanned",
+            Message::Banned(_) => "banned",
+            Message::ClearBanned => "clearbanned",
+            Message::GetRejects => "getrejects",
+            Message::Rejects(_) => "rejects",
+        }
+    }
+
+    /// Lowest negotiated protocol version a message may be exchanged at.
+    pub fn min_version(&self) -> i32 {
+        match self {
+            Message::Version(_) | Message::Verack => 0,
+            Message::Ping(_) | Message::Pong(_) | Message::GetPeerInfo | Message::PeerInfo(_) => {
+                PING_VERSION
+            }
+            Message::SetBan(_)
+            | Message::ListBanned
+            | Message::Banned(_)
+            | Message::ClearBanned => BAN_VERSION,
+            Message::GetAddr => ADDR_VERSION,
+            Message::GetHeaders(_) | Message::Headers(_) => HEADERS_VERSION,
+            Message::CmpctBlock(_) | Message::GetBlockTxn(_) | Message::BlockTxn(_) => {
+                COMPACT_VERSION
+            }
+            Message::NotFound(_) => INVENTORY_VERSION,
+            Message::Reject(_) | Message::GetRejects | Message::Rejects(_) => REJECT_VERSION,
+            _ => MIN_PEER_VERSION,
+        }
+    }
+
+    /// Whether the message administers the node, which only clients on the
+    /// same machine may do.
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            Message::SaveMempool
+                | Message::Stop
+                | Message::GetPeerInfo
+                | Message::SetBan(_)
+                | Message::ListBanned
+                | Message::ClearBanned
+                | Message::GetRejects
+        )
+    }
+
+    fn payload(&self) -> Result<Vec<u8>> {
+        let data = match self {
+            Message::Addr(data) => serialize(data)?,
+            Message::Version(data) => serialize(data)?,
+            Message::Tx(data) => serialize(data)?,
+            Message::GetData(data) => serialize(data)?,
+            Message::GetBlock(data) => serialize(data)?,
+            Message::Inv(data) => serialize(data)?,
+            Message::NotFound(data) => serialize(data)?,
+            Message::Reject(data) => serialize(data)?,
+            Message::Rejects(data) => serialize(data)?,
+            Message::Block(data) => serialize(data)?,
+            Message::GetHeaders(data) => serialize(data)?,
+            Message::Headers(data) => serialize(data)?,
+            Message::CmpctBlock(data) => serialize(data)?,
+            Message::GetBlockTxn(data) => serialize(data)?,
+            Message::BlockTxn(data) => serialize(data)?,
+            Message::Ping(nonce) | Message::Pong(nonce) => serialize(nonce)?,
+            Message::PeerInfo(data) => serialize(data)?,
+            Message::SetBan(data) => serialize(data)?,
+            Message::Banned(data) => serialize(data)?,
+            Message::Verack
+            | Message::GetAddr
+            | Message::SaveMempool
+            | Message::Stop
+            | Message::GetPeerInfo
+            | Message::ListBanned
+            | Message::ClearBanned
+            | Message::GetRejects => Vec::new(),
+        };
+        Ok(data)
+    }
+
+    fn from_payload(cmd: &str, data: &[u8]) -> Result<Message> {
+        let msg = match cmd {
+            "addr" => Message::Addr(deserialize(data)?),
+            "getaddr" => Message::GetAddr,
+            "version" => Message::Version(deserialize(data)?),
+            "tx" => Message::Tx(deserialize(data)?),
+            "getdata" => Message::GetData(deserialize(data)?),
+            "getblocks" => Message::GetBlock(deserialize(data).or_else(|_| {
+                // older peers only send their address
+                deserialize(data).map(|addr_from| GetBlocksmsg {
+                    addr_from,
+                    locator: Vec::new(),
+                    stop_hash: String::new(),
+                })
+            })?),
+            "inv" => Message::Inv(deserialize(data)?),
+            "notfound" => Message::NotFound(deserialize(data)?),
+            "reject" => Message::Reject(deserialize(data)?),
+            "block" => Message::Block(deserialize(data)?),
+            "getheaders" => Message::GetHeaders(deserialize(data)?),
+            "headers" => Message::Headers(deserialize(data)?),
+            "cmpctblock" => Message::CmpctBlock(deserialize(data)?),
+            "getblocktxn" => Message::GetBlockTxn(deserialize(data)?),
+            "blocktxn" => Message::BlockTxn(deserialize(data)?),
+            "verack" => Message::Verack,
+            "ping" => Message::Ping(deserialize(data)?),
+            "pong" => Message::Pong(deserialize(data)?),
+            "savemempool" => Message::SaveMempool,
+            "stop" => Message::Stop,
+            "getpeerinfo" => Message::GetPeerInfo,
+            "peerinfo" => Message::PeerInfo(deserialize(data)?),
+            "setban" => Message::SetBan(deserialize(data)?),
+            "listbanned" => Message::ListBanned,
+            "banned" => Message::Banned(deserialize(data)?),
+            "clearbanned" => Message::ClearBanned,
+            "getrejects" => Message::GetRejects,
+            "rejects" => Message::Rejects(deserialize(data)?),
+            _ => return Err(FrameError::UnknownCommand(cmd.to_string()).into()),
+        };
+        Ok(msg)
+    }
+}
+
+/// Encodes `msg` as one frame: magic, command, payload length, payload
+/// checksum, then the bincode payload.
+pub fn encode_message(msg: &Message) -> Result<Vec<u8>> {
+    let payload = msg.payload()?;
+    if payload.len() > MAX_MESSAGE_SIZE {
+        return Err(format_err!(
+            "Message {} is too large: {} bytes",
+            msg.command(),
+            payload.len()
+        ));
+    }
+
+    let mut data = Vec::with_capacity(HEADER_LEN + payload.len());
+    data.extend_from_slice(&NETWORK_MAGIC);
+    data.extend_from_slice(&cmd_to_bytes(msg.command()));
+    data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    data.extend_from_slice(&checksum(&payload));
+    data.extend_from_slice(&payload);
+    Ok(data)
+}
+
+pub fn write_message<W: Write>(writer: &mut W, msg: &Message) -> Result<()> {
+    writer.write_all(&encode_message(msg)?)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads the next frame from `reader`. Returns `None` once the peer closed
+/// the connection between two frames. Undecodable frames fail with a
+/// `FrameError`.
+pub fn read_message<R: Read>(reader: &mut R) -> Result<Option<Message>> {
+    let mut header = [0u8; HEADER_LEN];
+    if let Err(e) = reader.read_exact(&mut header) {
+        if e.kind() == ErrorKind::UnexpectedEof {
+            return Ok(None);
+        }
+        return Err(e.into());
+    }
+
+    let (payload_len, expected) = parse_header(&header)?;
+    let payload = read_payload(reader, payload_len)?;
+    decode_frame(&header, &payload, expected).map(Some)
+}
+
+/// Writes `msg` to an async stream, returning the frame size.
+pub async fn write_message_async<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    msg: &Message,
+) -> Result<usize> {
+    let data = encode_message(msg)?;
+    writer.write_all(&data).await?;
+    writer.flush().await?;
+    Ok(data.len())
+}
+
+/// Like `read_message` but from an async stream, also returning the frame
+/// size.
+pub async fn read_message_async<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Option<(Message, usize)>> {
+    let mut header = [0u8; HEADER_LEN];
+    if let Err(e) = reader.read_exact(&mut header).await {
+        if e.kind() == ErrorKind::UnexpectedEof {
+            return Ok(None);
+        }
+        return Err(e.into());
+    }
+
+    let (payload_len, expected) = parse_header(&header)?;
+    let payload = read_payload_async(reader, payload_len).await?;
+    let msg = decode_frame(&header, &payload, expected)?;
+    Ok(Some((msg, HEADER_LEN + payload_len)))
+}
+
+/// Reads the `len` bytes of a payload. The buffer grows as they arrive, so
+/// a header announcing a large payload doesn't allocate it up front.
+fn read_payload<R: Read>(reader: &mut R, len: usize) -> Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() < len {
+        return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+    Ok(payload)
+}
+
+/// Like `read_payload` but from an async stream.
+pub async fn read_payload_async<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    len: usize,
+) -> Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload).await?;
+    if payload.len() < len {
+        return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+    Ok(payload)
+}
+
+/// Decodes the payload of a frame whose header passed `parse_header`.
+fn decode_frame(header: &[u8; HEADER_LEN], payload: &[u8], expected: [u8; 4]) -> Result<Message> {
+    if checksum(payload) != expected {
+        return Err(FrameError::BadChecksum.into());
+    }
+
+    let cmd =
+        bytes_to_cmd(&header[4..4 + CMD_LEN]).map_err(|e| FrameError::Malformed(e.to_string()))?;
+    info!("cmd: {}", cmd);
+    match Message::from_payload(&cmd, payload) {
+        Ok(msg) => Ok(msg),
+        Err(e) if e.is::<FrameError>() => Err(e),
+        Err(e) => Err(FrameError::Malformed(format!("{} payload: {}", cmd, e)).into()),
+    }
+}
+
+/// Checks the magic and size of a frame header, returning the payload length
+/// and checksum it announces.
+pub fn parse_header(header: &[u8; HEADER_LEN]) -> Result<(usize, [u8; 4])> {
+    if header[..4] != NETWORK_MAGIC {
+        return Err(FrameError::BadMagic.into());
+    }
+
+    let mut len = [0u8; 4];
+    len.copy_from_slice(&header[4 + CMD_LEN..8 + CMD_LEN]);
+    let payload_len = u32::from_le_bytes(len) as usize;
+    if payload_len > MAX_MESSAGE_SIZE {
+        return Err(FrameError::Oversize(payload_len).into());
+    }
+
+    let mut sum = [0u8; 4];
+    sum.copy_from_slice(&header[8 + CMD_LEN..]);
+    Ok((payload_len, sum))
+}
+
+/// First four bytes of the payload's sha256.
+pub fn checksum(payload: &[u8]) -> [u8; 4] {
+    let digest = sha256::digest(payload);
+    let mut sum = [0u8; 4];
+    for (i, b) in sum.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&digest[i * 2..i * 2 + 2], 16).unwrap();
+    }
+    sum
+}
+
+fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LEN] {
+    let mut data = [0; CMD_LEN];
+    for (i, d) in cmd.as_bytes().iter().enumerate() {
+        data[i] = *d;
+    }
+    data
+}
+
+fn bytes_to_cmd(bytes: &[u8]) -> Result<String> {
+    let mut cmd = Vec::new();
+    for b in bytes {
+        if 0_u8 != *b {
+            cmd.push(*b);
+        }
+    }
+    Ok(String::from_utf8(cmd)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn read(frame: &[u8]) -> Result<Option<Message>> {
+        read_message(&mut Cursor::new(frame))
+    }
+
+    fn frame_error(e: anyhow::Error) -> FrameError {
+        e.downcast::<FrameError>().unwrap()
+    }
+
+    #[test]
+    fn frame_reads_back() {
+        let frame = encode_message(&Message::Ping(7)).unwrap();
+        assert!(matches!(read(&frame).unwrap(), Some(Message::Ping(7))));
+        assert!(read(&[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_payload_is_an_unexpected_eof() {
+        let frame = encode_message(&Message::Ping(7)).unwrap();
+        let e = read(&frame[..frame.len() - 1]).unwrap_err();
+        let e = e.downcast::<std::io::Error>().unwrap();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn payload_must_match_its_checksum() {
+        let mut frame = encode_message(&Message::Ping(7)).unwrap();
+        *frame.last_mut().unwrap() ^= 1;
+        let e = frame_error(read(&frame).unwrap_err());
+        assert!(matches!(e, FrameError::BadChecksum));
+    }
+
+    #[test]
+    fn oversized_length_is_refused() {
+        let mut frame = encode_message(&Message::Ping(7)).unwrap();
+        let len = (MAX_MESSAGE_SIZE as u32 + 1).to_le_bytes();
+        frame[4 + CMD_LEN..8 + CMD_LEN].copy_from_slice(&len);
+        let e = frame_error(read(&frame).unwrap_err());
+        assert!(matches!(e, FrameError::Oversize(size) if size == MAX_MESSAGE_SIZE + 1));
+    }
+
+    #[test]
+    fn announced_length_is_read_as_it_arrives() {
+        let mut frame = encode_message(&Message::Ping(7)).unwrap();
+        let len = (MAX_MESSAGE_SIZE as u32).to_le_bytes();
+        frame[4 + CMD_LEN..8 + CMD_LEN].copy_from_slice(&len);
+        let e = read(&frame).unwrap_err();
+        let e = e.downcast::<std::io::Error>().unwrap();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+}
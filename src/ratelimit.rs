@@ -0,0 +1,125 @@
+use super::*;
+use crate::protocol::{HEADER_LEN, MAX_MESSAGE_SIZE};
+use std::{collections::HashMap, fmt, time::Instant};
+
+// messages of any kind a peer may send in a burst, and per second after
+const MESSAGE_BURST: f64 = 500.0;
+const MESSAGE_RATE: f64 = 100.0;
+// bytes a peer may send in a burst, room for two messages of the largest
+// size, and per second after
+const BYTE_BURST: f64 = (2 * (HEADER_LEN + MAX_MESSAGE_SIZE)) as f64;
+const BYTE_RATE: f64 = 4_000_000.0;
+
+/// A message over the budget of its peer, dropped unread.
+#[derive(Debug)]
+pub struct RateLimited(pub String);
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+/// Tokens refilled at a steady rate up to a burst capacity. An action costs
+/// tokens and is refused when too few are left.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    // tokens added per second
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket of `capacity` tokens as of `now`.
+    pub fn new(capacity: f64, rate: f64, now: Instant) -> TokenBucket {
+        TokenBucket {
+            capacity,
+            rate,
+            tokens: capacity,
+            last: now,
+        }
+    }
+
+    /// Takes `cost` tokens if there are that many.
+    pub fn take(&mut self, cost: f64, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens < cost {
+            return false;
+        }
+        self.tokens -= cost;
+        true
+    }
+
+    /* -------------------- inner function -------------------- */
+
+    fn refill(&mut self, now: Instant) {
+        if now <= self.last {
+            return;
+        }
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+}
+
+/// What one peer may send: messages and bytes overall, and messages of the
+/// kinds that cost us work or memory each on their own budget.
+#[derive(Debug)]
+pub struct PeerLimits {
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    commands: HashMap<&'static str, TokenBucket>,
+}
+
+impl PeerLimits {
+    pub fn new(now: Instant) -> PeerLimits {
+        PeerLimits {
+            messages: TokenBucket::new(MESSAGE_BURST, MESSAGE_RATE, now),
+            bytes: TokenBucket::new(BYTE_BURST, BYTE_RATE, now),
+            commands: HashMap::new(),
+        }
+    }
+
+    /// Charges a `command` message of `len` bytes, failing with
+    /// `RateLimited` when it is over a budget.
+    pub fn charge(&mut self, command: &'static str, len: usize, now: Instant) -> Result<()> {
+        if !self.messages.take(1.0, now) {
+            return Err(RateLimited(String::from("too many messages")).into());
+        }
+        if !self.bytes.take(len as f64, now) {
+            return Err(RateLimited(String::from("too many bytes")).into());
+        }
+        if let Some((burst, rate)) = command_limit(command) {
+            let bucket = self
+                .commands
+                .entry(command)
+                .or_insert_with(|| TokenBucket::new(burst, rate, now));
+            if !bucket.take(1.0, now) {
+                return Err(RateLimited(format!("too many {} messages", command)).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Burst and messages per second allowed of `command`, none for those only
+/// bound by the overall budget.
+fn command_limit(command: &str) -> Option<(f64, f64)> {
+    let limit = match command {
+        "tx" => (200.0, 50.0),
+        "inv" => (200.0, 50.0),
+        "getdata" => (500.0, 100.0),
+        "getblocktxn" => (50.0, 10.0),
+        "getheaders" | "getblocks" => (20.0, 2.0),
+        "addr" => (10.0, 0.1),
+        "getaddr" => (2.0, 0.01),
+        "ping" => (10.0, 1.0),
+        "reject" => (100.0, 10.0),
+        _ => return None,
+    };
+    Some(limit)
+}
@@ -1,543 +1,2609 @@
 use anyhow::format_err;
-use bincode::{deserialize, serialize};
-use serde::{Deserialize, Serialize};
+use bincode::serialize;
+use chrono::prelude::*;
+use rand::{rngs::OsRng, RngCore};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::TcpStream as AsyncTcpStream,
+    sync::mpsc,
+    time,
+};
+use tokio_util::sync::CancellationToken;
 
 use super::*;
-use crate::{block::Block, transaction::Transaction, utxoset::UTXOSet};
+use crate::{
+    addrman::{AddrMan, MAX_ADDR_TO_SEND},
+    banman::{BanEntry, BanManager, DEFAULT_BAN_TIME},
+    block::Block,
+    block::BlockHeader,
+    blockchain::{InvalidBlock, DATA_DIR},
+    compact::{CompactBlock, PartialBlock},
+    mempool::{reject_code, InvalidTransaction, Mempool, MissingInputs},
+    miner::{BlockTemplate, Miner},
+    noise::{self, CipherState, Identity, Session, NOISE_MAGIC},
+    orphan::{Orphan, OrphanPool},
+    peer::{write_loop, Direction, Peer, SessionState},
+    protocol::*,
+    ratelimit::RateLimited,
+    sync::{BlockSync, InvalidHeader},
+    transaction::{Transaction, SUBSIDY},
+    transport::{Clock, TcpTransport, Transport},
+    utxoset::UTXOSet,
+};
 use std::{
-    collections::{HashMap, HashSet},
-    io::{Read, Write},
-    net::{TcpListener, TcpStream},
-    sync::{Arc, Mutex},
-    thread,
-    time::Duration,
+    collections::{HashMap, HashSet, VecDeque},
+    io::{BufReader, BufWriter, Cursor},
+    net::{IpAddr, TcpStream},
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::{Duration, Instant},
 };
 
-const KNOWN_NODE1: &str = "localhost:3000";
-const CMD_LEN: usize = 12;
-const VERSION: i32 = 1;
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-enum Message {
-    Addr(Vec<String>),
-    Version(Versionmsg),
-    Tx(Txmsg),
-    GetData(GetDatamsg),
-    GetBlock(GetBlocksmsg),
-    Inv(Invmsg),
-    Block(Blockmsg),
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Blockmsg {
-    addr_from: String,
-    block: Block,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct GetBlocksmsg {
-    addr_from: String,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct GetDatamsg {
-    addr_from: String,
-    kind: String,
-    id: String,
+// seed node used when none is configured, and where clients submit transactions
+const DEFAULT_NODE: &str = "localhost:3000";
+const MAX_INBOUND_CONNECTIONS: usize = 32;
+const MAX_OUTBOUND_CONNECTIONS: usize = 8;
+// how often the node tops up its outbound connections
+const CONNECT_INTERVAL: Duration = Duration::from_secs(10);
+// how often peer liveness is checked
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+const PING_INTERVAL: Duration = Duration::from_secs(60);
+// a peer that doesn't answer a ping or finish its handshake in time is dropped
+const PING_TIMEOUT: Duration = Duration::from_secs(30);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+// how long dialling a node may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// messages read from peers waiting for the chain actor, readers pause when
+// it is full
+const EVENT_QUEUE_LEN: usize = 256;
+// how often the address manager is written to disk
+const DUMP_ADDRESSES_INTERVAL: Duration = Duration::from_secs(15 * 60);
+// misbehavior score at which a peer gets banned
+const BAN_SCORE: i32 = 100;
+// blocks and transactions kept while their missing parents are fetched
+const MAX_ORPHAN_BLOCKS: usize = 100;
+const MAX_ORPHAN_BLOCKS_SIZE: usize = 10_000_000;
+const MAX_ORPHAN_TXS: usize = 100;
+const MAX_ORPHAN_TXS_SIZE: usize = 1_000_000;
+const ORPHAN_EXPIRY: Duration = Duration::from_secs(20 * 60);
+// transactions asked of one peer at a time, and how long it has to send them
+const MAX_TX_IN_FLIGHT: usize = 100;
+const TX_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+// blocks queued for download from a peer older than HEADERS_VERSION
+const MAX_BLOCKS_IN_TRANSIT: usize = MAX_BLOCKS_INV;
+// rejects and notfounds from peers kept for getrejects, and the length their
+// reasons are cut to
+const MAX_REJECTS: usize = 100;
+const MAX_REJECT_REASON: usize = 256;
+
+/// Where a node listens and which nodes it connects to.
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    // local address the listener binds to
+    pub bind: String,
+    // address announced to peers, the one they can reach us at
+    pub advertise: String,
+    // when not empty, the only nodes outbound connections are made to
+    pub connect: Vec<String>,
+    // nodes kept connected besides the ones learned from peers
+    pub addnode: Vec<String>,
+    // nodes dialled only to learn addresses while no other node is known
+    pub seednode: Vec<String>,
+    // dial peers encrypted and refuse plaintext from other machines
+    pub encrypt: bool,
+    // node keys expected from these addresses, dialled encrypted
+    pub pinned: HashMap<String, String>,
+    // where the mempool, peer addresses, ban list and node key are kept
+    pub data_dir: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Invmsg {
-    addr_from: String,
-    kind: String,
-    items: Vec<String>,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Txmsg {
-    addr_from: String,
-    transaction: Transaction,
+impl NodeConfig {
+    /// Binds and advertises `localhost:<port>`, seeding from `DEFAULT_NODE`.
+    pub fn new(port: &str) -> NodeConfig {
+        NodeConfig {
+            bind: String::from("localhost:") + port,
+            advertise: String::from("localhost:") + port,
+            connect: Vec::new(),
+            addnode: Vec::new(),
+            seednode: vec![String::from(DEFAULT_NODE)],
+            encrypt: false,
+            pinned: HashMap::new(),
+            data_dir: String::from(DATA_DIR),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Versionmsg {
-    addr_from: String,
-    version: i32,
-    best_height: i32,
+/// Work for the chain actor, queued by the network tasks.
+pub enum Event {
+    // an outbound session is up and should open with our version
+    Connected(u64),
+    Message(u64, Message),
+    Disconnected(u64),
+    // solved by our miner
+    Mined(Block),
+    // wakes the actor up to stop
+    Shutdown,
 }
 
+#[derive(Clone)]
 pub struct Server {
+    // the advertised address, sent as addr_from
     node_address: String,
+    config: NodeConfig,
     miner_address: String,
+    // set when there is a miner address
+    miner: Option<Miner>,
+    nonce: u64,
+    // static key of encrypted sessions
+    identity: Identity,
+    transport: Arc<dyn Transport>,
+    clock: Clock,
     inner: Arc<Mutex<ServerInner>>,
+    events: mpsc::Sender<Event>,
+    // cancelled to stop the listener and every session
+    shutdown: CancellationToken,
 }
 
 struct ServerInner {
-    known_nodes: HashSet<String>,
+    addrman: AddrMan,
     utxo: UTXOSet,
+    // blocks still to fetch from a peer older than HEADERS_VERSION
     blocks_in_transit: Vec<String>,
-    mempool: HashMap<String, Transaction>,
+    // transactions asked for: txid -> peer asked and when
+    tx_requests: HashMap<String, (u64, Instant)>,
+    // latest rejects and notfounds peers sent us, oldest first
+    rejects: VecDeque<RejectEntry>,
+    sync: BlockSync,
+    mempool: Mempool,
+    banman: BanManager,
+    peers: HashMap<u64, Peer>,
+    next_peer_id: u64,
+    // messages waiting for a peer to finish its handshake
+    pending: HashMap<u64, Vec<Message>>,
+    // compact blocks waiting for their missing transactions, one per peer
+    partial_blocks: HashMap<String, PartialBlock>,
+    // blocks and transactions whose parents were asked for
+    orphan_blocks: OrphanPool<Block>,
+    orphan_txs: OrphanPool<Transaction>,
+    // nodes being dialled and the messages queued for them
+    connecting: HashMap<String, Vec<Message>>,
+    // receiving end of `Server::events`, taken by the actor on start
+    events: Option<mpsc::Receiver<Event>>,
 }
 
 impl Server {
-    pub fn new(port: &str, miner_address: &str, utxo: UTXOSet) -> Result<Server> {
-        let mut node_set = HashSet::new();
-        node_set.insert(String::from(KNOWN_NODE1));
-        Ok(Server {
-            node_address: String::from("localhost:") + port,
+    pub fn new(config: NodeConfig, miner_address: &str, utxo: UTXOSet) -> Result<Server> {
+        let identity = Identity::load_or_create(&config.data_dir)?;
+        Ok(Server::with_transport(
+            config,
+            miner_address,
+            utxo,
+            identity,
+            Arc::new(TcpTransport),
+            Clock::System,
+        ))
+    }
+
+    /// A server reaching other nodes through `transport`, with peer timers
+    /// reading `clock`.
+    pub fn with_transport(
+        config: NodeConfig,
+        miner_address: &str,
+        utxo: UTXOSet,
+        identity: Identity,
+        transport: Arc<dyn Transport>,
+        clock: Clock,
+    ) -> Server {
+        let (events, receiver) = mpsc::channel(EVENT_QUEUE_LEN);
+        let dir = config.data_dir.clone();
+        Server {
+            node_address: config.advertise.clone(),
+            config,
             miner_address: miner_address.to_string(),
+            miner: if miner_address.is_empty() {
+                None
+            } else {
+                Some(Miner::new())
+            },
+            nonce: OsRng.next_u64(),
+            identity,
+            transport,
+            clock,
             inner: Arc::new(Mutex::new(ServerInner {
-                known_nodes: node_set,
-                utxo: utxo,
+                addrman: AddrMan::new(&dir),
+                utxo,
                 blocks_in_transit: Vec::new(),
-                mempool: HashMap::new(),
+                tx_requests: HashMap::new(),
+                rejects: VecDeque::new(),
+                sync: BlockSync::new(),
+                mempool: Mempool::new(&dir),
+                banman: BanManager::new(&dir),
+                peers: HashMap::new(),
+                next_peer_id: 0,
+                pending: HashMap::new(),
+                partial_blocks: HashMap::new(),
+                orphan_blocks: OrphanPool::new(
+                    MAX_ORPHAN_BLOCKS,
+                    MAX_ORPHAN_BLOCKS_SIZE,
+                    ORPHAN_EXPIRY,
+                ),
+                orphan_txs: OrphanPool::new(MAX_ORPHAN_TXS, MAX_ORPHAN_TXS_SIZE, ORPHAN_EXPIRY),
+                connecting: HashMap::new(),
+                events: Some(receiver),
             })),
-        })
+            events,
+            shutdown: CancellationToken::new(),
+        }
     }
 
+    /// Runs the node: peer sessions, the listener and the timers as tasks on
+    /// an async runtime, the handlers on a single chain actor.
     pub fn start_server(&self) -> Result<()> {
-        let server1 = Server {
-            node_address: self.node_address.clone(),
-            miner_address: self.miner_address.clone(),
-            inner: Arc::clone(&self.inner),
-        };
-
         info!(
-            "Start server at {}, minning address: {}",
-            &self.node_address, &self.miner_address
+            "Start server at {} (advertised as {}), minning address: {}",
+            &self.config.bind, &self.node_address, &self.miner_address
+        );
+        info!(
+            "node key {}, encrypted outbound: {}",
+            self.identity.public_hex(),
+            self.config.encrypt
         );
 
-        thread::spawn(move || {
-            thread::sleep(Duration::from_millis(1000));
-            if server1.get_best_height()? == -1 {
-                server1.request_blocks()
-            } else {
-                server1.send_version(KNOWN_NODE1)
+        // kept up to date block by block from here on
+        self.utxo_reindex()?;
+        self.load_mempool()?;
+        let now = self.clock.timestamp();
+        self.inner.lock().unwrap().banman.load(now)?;
+        self.inner.lock().unwrap().addrman.load()?;
+
+        let events = self
+            .inner
+            .lock()
+            .unwrap()
+            .events
+            .take()
+            .ok_or_else(|| format_err!("Server is already running"))?;
+        if let Some(miner) = &self.miner {
+            let events = self.events.clone();
+            miner.start(move |block| {
+                if events.blocking_send(Event::Mined(block)).is_err() {
+                    error!("submit mined block failed: server stopped");
+                }
+            });
+        }
+        let runtime = tokio::runtime::Runtime::new()?;
+        let result = runtime.block_on(async {
+            let server1 = self.clone();
+            let actor = tokio::task::spawn_blocking(move || server1.run_actor(events));
+            let server1 = self.clone();
+            tokio::spawn(async move { server1.wait_for_signal().await });
+
+            let result = self.run_network().await;
+            // stop everything else also when the listener failed
+            self.shutdown.cancel();
+            if let Some(miner) = &self.miner {
+                miner.stop();
+            }
+            if self.events.send(Event::Shutdown).await.is_err() {
+                debug!("chain actor already stopped");
             }
+            if let Err(e) = actor.await {
+                error!("chain actor failed: {}", e);
+            }
+            result
         });
+        self.flush()?;
+        info!("Server stopped");
+        result
+    }
 
-        let listener = TcpListener::bind(&self.node_address).unwrap();
-        info!("Server listening...");
-
-        for stream in listener.incoming() {
-            let stream = stream?;
-            let server1 = Server {
-                node_address: self.node_address.clone(),
-                miner_address: self.miner_address.clone(),
-                inner: Arc::clone(&self.inner),
-            };
-            thread::spawn(move || server1.handle_connection(stream));
+    /// Starts the shutdown on SIGINT or SIGTERM.
+    async fn wait_for_signal(&self) {
+        tokio::select! {
+            Ok(()) = tokio::signal::ctrl_c() => info!("received SIGINT, shutting down"),
+            _ = terminated() => info!("received SIGTERM, shutting down"),
+            _ = self.shutdown.cancelled() => return,
         }
-
-        Ok(())
+        self.shutdown.cancel();
     }
 
-    pub fn send_transaction(tx: &Transaction, utxo: UTXOSet) -> Result<()> {
-        let server = Server::new("7000", "", utxo)?;
-        server.send_tx(KNOWN_NODE1, tx)?;
+    /// Writes out the state only kept in memory and syncs the chain
+    /// database, once the actor has stopped.
+    fn flush(&self) -> Result<()> {
+        let inner = self.inner.lock().unwrap();
+        inner.mempool.save()?;
+        inner.addrman.save()?;
+        inner.banman.save()?;
+        inner.utxo.blockchain.flush()?;
+        info!("saved mempool, peer addresses, ban list and chain");
         Ok(())
     }
 
-    /* -------------------- inner function -------------------- */
-
-    fn remove_node(&self, addr: &str) {
-        self.inner.lock().unwrap().known_nodes.remove(addr);
-    }
+    /// Accepts inbound sessions and runs the outbound and keepalive timers
+    /// until the server shuts down.
+    async fn run_network(&self) -> Result<()> {
+        let listener = tokio::net::TcpListener::bind(&self.config.bind).await?;
+        info!("Server listening...");
 
-    fn add_nodes(&self, addr: &str) {
-        self.inner
-            .lock()
-            .unwrap()
-            .known_nodes
-            .insert(String::from(addr));
-    }
+        let server1 = self.clone();
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(1000)).await;
+            server1.maintain_outbound().await
+        });
+        let server1 = self.clone();
+        tokio::spawn(async move { server1.maintain_peers().await });
 
-    fn get_known_nodes(&self) -> HashSet<String> {
-        self.inner.lock().unwrap().known_nodes.clone()
-    }
+        loop {
+            let accepted = tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                accepted = listener.accept() => accepted,
+            };
+            let (stream, socket_addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("accept failed: {}", e);
+                    continue;
+                }
+            };
+            let ip = socket_addr.ip().to_string();
+            if !self.accepts_inbound(&ip) {
+                continue;
+            }
+            let server1 = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server1.accept(stream, &ip).await {
+                    info!("start inbound peer from {} failed: {}", ip, e);
+                }
+            });
+        }
 
-    fn node_is_known(&self, addr: &str) -> bool {
-        self.inner.lock().unwrap().known_nodes.get(addr).is_some()
+        Ok(())
     }
 
-    fn replace_in_transit(&self, hashs: Vec<String>) {
-        let bit = &mut self.inner.lock().unwrap().blocks_in_transit;
-        bit.clone_from(&hashs)
+    /// Runs the handlers one event at a time, so chain, mempool and sync
+    /// state only change here.
+    fn run_actor(&self, mut events: mpsc::Receiver<Event>) {
+        if let Err(e) = self.update_template(true) {
+            error!("update block template failed: {}", e);
+        }
+        while let Some(event) = events.blocking_recv() {
+            // what is still queued is dropped on shutdown
+            if self.shutdown.is_cancelled() || !self.handle_event(event) {
+                break;
+            }
+        }
     }
 
-    fn get_in_transit(&self) -> Vec<String> {
-        self.inner.lock().unwrap().blocks_in_transit.clone()
+    /// Handles one event of the actor, returning false once it should stop.
+    /// A failed handshake ends the session, a failing handler doesn't.
+    pub(crate) fn handle_event(&self, event: Event) -> bool {
+        match event {
+            Event::Connected(peer_id) => {
+                if let Some(peer) = self.get_peer(peer_id) {
+                    if let Err(e) = self.send_version(&peer) {
+                        info!("peer {} disconnect: {}", peer_id, e);
+                        peer.disconnect();
+                    }
+                }
+            }
+            Event::Message(peer_id, msg) => {
+                let handshake = matches!(msg, Message::Version(_) | Message::Verack);
+                match self.handle_message(peer_id, msg) {
+                    Ok(()) => {}
+                    Err(e) if handshake => {
+                        info!("peer {} disconnect: {}", peer_id, e);
+                        if let Some(peer) = self.get_peer(peer_id) {
+                            peer.disconnect();
+                        }
+                    }
+                    Err(e) => error!("handle message from peer {} failed: {}", peer_id, e),
+                }
+            }
+            Event::Disconnected(peer_id) => self.remove_peer(peer_id),
+            Event::Mined(block) => {
+                if let Err(e) = self.handle_mined_block(block) {
+                    error!("submit mined block failed: {}", e);
+                }
+            }
+            Event::Shutdown => return false,
+        }
+        true
     }
 
-    fn get_mempool_tx(&self, addr: &str) -> Option<Transaction> {
-        match self.inner.lock().unwrap().mempool.get(addr) {
-            Some(tx) => Some(tx.clone()),
-            None => None,
+    /// Submits `tx` to the default node, failing with the reason it gives
+    /// when it rejects it. A ping after the transaction tells when the node
+    /// is done with it.
+    pub fn send_transaction(tx: &Transaction) -> Result<()> {
+        let (mut reader, mut writer) = Server::client_connect(DEFAULT_NODE)?;
+        let data = Txmsg {
+            addr_from: String::new(),
+            transaction: tx.clone(),
+        };
+        write_message(&mut writer, &Message::Tx(data))?;
+        let nonce = OsRng.next_u64();
+        write_message(&mut writer, &Message::Ping(nonce))?;
+        loop {
+            match read_message(&mut reader)? {
+                Some(Message::Reject(reject)) if reject.item.hash.to_hex() == tx.id => {
+                    // already in its mempool, as good as accepted
+                    if reject.code == RejectCode::Duplicate {
+                        info!("node {} already has transaction {}", DEFAULT_NODE, tx.id);
+                        return Ok(());
+                    }
+                    return Err(format_err!(
+                        "Node {} rejected transaction {}: {} ({})",
+                        DEFAULT_NODE,
+                        tx.id,
+                        reject.reason,
+                        reject.code
+                    ));
+                }
+                Some(Message::Pong(n)) if n == nonce => return Ok(()),
+                Some(Message::Ping(n)) => write_message(&mut writer, &Message::Pong(n))?,
+                Some(_) => {}
+                None => return Err(format_err!("Node {} closed the connection", DEFAULT_NODE)),
+            }
         }
     }
 
-    fn get_mempool(&self) -> HashMap<String, Transaction> {
-        self.inner.lock().unwrap().mempool.clone()
+    /// Asks the node listening on `port` to write its mempool to disk.
+    pub fn request_save_mempool(port: &str) -> Result<()> {
+        let addr = String::from("localhost:") + port;
+        Server::send_once(&addr, Message::SaveMempool)?;
+        info!("send save mempool request to: {}", addr);
+        Ok(())
     }
 
-    fn insert_mempool(&self, tx: Transaction) {
-        self.inner.lock().unwrap().mempool.insert(tx.id.clone(), tx);
+    /// Asks the node listening on `port` to shut down.
+    pub fn request_stop(port: &str) -> Result<()> {
+        let addr = String::from("localhost:") + port;
+        Server::send_once(&addr, Message::Stop)?;
+        info!("send stop request to: {}", addr);
+        Ok(())
     }
 
-    fn clear_mempool(&self) {
-        self.inner.lock().unwrap().mempool.clear();
+    /// Fetches the peer list of the node listening on `port`.
+    pub fn request_peer_info(port: &str) -> Result<Vec<PeerInfo>> {
+        let addr = String::from("localhost:") + port;
+        match Server::request_once(&addr, Message::GetPeerInfo)? {
+            Message::PeerInfo(peers) => Ok(peers),
+            msg => Err(format_err!("Unexpected reply {}", msg.command())),
+        }
     }
 
-    fn get_best_height(&self) -> Result<i32> {
-        self.inner.lock().unwrap().utxo.blockchain.get_best_height()
+    /// Bans `ip` on the node listening on `port` for `ban_time` seconds, or
+    /// lifts its ban when `add` is false.
+    pub fn request_set_ban(port: &str, ip: &str, add: bool, ban_time: i64) -> Result<()> {
+        let addr = String::from("localhost:") + port;
+        let data = SetBanmsg {
+            ip: ip.to_string(),
+            add,
+            ban_time,
+        };
+        Server::send_once(&addr, Message::SetBan(data))
     }
 
-    fn get_block_hashs(&self) -> Vec<String> {
-        self.inner.lock().unwrap().utxo.blockchain.get_block_hashs()
+    /// Fetches the ban list of the node listening on `port`.
+    pub fn request_list_banned(port: &str) -> Result<Vec<BanEntry>> {
+        let addr = String::from("localhost:") + port;
+        match Server::request_once(&addr, Message::ListBanned)? {
+            Message::Banned(bans) => Ok(bans),
+            msg => Err(format_err!("Unexpected reply {}", msg.command())),
+        }
     }
 
-    fn get_block(&self, block_hash: &str) -> Result<Block> {
-        self.inner
-            .lock()
-            .unwrap()
-            .utxo
-            .blockchain
-            .get_block(block_hash)
+    /// Fetches the rejects and notfounds peers sent the node listening on
+    /// `port`.
+    pub fn request_rejects(port: &str) -> Result<Vec<RejectEntry>> {
+        let addr = String::from("localhost:") + port;
+        match Server::request_once(&addr, Message::GetRejects)? {
+            Message::Rejects(rejects) => Ok(rejects),
+            msg => Err(format_err!("Unexpected reply {}", msg.command())),
+        }
     }
 
-    fn verify_tx(&self, tx: &Transaction) -> Result<bool> {
-        self.inner
-            .lock()
-            .unwrap()
-            .utxo
-            .blockchain
-            .verify_transaction(tx)
+    /// Lifts every ban on the node listening on `port`.
+    pub fn request_clear_banned(port: &str) -> Result<()> {
+        let addr = String::from("localhost:") + port;
+        Server::send_once(&addr, Message::ClearBanned)
     }
 
-    fn add_block(&self, block: Block) -> Result<()> {
-        self.inner.lock().unwrap().utxo.blockchain.add_block(block)
+    /// Delivers a single message as a client and closes the connection.
+    fn send_once(addr: &str, msg: Message) -> Result<()> {
+        let (_, mut writer) = Server::client_connect(addr)?;
+        write_message(&mut writer, &msg)
     }
 
-    fn mine_block(&self, txs: Vec<Transaction>) -> Result<Block> {
-        self.inner.lock().unwrap().utxo.blockchain.mine_block(txs)
+    /// Sends a single message as a client and waits for the node's reply.
+    fn request_once(addr: &str, msg: Message) -> Result<Message> {
+        let (mut reader, mut writer) = Server::client_connect(addr)?;
+        write_message(&mut writer, &msg)?;
+        loop {
+            match read_message(&mut reader)? {
+                Some(Message::Ping(nonce)) => write_message(&mut writer, &Message::Pong(nonce))?,
+                Some(reply) => return Ok(reply),
+                None => return Err(format_err!("Node {} closed the connection", addr)),
+            }
+        }
     }
 
-    fn utxo_reindex(&self) -> Result<()> {
-        self.inner.lock().unwrap().utxo.reindex()
+    /// Handshakes as a client: an empty address and no services, so the node
+    /// doesn't record us as a peer.
+    fn client_connect(addr: &str) -> Result<(BufReader<TcpStream>, BufWriter<TcpStream>)> {
+        let stream = TcpStream::connect(addr)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        let version = Versionmsg {
+            addr_from: String::new(),
+            version: PROTOCOL_VERSION,
+            services: 0,
+            user_agent: user_agent(),
+            nonce: OsRng.next_u64(),
+            timestamp: Utc::now().timestamp(),
+            best_height: -1,
+        };
+        write_message(&mut writer, &Message::Version(version))?;
+
+        let mut got_verack = false;
+        while !got_verack {
+            match read_message(&mut reader)? {
+                Some(Message::Version(v)) if v.version < MIN_PEER_VERSION => {
+                    return Err(format_err!(
+                        "Node {} speaks old version {}",
+                        addr,
+                        v.version
+                    ));
+                }
+                Some(Message::Version(_)) => {}
+                Some(Message::Verack) => got_verack = true,
+                Some(_) => {}
+                None => return Err(format_err!("Node {} closed the connection", addr)),
+            }
+        }
+        write_message(&mut writer, &Message::Verack)?;
+        Ok((reader, writer))
     }
 
-    /* -------------------- send and function -------------------- */
-
-    fn send_data(&self, addr: &str, data: &[u8]) -> Result<()> {
-        if addr == &self.node_address {
+    /* -------------------- peer function -------------------- */
+
+    /// Tells an inbound session opening with the encrypted handshake from a
+    /// plaintext one, which only clients on this machine may use when
+    /// encryption is on.
+    async fn accept(&self, mut stream: AsyncTcpStream, ip: &str) -> Result<()> {
+        let mut prefix = [0u8; 4];
+        time::timeout(HANDSHAKE_TIMEOUT, stream.read_exact(&mut prefix)).await??;
+        if prefix == NOISE_MAGIC {
+            let session = time::timeout(
+                HANDSHAKE_TIMEOUT,
+                noise::respond(&mut stream, &self.identity),
+            )
+            .await??;
+            self.start_peer(stream, "", Direction::Inbound, Some(session), Vec::new())?;
             return Ok(());
         }
-        let mut stream = match TcpStream::connect(addr) {
-            Ok(s) => s,
-            Err(_) => {
-                self.remove_node(addr);
-                return Ok(());
-            }
-        };
-
-        stream.write(data)?;
 
-        info!("data send successfully");
+        let local = match ip.parse::<IpAddr>() {
+            Ok(ip) => ip.is_loopback(),
+            Err(_) => false,
+        };
+        if self.config.encrypt && !local {
+            return Err(format_err!("plaintext session refused"));
+        }
+        // the bytes read belong to the first frame
+        self.start_peer(stream, "", Direction::Inbound, None, prefix.to_vec())?;
         Ok(())
     }
 
-    fn request_blocks(&self) -> Result<()> {
-        for node in self.get_known_nodes() {
-            self.send_get_blocks(&node)?
+    /// Whether a session from `ip` may be opened.
+    pub(crate) fn accepts_inbound(&self, ip: &str) -> bool {
+        if self.is_banned(ip) {
+            info!("refuse inbound connection from banned {}", ip);
+            return false;
         }
-        Ok(())
+        if self.count_peers(Direction::Inbound) >= MAX_INBOUND_CONNECTIONS {
+            info!("refuse inbound connection: too many peers");
+            return false;
+        }
+        true
     }
 
-    fn send_block(&self, addr: &str, b: &Block) -> Result<()> {
-        info!("send block data to: {} block hash: {}", addr, b.get_hash());
-        let data = Blockmsg {
-            addr_from: self.node_address.clone(),
-            block: b.clone(),
+    /// Registers a session over `stream` and spawns its read and write
+    /// tasks, `prefix` being what was already read off the stream.
+    fn start_peer(
+        &self,
+        stream: AsyncTcpStream,
+        addr: &str,
+        direction: Direction,
+        session: Option<Session>,
+        prefix: Vec<u8>,
+    ) -> Result<Peer> {
+        let ip = stream.peer_addr()?.ip().to_string();
+        let (reader, writer) = stream.into_split();
+        let reader = Cursor::new(prefix).chain(reader);
+        let (send, recv, remote_key) = match session {
+            Some(session) => (
+                Some(session.send),
+                Some(session.recv),
+                Some(session.remote_key),
+            ),
+            None => (None, None, None),
         };
-        let data = serialize(&(cmd_to_bytes("block"), data))?;
-        self.send_data(addr, &data)
-    }
+        let (peer, receiver) = self.register_peer(addr, direction, &ip, remote_key);
 
-    fn send_addr(&self, addr: &str) -> Result<()> {
-        info!("send address info to: {}", addr);
-        let nodes = self.get_known_nodes();
-        let data = serialize(&(cmd_to_bytes("addr"), nodes))?;
-        self.send_data(addr, &data)
+        tokio::spawn(write_loop(writer, receiver, peer.clone(), send));
+        let server1 = self.clone();
+        let peer1 = peer.clone();
+        tokio::spawn(async move { server1.run_peer(peer1, reader, recv).await });
+        Ok(peer)
     }
 
-    fn send_inv(&self, addr: &str, kind: &str, items: Vec<String>) -> Result<()> {
-        info!(
-            "send inv message to: {} kind: {} data: {:?}",
-            addr, kind, items
-        );
-        let data = Invmsg {
-            addr_from: self.node_address.clone(),
-            kind: kind.to_string(),
-            items,
+    /// Adds a session to the peer table, returning its handle and the queue
+    /// of messages the transport has to deliver. Messages queued while
+    /// dialling `addr` wait for the handshake.
+    pub(crate) fn register_peer(
+        &self,
+        addr: &str,
+        direction: Direction,
+        ip: &str,
+        remote_key: Option<String>,
+    ) -> (Peer, mpsc::Receiver<Message>) {
+        let (peer, receiver) = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.next_peer_id += 1;
+            let (mut peer, receiver) = Peer::new(
+                inner.next_peer_id,
+                addr,
+                direction,
+                ip,
+                self.shutdown.child_token(),
+                self.clock.now(),
+            );
+            peer.remote_key = remote_key;
+            inner.peers.insert(peer.id, peer.clone());
+            if let Some(queued) = inner.connecting.remove(addr) {
+                if !queued.is_empty() {
+                    inner.pending.insert(peer.id, queued);
+                }
+            }
+            (peer, receiver)
         };
-        let data = serialize(&(cmd_to_bytes("inv"), data))?;
-        self.send_data(addr, &data)
+        match &peer.remote_key {
+            Some(key) => info!(
+                "peer {} connected: {:?} {} encrypted, key {}",
+                peer.id, direction, addr, key
+            ),
+            None => info!("peer {} connected: {:?} {}", peer.id, direction, addr),
+        }
+        (peer, receiver)
     }
 
-    fn send_get_blocks(&self, addr: &str) -> Result<()> {
-        info!("send get blocks message to: {}", addr);
-        let data = GetBlocksmsg {
-            addr_from: self.node_address.clone(),
-        };
-        let data = serialize(&(cmd_to_bytes("getblocks"), data))?;
-        self.send_data(addr, &data)
-    }
+    /// Dials `addr` in the background, `queued` and whatever is sent to it
+    /// meanwhile going out once the session is up.
+    pub(crate) fn connect(&self, addr: &str, queued: Vec<Message>) -> Result<()> {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(waiting) = inner.connecting.get_mut(addr) {
+                waiting.extend(queued);
+                return Ok(());
+            }
+            let outbound = inner
+                .peers
+                .values()
+                .filter(|peer| peer.direction == Direction::Outbound)
+                .count();
+            if outbound + inner.connecting.len() >= MAX_OUTBOUND_CONNECTIONS {
+                return Err(format_err!(
+                    "Too many outbound connections to reach {}",
+                    addr
+                ));
+            }
+            inner.connecting.insert(addr.to_string(), queued);
+            inner.addrman.attempt(addr, self.clock.timestamp());
+        }
 
-    fn send_get_data(&self, addr: &str, kind: &str, id: &str) -> Result<()> {
-        info!(
-            "send get data message to: {} kind: {} id: {}",
-            addr, kind, id
-        );
-        let data = GetDatamsg {
-            addr_from: self.node_address.clone(),
-            kind: kind.to_string(),
-            id: id.to_string(),
-        };
-        let data = serialize(&(cmd_to_bytes("getdata"), data))?;
-        self.send_data(addr, &data)
+        self.transport.dial(self, addr);
+        Ok(())
     }
 
-    pub fn send_tx(&self, addr: &str, tx: &Transaction) -> Result<()> {
-        info!("send tx to: {} txid: {}", addr, &tx.id);
-        let data = Txmsg {
-            addr_from: self.node_address.clone(),
-            transaction: tx.clone(),
-        };
-        let data = serialize(&(cmd_to_bytes("tx"), data))?;
-        self.send_data(addr, &data)
+    /// Drops what was queued for `addr`, which couldn't be reached.
+    pub(crate) fn dial_failed(&self, addr: &str, e: &anyhow::Error) {
+        self.inner.lock().unwrap().connecting.remove(addr);
+        info!("connect to {} failed: {}", addr, e);
     }
 
-    fn send_version(&self, addr: &str) -> Result<()> {
-        info!("send version info to: {}", addr);
-        let data = Versionmsg {
-            addr_from: self.node_address.clone(),
-            best_height: self.get_best_height()?,
-            version: VERSION,
+    /// Opens a TCP session to `addr`, encrypted when configured or pinned.
+    pub(crate) async fn dial(&self, addr: &str) -> Result<()> {
+        let socket_addr = tokio::net::lookup_host(addr)
+            .await?
+            .next()
+            .ok_or_else(|| format_err!("Can't resolve {}", addr))?;
+        if self.is_banned(&socket_addr.ip().to_string()) {
+            return Err(format_err!("{} is banned", addr));
+        }
+        let mut stream =
+            time::timeout(CONNECT_TIMEOUT, AsyncTcpStream::connect(socket_addr)).await??;
+
+        let pinned = self.config.pinned.get(addr);
+        let session = if self.config.encrypt || pinned.is_some() {
+            let session = time::timeout(
+                HANDSHAKE_TIMEOUT,
+                noise::initiate(&mut stream, &self.identity),
+            )
+            .await??;
+            if let Some(key) = pinned {
+                if !session.has_key(key) {
+                    return Err(format_err!(
+                        "{} has key {}, not the pinned {}",
+                        addr,
+                        session.remote_key,
+                        key
+                    ));
+                }
+            }
+            Some(session)
+        } else {
+            None
         };
-        let data = serialize(&(cmd_to_bytes("version"), data))?;
-        self.send_data(addr, &data)
+        let peer = self.start_peer(stream, addr, Direction::Outbound, session, Vec::new())?;
+        self.queue_event(&peer, Event::Connected(peer.id)).await;
+        Ok(())
     }
 
-    fn handle_version(&self, msg: Versionmsg) -> Result<()> {
-        info!("receive version msg: {:#?}", msg);
-        let my_best_height = self.get_best_height()?;
-        if my_best_height < msg.best_height {
-            self.send_get_blocks(&msg.addr_from)?;
-        } else if my_best_height > msg.best_height {
-            self.send_version(&msg.addr_from)?;
-        }
+    /// Read task of a peer session. The session opens with version then
+    /// verack, and only messages of the negotiated version are passed on to
+    /// the actor. Undecodable frames add to the peer's misbehavior score, a
+    /// frame that leaves the stream out of step, or fails to decrypt, ends the
+    /// session.
+    async fn run_peer<R: AsyncRead + Unpin>(
+        &self,
+        peer: Peer,
+        stream: R,
+        mut cipher: Option<CipherState>,
+    ) {
+        let mut reader = tokio::io::BufReader::new(stream);
+        let mut session = SessionState::default();
+
+        let result = loop {
+            let read = tokio::select! {
+                _ = peer.disconnected() => break Ok(()),
+                read = async {
+                    match &mut cipher {
+                        Some(cipher) => noise::read_encrypted(&mut reader, cipher).await,
+                        None => read_message_async(&mut reader).await,
+                    }
+                } => read,
+            };
+            let (msg, len) = match read {
+                Ok(Some((msg, len))) => {
+                    peer.bytes_recv.fetch_add(len as u64, Ordering::Relaxed);
+                    (msg, len)
+                }
+                Ok(None) => break Ok(()),
+                Err(e) => match e.downcast_ref::<FrameError>() {
+                    Some(FrameError::UnknownCommand(_)) => {
+                        self.misbehaving(peer.id, 10, &e.to_string());
+                        continue;
+                    }
+                    Some(FrameError::BadChecksum) | Some(FrameError::Malformed(_)) => {
+                        self.misbehaving(peer.id, 20, &e.to_string());
+                        continue;
+                    }
+                    Some(FrameError::BadMagic) | Some(FrameError::Oversize(_)) => {
+                        self.misbehaving(peer.id, 50, &e.to_string());
+                        break Err(e);
+                    }
+                    None => break Err(e),
+                },
+            };
+            info!("Accept request: {} from peer {}", msg.command(), peer.id);
+            self.touch_peer(peer.id);
 
-        self.send_addr(&msg.addr_from)?;
+            if let Err(e) = session.receive(&msg) {
+                break Err(e);
+            }
+            if !self.rate_limit(&peer, &msg, len) {
+                continue;
+            }
+
+            // waits while the actor is behind, which stops reading the socket
+            if !self.queue_event(&peer, Event::Message(peer.id, msg)).await {
+                break Ok(());
+            }
+        };
 
-        if !self.node_is_known(&msg.addr_from) {
-            self.add_nodes(&msg.addr_from);
+        if let Err(e) = result {
+            info!("peer {} disconnect: {}", peer.id, e);
+        }
+        peer.disconnect();
+        if self
+            .events
+            .send(Event::Disconnected(peer.id))
+            .await
+            .is_err()
+        {
+            debug!("peer {} gone after the actor stopped", peer.id);
         }
-        Ok(())
     }
 
-    fn handle_addr(&self, msg: Vec<String>) -> Result<()> {
-        info!("receive address msg: {:#?}", msg);
-        for node in msg {
-            self.add_nodes(&node);
+    /// Hands `event` to the actor unless the session ends first. Returns
+    /// whether it was queued.
+    async fn queue_event(&self, peer: &Peer, event: Event) -> bool {
+        tokio::select! {
+            _ = peer.disconnected() => false,
+            sent = self.events.send(event) => sent.is_ok(),
         }
-        Ok(())
     }
 
-    fn handle_block(&self, msg: Blockmsg) -> Result<()> {
-        info!(
-            "receive block msg: {}, {}",
-            msg.addr_from,
-            msg.block.get_hash()
-        );
-        self.add_block(msg.block)?;
-
-        let mut in_transit = self.get_in_transit();
-        if in_transit.len() > 0 {
-            let block_hash = &in_transit[0];
-            self.send_get_data(&msg.addr_from, "block", block_hash)?;
-            in_transit.remove(0);
-            self.replace_in_transit(in_transit);
-        } else {
-            self.utxo_reindex()?;
+    /// Keeps up to `MAX_OUTBOUND_CONNECTIONS` sessions open to the nodes
+    /// picked by `outbound_targets`, and saves the address manager now and
+    /// then.
+    async fn maintain_outbound(&self) {
+        let mut last_dump = Instant::now();
+        let mut interval = time::interval(CONNECT_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                _ = interval.tick() => {}
+            }
+            self.connect_outbound();
+            if last_dump.elapsed() >= DUMP_ADDRESSES_INTERVAL {
+                if let Err(e) = self.inner.lock().unwrap().addrman.save() {
+                    error!("save peer addresses failed: {}", e);
+                }
+                last_dump = Instant::now();
+            }
         }
+    }
 
-        Ok(())
+    /// Dials the outbound targets we aren't connected to yet.
+    pub(crate) fn connect_outbound(&self) {
+        for node in self.outbound_targets() {
+            if self.count_peers(Direction::Outbound) >= MAX_OUTBOUND_CONNECTIONS {
+                break;
+            }
+            if node == self.node_address || self.find_peer(&node).is_some() {
+                continue;
+            }
+            if let Err(e) = self.connect(&node, Vec::new()) {
+                info!("connect to {} failed: {}", node, e);
+            }
+        }
     }
 
-    fn handle_inv(&self, msg: Invmsg) -> Result<()> {
-        info!("receive inv msg: {:#?}", msg);
-        if msg.kind == "block" {
-            let block_hash = &msg.items[0];
-            self.send_get_data(&msg.addr_from, "block", block_hash)?;
+    /// With `connect` set only those nodes, otherwise the `addnode` entries
+    /// followed by picks from the address manager, falling back to the seeds
+    /// while it knows no address.
+    fn outbound_targets(&self) -> Vec<String> {
+        if !self.config.connect.is_empty() {
+            return self.config.connect.clone();
+        }
+        let mut targets = self.config.addnode.clone();
+        let inner = self.inner.lock().unwrap();
+        if inner.addrman.is_empty() {
+            targets.extend(self.config.seednode.iter().cloned());
+            return targets;
+        }
 
-            let mut new_in_transit = Vec::new();
-            for b in &msg.items {
-                if b != block_hash {
-                    new_in_transit.push(b.clone());
-                }
-            }
-            self.replace_in_transit(new_in_transit);
-        } else if msg.kind == "tx" {
-            let txid = &msg.items[0];
-            match self.get_mempool_tx(txid) {
-                Some(tx) => {
-                    if tx.id.is_empty() {
-                        self.send_get_data(&msg.addr_from, "tx", txid)?
-                    }
+        let mut exclude: HashSet<String> = inner
+            .peers
+            .values()
+            .map(|peer| peer.addr.clone())
+            .chain(targets.iter().cloned())
+            .collect();
+        exclude.insert(self.node_address.clone());
+        let outbound = inner
+            .peers
+            .values()
+            .filter(|peer| peer.direction == Direction::Outbound)
+            .count();
+        for _ in outbound..MAX_OUTBOUND_CONNECTIONS {
+            match inner.addrman.select(&exclude, self.clock.timestamp()) {
+                Some(addr) => {
+                    exclude.insert(addr.clone());
+                    targets.push(addr);
                 }
-                None => self.send_get_data(&msg.addr_from, "tx", txid)?,
+                None => break,
             }
         }
-        Ok(())
+        targets
     }
 
-    fn handle_get_blocks(&self, msg: GetBlocksmsg) -> Result<()> {
-        info!("receive get blocks msg: {:#?}", msg);
-        let block_hashs = self.get_block_hashs();
-        self.send_inv(&msg.addr_from, "block", block_hashs)?;
-        Ok(())
+    /// Nodes from `connect` or `addnode`, which are never forgotten.
+    fn is_configured(&self, addr: &str) -> bool {
+        self.config.connect.iter().any(|node| node == addr)
+            || self.config.addnode.iter().any(|node| node == addr)
     }
 
-    fn handle_get_data(&self, msg: GetDatamsg) -> Result<()> {
-        info!("receive get data msg: {:#?}", msg);
-        if msg.kind == "block" {
-            let block = self.get_block(&msg.id)?;
-            self.send_block(&msg.addr_from, &block)?;
-        } else if msg.kind == "tx" {
-            let tx = self.get_mempool_tx(&msg.id).unwrap();
-            self.send_tx(&msg.addr_from, &tx)?;
+    async fn maintain_peers(&self) {
+        let mut interval = time::interval(KEEPALIVE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                _ = interval.tick() => {}
+            }
+            self.check_peers();
         }
-        Ok(())
     }
 
-    fn handle_tx(&self, msg: Txmsg) -> Result<()> {
-        info!("receive tx msg: {} {}", msg.addr_from, &msg.transaction.id);
-        self.insert_mempool(msg.transaction.clone());
-
-        let known_nodes = self.get_known_nodes();
-        if self.node_address == KNOWN_NODE1 {
-            for node in known_nodes {
-                if node != self.node_address && node != msg.addr_from {
-                    self.send_inv(&node, "tx", vec![msg.transaction.id.clone()])?;
+    /// Pings peers that support it, measuring latency, and drops those that
+    /// time out on a ping or never finish their handshake.
+    pub(crate) fn check_peers(&self) {
+        let stalled = self.inner.lock().unwrap().sync.timed_out(self.clock.now());
+        for peer_id in stalled {
+            if let Some(peer) = self.get_peer(peer_id) {
+                info!("peer {} stalled block download", peer_id);
+                peer.disconnect();
+            }
+        }
+        let now = self.clock.now();
+        // a transaction not sent in time may be asked of another peer
+        self.inner
+            .lock()
+            .unwrap()
+            .tx_requests
+            .retain(|_, (_, sent)| now.duration_since(*sent) < TX_REQUEST_TIMEOUT);
+        let peers: Vec<Peer> = self.inner.lock().unwrap().peers.values().cloned().collect();
+        for peer in peers {
+            if !peer.handshaked {
+                if now.duration_since(peer.connected_at) > HANDSHAKE_TIMEOUT {
+                    info!("peer {} handshake timeout", peer.id);
+                    peer.disconnect();
                 }
+                continue;
             }
-        } else {
-            let mut mempool = self.get_mempool();
-            debug!("Current mempool: {:#?}", &mempool);
-            if mempool.len() >= 1 && !self.miner_address.is_empty() {
-                loop {
-                    let mut txs = Vec::new();
-
-                    for (_, tx) in &mempool {
-                        if self.verify_tx(tx)? {
-                            txs.push(tx.clone());
-                        }
-                    }
-
-                    if txs.is_empty() {
-                        return Ok(());
-                    }
-
-                    let cbtx =
-                        Transaction::new_coinbase(self.miner_address.clone(), String::new())?;
-                    txs.push(cbtx);
-
-                    for tx in &txs {
-                        mempool.remove(&tx.id);
+            if peer.version < PING_VERSION {
+                continue;
+            }
+            match peer.ping {
+                Some((_, sent)) if now.duration_since(sent) > PING_TIMEOUT => {
+                    info!("peer {} ping timeout", peer.id);
+                    peer.disconnect();
+                }
+                None if now.duration_since(peer.last_ping) >= PING_INTERVAL => {
+                    let nonce = OsRng.next_u64();
+                    if let Some(p) = self.inner.lock().unwrap().peers.get_mut(&peer.id) {
+                        p.ping = Some((nonce, now));
+                        p.last_ping = now;
                     }
-
-                    let new_block = self.mine_block(txs)?;
-                    self.utxo_reindex()?;
-
-                    for node in self.get_known_nodes() {
-                        if node != self.node_address {
-                            self.send_inv(&node, "block", vec![new_block.get_hash()])?;
-                        }
+                    if let Err(e) = peer.send(Message::Ping(nonce)) {
+                        info!("ping peer {} failed: {}", peer.id, e);
                     }
+                }
+                _ => {}
+            }
+        }
+    }
 
-                    if mempool.len() == 0 {
-                        break;
-                    }
+    /// Charges `msg` to the budgets of `peer`. Returns false when it is over
+    /// budget, the message is then dropped and the peer scored a little.
+    pub(crate) fn rate_limit(&self, peer: &Peer, msg: &Message, len: usize) -> bool {
+        match peer.charge(msg, len, self.clock.now()) {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("drop {} from peer {}: {}", msg.command(), peer.id, e);
+                if e.is::<RateLimited>() {
+                    self.misbehaving(peer.id, 1, &e.to_string());
                 }
-                self.clear_mempool();
+                false
             }
         }
+    }
 
-        Ok(())
+    pub(crate) fn touch_peer(&self, id: u64) {
+        let now = self.clock.now();
+        if let Some(peer) = self.inner.lock().unwrap().peers.get_mut(&id) {
+            peer.last_recv = now;
+        }
     }
 
-    fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
-        let mut buffer = Vec::new();
-        let count = stream.read_to_end(&mut buffer)?;
-        info!("Accept request: length {}", count);
+    fn get_peer_info(&self) -> Vec<PeerInfo> {
+        let mut peers: Vec<PeerInfo> = self
+            .inner
+            .lock()
+            .unwrap()
+            .peers
+            .values()
+            .filter(|peer| !peer.addr.is_empty())
+            .map(|peer| peer.info())
+            .collect();
+        peers.sort_by_key(|peer| peer.id);
+        peers
+    }
 
-        let cmd = bytes_to_cmd(&buffer)?;
+    /// The session we dialled to `addr`, inbound peers only claim theirs.
+    fn find_peer(&self, addr: &str) -> Option<Peer> {
+        self.inner
+            .lock()
+            .unwrap()
+            .peers
+            .values()
+            .find(|peer| peer.direction == Direction::Outbound && peer.addr == addr)
+            .cloned()
+    }
 
-        match cmd {
-            Message::Addr(data) => self.handle_addr(data)?,
-            Message::Block(data) => self.handle_block(data)?,
-            Message::Inv(data) => self.handle_inv(data)?,
-            Message::GetBlock(data) => self.handle_get_blocks(data)?,
-            Message::GetData(data) => self.handle_get_data(data)?,
-            Message::Tx(data) => self.handle_tx(data)?,
-            Message::Version(data) => self.handle_version(data)?,
-        }
+    pub(crate) fn get_peer(&self, id: u64) -> Option<Peer> {
+        self.inner.lock().unwrap().peers.get(&id).cloned()
+    }
 
-        Ok(())
+    fn remove_peer(&self, id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(peer) = inner.peers.remove(&id) {
+            if peer.handshaked && !peer.addr.is_empty() {
+                inner.addrman.seen(&peer.addr, self.clock.timestamp());
+            }
+        }
+        inner.pending.remove(&id);
+        inner.tx_requests.retain(|_, (peer, _)| *peer != id);
+        inner
+            .partial_blocks
+            .retain(|_, partial| partial.peer_id != id);
+        let syncing = inner.sync.is_syncing();
+        inner.sync.peer_gone(id);
+        let stopped = syncing && !inner.sync.is_syncing();
+        drop(inner);
+        // hand the blocks it still owed us to the other peers
+        self.request_blocks();
+        // the chain it was the only one to send is gone, mine again
+        if stopped {
+            if let Err(e) = self.update_template(true) {
+                error!("update block template failed: {}", e);
+            }
+        }
     }
-}
 
-fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LEN] {
-    let mut data = [0; CMD_LEN];
-    for (i, d) in cmd.as_bytes().iter().enumerate() {
-        data[i] = *d;
+    /// Spreads the missing blocks of the header chain over the peers that
+    /// have them.
+    fn request_blocks(&self) {
+        let now = self.clock.now();
+        let requests: Vec<(Peer, Vec<String>)> = {
+            let mut inner = self.inner.lock().unwrap();
+            let inner = &mut *inner;
+            inner
+                .peers
+                .values()
+                .filter(|peer| {
+                    peer.handshaked
+                        && peer.version >= HEADERS_VERSION
+                        && peer.services & NODE_NETWORK != 0
+                })
+                .map(|peer| {
+                    let hashs = inner.sync.request(peer.id, peer.best_height, now);
+                    (peer.clone(), hashs)
+                })
+                .filter(|(_, hashs)| !hashs.is_empty())
+                .collect()
+        };
+        for (peer, hashs) in requests {
+            for hash in hashs {
+                if let Err(e) = self.send_get_data(&peer, InvKind::Block, &hash) {
+                    info!("request block {} from peer {} failed: {}", hash, peer.id, e);
+                }
+            }
+        }
     }
-    data
-}
 
-fn bytes_to_cmd(bytes: &[u8]) -> Result<Message> {
-    let mut cmd = Vec::new();
-    let cmd_bytes = &bytes[..CMD_LEN];
-    let data = &bytes[CMD_LEN..];
-    for b in cmd_bytes {
-        if 0 as u8 != *b {
-            cmd.push(*b);
-        }
-    }
-    info!("cmd: {}", String::from_utf8(cmd.clone())?);
-
-    if cmd == "addr".as_bytes() {
-        let data: Vec<String> = deserialize(data)?;
-        Ok(Message::Addr(data))
-    } else if cmd == "block".as_bytes() {
-        let data: Blockmsg = deserialize(data)?;
-        Ok(Message::Block(data))
-    } else if cmd == "inv".as_bytes() {
-        let data: Invmsg = deserialize(data)?;
-        Ok(Message::Inv(data))
-    } else if cmd == "getblocks".as_bytes() {
-        let data: GetBlocksmsg = deserialize(data)?;
-        Ok(Message::GetBlock(data))
-    } else if cmd == "getdata".as_bytes() {
-        let data: GetDatamsg = deserialize(data)?;
-        Ok(Message::GetData(data))
-    } else if cmd == "tx".as_bytes() {
-        let data: Txmsg = deserialize(data)?;
-        Ok(Message::Tx(data))
-    } else if cmd == "version".as_bytes() {
-        let data: Versionmsg = deserialize(data)?;
-        Ok(Message::Version(data))
-    } else {
-        Err(format_err!("Unknown command in the server"))
+    /// Adds the downloaded blocks that extend our chain, in order. A block
+    /// that fails is dropped with the headers building on it, while the
+    /// blocks connected before it still make a new tip.
+    fn connect_blocks(&self) -> Result<()> {
+        let mut connected = false;
+        let mut dropped = false;
+        loop {
+            let (peer_id, block) = match self.inner.lock().unwrap().sync.next_block() {
+                Some(next) => next,
+                None => break,
+            };
+            let hash = block.get_hash();
+            info!("connect block {} at {}", hash, block.get_height());
+            match self.accept_block(block) {
+                Ok(()) => connected = true,
+                Err(e) => {
+                    info!("drop block {} from peer {}: {}", hash, peer_id, e);
+                    self.block_failed(peer_id, &hash, &e);
+                    self.inner.lock().unwrap().sync.drop_descendants(&hash);
+                    dropped = true;
+                }
+            }
+        }
+        if connected {
+            self.tip_changed()?;
+        } else if dropped {
+            // nothing is left to sync, so the miner gets going again
+            self.update_template(true)?;
+        }
+        Ok(())
+    }
+
+    /// Adds `block` and then the orphans that were waiting for it.
+    fn accept_block(&self, block: Block) -> Result<()> {
+        let mut children = self.store_block(block)?;
+        while let Some(orphan) = children.pop() {
+            info!("connect orphan block {}", orphan.id);
+            match self.store_block(orphan.item) {
+                Ok(more) => children.extend(more),
+                Err(e) => {
+                    info!("drop orphan block {}: {}", orphan.id, e);
+                    self.block_failed(orphan.peer_id, &orphan.id, &e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that `block` follows its parent and its transactions against
+    /// our chain, then stores it. When it becomes the tip, the unspent
+    /// outputs and the mempool move onto it. Returns the orphans whose parent
+    /// it is.
+    fn store_block(&self, block: Block) -> Result<Vec<Orphan<Block>>> {
+        let parent = self.get_block(&block.get_prev_hash())?;
+        if block.get_height() != parent.get_height() + 1 {
+            return Err(InvalidBlock(format!(
+                "Block {} has height {} after {}",
+                block.get_hash(),
+                block.get_height(),
+                parent.get_height()
+            ))
+            .into());
+        }
+        let valid = self
+            .inner
+            .lock()
+            .unwrap()
+            .utxo
+            .blockchain
+            .verify_block_transactions(&block.get_prev_hash(), block.get_transaction())?;
+        if !valid {
+            return Err(InvalidBlock(format!(
+                "Block {} has an invalid transaction",
+                block.get_hash()
+            ))
+            .into());
+        }
+        let hash = block.get_hash();
+        let tip = self.get_tip();
+        self.add_block(block.clone())?;
+        if tip != hash && self.get_tip() == hash {
+            if block.get_prev_hash() == tip {
+                self.connect_tip(&block)?;
+            } else {
+                self.reorganize(&tip)?;
+            }
+        }
+        Ok(self
+            .inner
+            .lock()
+            .unwrap()
+            .orphan_blocks
+            .take_children(&hash))
+    }
+
+    /// Updates the UTXO set with `block`, which extends the old tip, and
+    /// drops what it confirms from the mempool.
+    fn connect_tip(&self, block: &Block) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.utxo.update(block)?;
+        inner.mempool.remove_block(block);
+        Ok(())
+    }
+
+    /// Follows the tip to another branch than the one of `old_tip`: rebuilds
+    /// the UTXO set, drops what the new branch confirms from the mempool and
+    /// gives it back the transactions of the blocks left behind.
+    fn reorganize(&self, old_tip: &str) -> Result<()> {
+        let (disconnected, connected) = {
+            let inner = self.inner.lock().unwrap();
+            let blockchain = &inner.utxo.blockchain;
+            blockchain.find_fork(old_tip, &blockchain.get_tip())?
+        };
+        info!(
+            "reorganize: {} blocks disconnected, {} connected",
+            disconnected.len(),
+            connected.len()
+        );
+        self.utxo_reindex()?;
+        for block in connected.iter().rev() {
+            self.remove_mempool_block(block);
+        }
+        for block in disconnected.iter().rev() {
+            for tx in block
+                .get_transaction()
+                .iter()
+                .filter(|tx| !tx.is_coinbase())
+            {
+                if let Err(e) = self.accept_mempool(tx.clone()) {
+                    debug!("drop transaction {} of a disconnected block: {}", tx.id, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Announces the new tip after blocks were added, then retries the
+    /// orphan transactions they may have confirmed parents of, and gives the
+    /// miner a template on the new tip.
+    fn tip_changed(&self) -> Result<()> {
+        let tip = self.get_block(&self.get_tip())?;
+        self.relay_block(&tip)?;
+        let orphans = self.inner.lock().unwrap().orphan_txs.take_all();
+        self.process_orphan_txs(orphans);
+        self.update_template(true)
+    }
+
+    /// Keeps a block whose parent we don't have and asks its sender for the
+    /// parent, through the headers when the peer speaks them.
+    fn add_orphan_block(&self, peer_id: u64, block: Block) -> Result<()> {
+        let hash = block.get_hash();
+        let prev = block.get_prev_hash();
+        let size = serialize(&block)?.len();
+        let parent_pending = {
+            let mut inner = self.inner.lock().unwrap();
+            let pending = inner.orphan_blocks.contains(&prev);
+            let now = self.clock.now();
+            if !inner
+                .orphan_blocks
+                .add(&hash, block, vec![prev.clone()], size, peer_id, now)
+            {
+                return Err(format_err!("Orphan block {} is too large", hash));
+            }
+            pending
+        };
+        info!("orphan block {}, missing parent {}", hash, prev);
+
+        let peer = match self.get_peer(peer_id) {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+        if peer.version >= HEADERS_VERSION {
+            self.send_get_headers(&peer)
+        } else if parent_pending {
+            Ok(())
+        } else {
+            self.send_get_data(&peer, InvKind::Block, &prev)
+        }
+    }
+
+    /// Keeps a transaction spending outputs of `parents`, which we haven't
+    /// seen, and asks its sender for them.
+    fn add_orphan_tx(&self, peer_id: u64, tx: Transaction, parents: Vec<String>) -> Result<()> {
+        let txid = tx.id.clone();
+        let size = serialize(&tx)?.len();
+        let missing: Vec<String> = {
+            let mut inner = self.inner.lock().unwrap();
+            let missing = parents
+                .iter()
+                .filter(|parent| !inner.orphan_txs.contains(parent))
+                .cloned()
+                .collect();
+            let now = self.clock.now();
+            if !inner.orphan_txs.add(&txid, tx, parents, size, peer_id, now) {
+                return Err(format_err!("Orphan transaction {} is too large", txid));
+            }
+            missing
+        };
+        info!("orphan tx {}, missing parents {:?}", txid, missing);
+
+        if let Some(peer) = self.get_peer(peer_id) {
+            for parent in &missing {
+                self.request_tx(&peer, parent)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Asks `peer` for transaction `txid`, unless some peer is already asked
+    /// for it or this one has `MAX_TX_IN_FLIGHT` requests outstanding.
+    fn request_tx(&self, peer: &Peer, txid: &str) -> Result<()> {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.tx_requests.contains_key(txid) {
+                return Ok(());
+            }
+            let busy = inner
+                .tx_requests
+                .values()
+                .filter(|(id, _)| *id == peer.id)
+                .count();
+            if busy >= MAX_TX_IN_FLIGHT {
+                debug!("peer {} has too many tx requests, skip {}", peer.id, txid);
+                return Ok(());
+            }
+            inner
+                .tx_requests
+                .insert(txid.to_string(), (peer.id, self.clock.now()));
+        }
+        self.send_get_data(peer, InvKind::Tx, txid)
+    }
+
+    /// Retries orphan transactions whose parents came in. Those the mempool
+    /// takes are relayed and free their own orphans in turn, those still
+    /// missing parents go back to the pool.
+    fn process_orphan_txs(&self, mut orphans: Vec<Orphan<Transaction>>) {
+        while let Some(orphan) = orphans.pop() {
+            let txid = orphan.id.clone();
+            match self.accept_mempool(orphan.item.clone()) {
+                Ok(()) => {
+                    info!("accept orphan tx {}", txid);
+                    self.relay_inventory(InvKind::Tx, &txid, Some(orphan.peer_id));
+                    let children = self.inner.lock().unwrap().orphan_txs.take_children(&txid);
+                    orphans.extend(children);
+                }
+                Err(e) if e.is::<MissingInputs>() => {
+                    let now = self.clock.now();
+                    self.inner.lock().unwrap().orphan_txs.restore(orphan, now);
+                }
+                Err(e) => {
+                    info!("reject orphan tx {}: {}", txid, e);
+                    if let Some(code) = reject_code(&e) {
+                        let reason = e.to_string();
+                        self.send_reject(orphan.peer_id, InvKind::Tx, &txid, code, &reason);
+                    }
+                    if e.is::<InvalidTransaction>() {
+                        self.misbehaving(orphan.peer_id, 10, &e.to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Gives the miner a new template when the tip moved, or when the
+    /// mempool now pays more fees than the block being mined. The miner
+    /// idles while syncing or with nothing to mine.
+    fn update_template(&self, tip_moved: bool) -> Result<()> {
+        let miner = match &self.miner {
+            Some(miner) => miner,
+            None => return Ok(()),
+        };
+        if self.inner.lock().unwrap().sync.is_syncing() {
+            miner.update(None);
+            return Ok(());
+        }
+        let template = self.block_template()?;
+        // nothing but the coinbase
+        if template.transactions.len() == 1 {
+            miner.update(None);
+            return Ok(());
+        }
+        if !tip_moved && matches!(miner.current_fees(), Some(current) if current >= template.fees) {
+            return Ok(());
+        }
+
+        info!(
+            "new block template at {}: {} transactions, fees {}",
+            template.height,
+            template.transactions.len(),
+            template.fees
+        );
+        miner.update(Some(template));
+        Ok(())
+    }
+
+    /// A coinbase paying our miner address the subsidy and the fees, then the
+    /// best of the mempool, on top of our tip.
+    pub(crate) fn block_template(&self) -> Result<BlockTemplate> {
+        let (mut txs, fees, tip, height) = {
+            let inner = self.inner.lock().unwrap();
+            let txs = inner.mempool.block_template();
+            let fees = txs
+                .iter()
+                .filter_map(|tx| inner.mempool.get(&tx.id))
+                .try_fold(0i32, |fees, entry| fees.checked_add(entry.fee))
+                .ok_or_else(|| format_err!("Block template fees are out of range"))?;
+            let blockchain = &inner.utxo.blockchain;
+            (
+                txs,
+                fees,
+                blockchain.get_tip(),
+                blockchain.get_best_height()? + 1,
+            )
+        };
+        // the height keeps coinbase ids apart
+        let mut cbtx =
+            Transaction::new_coinbase(self.miner_address.clone(), format!("height {}", height))?;
+        cbtx.vout[0].value = SUBSIDY
+            .checked_add(fees)
+            .ok_or_else(|| format_err!("Block template reward is out of range"))?;
+        cbtx.id = cbtx.hash()?;
+        txs.insert(0, cbtx);
+        Ok(BlockTemplate {
+            prev_block_hash: tip,
+            height,
+            transactions: txs,
+            fees,
+        })
+    }
+
+    /// Tells the peer a block `hash` failed to store from why and scores
+    /// it, when the block broke the chain rules rather than failed for
+    /// reasons of our own.
+    fn block_failed(&self, peer_id: u64, hash: &str, e: &anyhow::Error) {
+        if e.is::<InvalidBlock>() {
+            self.send_reject(
+                peer_id,
+                InvKind::Block,
+                hash,
+                RejectCode::Invalid,
+                &e.to_string(),
+            );
+            self.misbehaving(peer_id, 100, &e.to_string());
+        }
+    }
+
+    /// Adds `howmuch` to the peer's misbehavior score, banning its ip and
+    /// dropping it once the score reaches `BAN_SCORE`.
+    fn misbehaving(&self, peer_id: u64, howmuch: i32, reason: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let peer = match inner.peers.get_mut(&peer_id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        peer.misbehavior += howmuch;
+        info!(
+            "peer {} misbehaving ({} -> {}): {}",
+            peer_id, howmuch, peer.misbehavior, reason
+        );
+        if peer.misbehavior < BAN_SCORE {
+            return;
+        }
+
+        let peer = peer.clone();
+        let now = self.clock.timestamp();
+        if let Err(e) = inner.banman.ban(&peer.ip, DEFAULT_BAN_TIME, reason, now) {
+            error!("save ban list failed: {}", e);
+        }
+        peer.disconnect();
+    }
+
+    fn count_peers(&self, direction: Direction) -> usize {
+        self.inner
+            .lock()
+            .unwrap()
+            .peers
+            .values()
+            .filter(|peer| peer.direction == direction)
+            .count()
+    }
+
+    /* -------------------- inner function -------------------- */
+
+    fn add_addrs(&self, addrs: &[String], source: &str) {
+        let now = self.clock.timestamp();
+        self.inner.lock().unwrap().addrman.add(addrs, source, now);
+    }
+
+    /// Records hashes a peer has, so they aren't announced back to it.
+    fn mark_known(&self, peer_id: u64, hashs: &[String]) {
+        if let Some(peer) = self.get_peer(peer_id) {
+            for hash in hashs {
+                peer.add_known(hash);
+            }
+        }
+    }
+
+    fn replace_in_transit(&self, hashs: Vec<String>) {
+        let bit = &mut self.inner.lock().unwrap().blocks_in_transit;
+        bit.clone_from(&hashs)
+    }
+
+    fn get_in_transit(&self) -> Vec<String> {
+        self.inner.lock().unwrap().blocks_in_transit.clone()
+    }
+
+    fn is_banned(&self, ip: &str) -> bool {
+        let now = self.clock.timestamp();
+        self.inner.lock().unwrap().banman.is_banned(ip, now)
+    }
+
+    fn set_ban(&self, msg: SetBanmsg) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if !msg.add {
+            inner.banman.unban(&msg.ip)?;
+            return Ok(());
+        }
+        let now = self.clock.timestamp();
+        inner
+            .banman
+            .ban(&msg.ip, msg.ban_time, "manually added", now)?;
+        for peer in inner.peers.values() {
+            if peer.ip == msg.ip {
+                peer.disconnect();
+            }
+        }
+        Ok(())
+    }
+
+    fn get_mempool_tx(&self, txid: &str) -> Option<Transaction> {
+        self.inner
+            .lock()
+            .unwrap()
+            .mempool
+            .get(txid)
+            .map(|entry| entry.tx.clone())
+    }
+
+    fn accept_mempool(&self, tx: Transaction) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let now = self.clock.timestamp();
+        inner.mempool.expire(now);
+        inner.mempool.accept(tx, &inner.utxo, now)
+    }
+
+    fn remove_mempool_block(&self, block: &Block) {
+        self.inner.lock().unwrap().mempool.remove_block(block);
+    }
+
+    fn load_mempool(&self) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        inner.mempool.load(&inner.utxo, self.clock.timestamp())
+    }
+
+    fn save_mempool(&self) -> Result<()> {
+        self.inner.lock().unwrap().mempool.save()
+    }
+
+    pub(crate) fn get_tip(&self) -> String {
+        self.inner.lock().unwrap().utxo.blockchain.get_tip()
+    }
+
+    pub(crate) fn get_best_height(&self) -> Result<i32> {
+        self.inner.lock().unwrap().utxo.blockchain.get_best_height()
+    }
+
+    fn has_block(&self, block_hash: &str) -> Result<bool> {
+        self.inner
+            .lock()
+            .unwrap()
+            .utxo
+            .blockchain
+            .has_block(block_hash)
+    }
+
+    /// Our block locator, led by the best header still being downloaded.
+    fn get_block_locator(&self) -> Vec<String> {
+        let inner = self.inner.lock().unwrap();
+        let mut locator: Vec<String> = inner.sync.best_header().into_iter().collect();
+        locator.extend(inner.utxo.blockchain.get_block_locator());
+        locator
+    }
+
+    fn get_hashs_after(&self, locator: &[String], stop_hash: &str) -> Vec<String> {
+        self.inner.lock().unwrap().utxo.blockchain.get_hashs_after(
+            locator,
+            stop_hash,
+            MAX_BLOCKS_INV,
+        )
+    }
+
+    fn get_block(&self, block_hash: &str) -> Result<Block> {
+        self.inner
+            .lock()
+            .unwrap()
+            .utxo
+            .blockchain
+            .get_block(block_hash)
+    }
+
+    fn add_block(&self, block: Block) -> Result<()> {
+        self.inner.lock().unwrap().utxo.blockchain.add_block(block)
+    }
+
+    fn utxo_reindex(&self) -> Result<()> {
+        self.inner.lock().unwrap().utxo.reindex()
+    }
+
+    /* -------------------- send and function -------------------- */
+
+    /// Queues `msg` on the session of `peer`, held back until the handshake
+    /// is done.
+    fn send_to(&self, peer: &Peer, msg: &Message) -> Result<()> {
+        if !peer.handshaked {
+            let mut inner = self.inner.lock().unwrap();
+            inner.pending.entry(peer.id).or_default().push(msg.clone());
+            return Ok(());
+        }
+        if msg.min_version() > peer.version {
+            debug!(
+                "skip {} for peer {} at version {}",
+                msg.command(),
+                peer.id,
+                peer.version
+            );
+            return Ok(());
+        }
+        peer.send(msg.clone())
+    }
+
+    fn send_block(&self, peer: &Peer, b: &Block) -> Result<()> {
+        info!(
+            "send block data to peer: {} block hash: {}",
+            peer.id,
+            b.get_hash()
+        );
+        let data = Blockmsg {
+            addr_from: self.node_address.clone(),
+            block: b.clone(),
+        };
+        peer.send(Message::Block(data))
+    }
+
+    /// Announces our own address, other addresses are only sent on getaddr.
+    fn send_addr(&self, peer: &Peer) -> Result<()> {
+        info!("send address info to peer: {}", peer.id);
+        let nodes = vec![self.node_address.clone()];
+        self.send_to(peer, &Message::Addr(nodes))
+    }
+
+    /// Announces `hash` to every handshaked node except `origin` and those
+    /// that already have it.
+    fn relay_inventory(&self, kind: InvKind, hash: &str, origin: Option<u64>) {
+        let peers: Vec<Peer> = self
+            .inner
+            .lock()
+            .unwrap()
+            .peers
+            .values()
+            .filter(|peer| peer.handshaked && !peer.addr.is_empty())
+            .filter(|peer| Some(peer.id) != origin && !peer.knows(hash))
+            .cloned()
+            .collect();
+        for peer in peers {
+            peer.add_known(hash);
+            if let Err(e) = self.send_inv(&peer, kind, vec![hash.to_string()]) {
+                info!("relay {} {} to peer {} failed: {}", kind, hash, peer.id, e);
+            }
+        }
+    }
+
+    /// Announces a new block to every handshaked node that doesn't have it:
+    /// a compact block to those that take one, an inv to the others.
+    fn relay_block(&self, block: &Block) -> Result<()> {
+        let hash = block.get_hash();
+        let compact = CompactBlock::new(block)?;
+        let peers: Vec<Peer> = self
+            .inner
+            .lock()
+            .unwrap()
+            .peers
+            .values()
+            .filter(|peer| peer.handshaked && !peer.addr.is_empty() && !peer.knows(&hash))
+            .cloned()
+            .collect();
+        for peer in peers {
+            peer.add_known(&hash);
+            let sent = if peer.version >= COMPACT_VERSION {
+                info!("send compact block {} to peer: {}", hash, peer.id);
+                self.send_to(&peer, &Message::CmpctBlock(compact.clone()))
+            } else {
+                self.send_inv(&peer, InvKind::Block, vec![hash.clone()])
+            };
+            if let Err(e) = sent {
+                info!("relay block {} to peer {} failed: {}", hash, peer.id, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn send_inv(&self, peer: &Peer, kind: InvKind, hashs: Vec<String>) -> Result<()> {
+        info!(
+            "send inv message to peer: {} kind: {} data: {:?}",
+            peer.id, kind, hashs
+        );
+        let items = hashs
+            .iter()
+            .map(|hash| InvItem::new(kind, hash))
+            .collect::<Result<Vec<InvItem>>>()?;
+        let data = Invmsg {
+            addr_from: self.node_address.clone(),
+            items,
+        };
+        self.send_to(peer, &Message::Inv(data))
+    }
+
+    fn send_get_blocks(&self, peer: &Peer) -> Result<()> {
+        info!("send get blocks message to peer: {}", peer.id);
+        let data = GetBlocksmsg {
+            addr_from: self.node_address.clone(),
+            locator: self
+                .inner
+                .lock()
+                .unwrap()
+                .utxo
+                .blockchain
+                .get_block_locator(),
+            stop_hash: String::new(),
+        };
+        self.send_to(peer, &Message::GetBlock(data))
+    }
+
+    fn send_get_headers(&self, peer: &Peer) -> Result<()> {
+        info!("send get headers message to peer: {}", peer.id);
+        let data = GetHeadersmsg {
+            locator: self.get_block_locator(),
+            stop_hash: String::new(),
+        };
+        self.send_to(peer, &Message::GetHeaders(data))
+    }
+
+    fn send_get_data(&self, peer: &Peer, kind: InvKind, id: &str) -> Result<()> {
+        info!(
+            "send get data message to peer: {} kind: {} id: {}",
+            peer.id, kind, id
+        );
+        let data = GetDatamsg {
+            addr_from: self.node_address.clone(),
+            items: vec![InvItem::new(kind, id)?],
+        };
+        self.send_to(peer, &Message::GetData(data))
+    }
+
+    fn send_tx(&self, peer: &Peer, tx: &Transaction) -> Result<()> {
+        info!("send tx to peer: {} txid: {}", peer.id, &tx.id);
+        let data = Txmsg {
+            addr_from: self.node_address.clone(),
+            transaction: tx.clone(),
+        };
+        peer.send(Message::Tx(data))
+    }
+
+    fn send_version(&self, peer: &Peer) -> Result<()> {
+        info!("send version info to peer: {}", peer.id);
+        let data = Versionmsg {
+            addr_from: self.node_address.clone(),
+            version: PROTOCOL_VERSION,
+            services: NODE_NETWORK,
+            user_agent: user_agent(),
+            nonce: self.nonce,
+            timestamp: Utc::now().timestamp(),
+            best_height: self.get_best_height()?,
+        };
+        peer.send(Message::Version(data))
+    }
+
+    fn handle_version(&self, peer_id: u64, msg: Versionmsg) -> Result<()> {
+        info!("receive version msg: {:#?}", msg);
+        if msg.nonce == self.nonce {
+            return Err(format_err!("connected to self"));
+        }
+        if msg.version < MIN_PEER_VERSION {
+            return Err(format_err!("peer version {} is too old", msg.version));
+        }
+
+        let peer = {
+            let mut inner = self.inner.lock().unwrap();
+            let peer = match inner.peers.get_mut(&peer_id) {
+                Some(peer) => peer,
+                None => return Ok(()),
+            };
+            // only what the peer claims, kept for addrman and never dialled
+            if peer.direction == Direction::Inbound {
+                peer.addr = msg.addr_from.clone();
+            }
+            peer.version = msg.version.min(PROTOCOL_VERSION);
+            peer.services = msg.services;
+            peer.user_agent = msg.user_agent.clone();
+            peer.start_height = msg.best_height;
+            peer.best_height = msg.best_height;
+            peer.clone()
+        };
+
+        // outbound sessions open with our version, inbound ones answer with it
+        if peer.direction == Direction::Inbound {
+            self.send_version(&peer)?;
+        }
+        peer.send(Message::Verack)
+    }
+
+    fn handle_verack(&self, peer_id: u64) -> Result<()> {
+        let (peer, pending) = {
+            let mut inner = self.inner.lock().unwrap();
+            let peer = match inner.peers.get_mut(&peer_id) {
+                Some(peer) => peer,
+                None => return Ok(()),
+            };
+            peer.handshaked = true;
+            let peer = peer.clone();
+            (peer, inner.pending.remove(&peer_id).unwrap_or_default())
+        };
+        info!(
+            "peer {} handshaked: {} version {} {}",
+            peer.id, peer.addr, peer.version, peer.user_agent
+        );
+
+        for msg in pending {
+            self.send_to(&peer, &msg)?;
+        }
+
+        // one-shot clients don't serve blocks or want addresses
+        if peer.addr.is_empty() || peer.services & NODE_NETWORK == 0 {
+            return Ok(());
+        }
+
+        let my_best_height = self.get_best_height()?;
+        if my_best_height < peer.start_height {
+            if peer.version >= HEADERS_VERSION {
+                self.send_get_headers(&peer)?;
+            } else {
+                self.send_get_blocks(&peer)?;
+            }
+        }
+
+        self.send_addr(&peer)?;
+
+        if peer.direction == Direction::Inbound {
+            self.add_addrs(std::slice::from_ref(&peer.addr), &peer.addr);
+            return Ok(());
+        }
+        // we reached it where we dialled it, and it can tell us about others
+        {
+            let mut inner = self.inner.lock().unwrap();
+            let now = self.clock.timestamp();
+            inner
+                .addrman
+                .add(std::slice::from_ref(&peer.addr), &peer.addr, now);
+            inner.addrman.good(&peer.addr, now);
+        }
+        if peer.version >= ADDR_VERSION {
+            peer.send(Message::GetAddr)?;
+        }
+        Ok(())
+    }
+
+    fn handle_ping(&self, peer_id: u64, nonce: u64) -> Result<()> {
+        match self.get_peer(peer_id) {
+            Some(peer) => peer.send(Message::Pong(nonce)),
+            None => Ok(()),
+        }
+    }
+
+    fn handle_pong(&self, peer_id: u64, nonce: u64) -> Result<()> {
+        if let Some(peer) = self.inner.lock().unwrap().peers.get_mut(&peer_id) {
+            match peer.ping {
+                Some((expected, sent)) if expected == nonce => {
+                    peer.latency = Some(self.clock.now().duration_since(sent));
+                    peer.ping = None;
+                }
+                _ => debug!("peer {} unexpected pong {}", peer_id, nonce),
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_get_peer_info(&self, peer_id: u64) -> Result<()> {
+        match self.get_peer(peer_id) {
+            Some(peer) => peer.send(Message::PeerInfo(self.get_peer_info())),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether the session comes from a client on this machine.
+    fn is_local(&self, peer_id: u64) -> bool {
+        match self.get_peer(peer_id) {
+            Some(peer) => match peer.ip.parse::<IpAddr>() {
+                Ok(ip) => ip.is_loopback(),
+                Err(_) => false,
+            },
+            None => false,
+        }
+    }
+
+    fn handle_stop(&self, peer_id: u64) -> Result<()> {
+        info!("stop requested by peer {}", peer_id);
+        self.shutdown.cancel();
+        Ok(())
+    }
+
+    fn handle_list_banned(&self, peer_id: u64) -> Result<()> {
+        let now = self.clock.timestamp();
+        let bans = self.inner.lock().unwrap().banman.list(now);
+        match self.get_peer(peer_id) {
+            Some(peer) => peer.send(Message::Banned(bans)),
+            None => Ok(()),
+        }
+    }
+
+    fn handle_addr(&self, peer_id: u64, msg: Vec<String>) -> Result<()> {
+        info!("receive address msg: {:#?}", msg);
+        if msg.len() > MAX_ADDR_TO_SEND {
+            self.misbehaving(peer_id, 20, "oversized addr message");
+            return Err(format_err!("Addr message with {} entries", msg.len()));
+        }
+        if let Some(peer) = self.get_peer(peer_id) {
+            let source = if peer.addr.is_empty() {
+                peer.ip
+            } else {
+                peer.addr
+            };
+            self.add_addrs(&msg, &source);
+        }
+        Ok(())
+    }
+
+    fn handle_get_addr(&self, peer_id: u64) -> Result<()> {
+        let now = self.clock.timestamp();
+        let addrs = self.inner.lock().unwrap().addrman.get_addr(now);
+        match self.get_peer(peer_id) {
+            Some(peer) => peer.send(Message::Addr(addrs)),
+            None => Ok(()),
+        }
+    }
+
+    fn handle_block(&self, peer_id: u64, msg: Blockmsg) -> Result<()> {
+        info!(
+            "receive block msg: {}, {}",
+            msg.addr_from,
+            msg.block.get_hash()
+        );
+        if !msg.block.verify()? {
+            self.send_reject(
+                peer_id,
+                InvKind::Block,
+                &msg.block.get_hash(),
+                RejectCode::Invalid,
+                "invalid proof of work",
+            );
+            self.misbehaving(peer_id, 100, "invalid block proof of work");
+            return Err(format_err!("Block {} is invalid", msg.block.get_hash()));
+        }
+
+        let synced = self
+            .inner
+            .lock()
+            .unwrap()
+            .sync
+            .block_received(peer_id, msg.block.clone());
+        match synced {
+            Ok(true) => {
+                self.connect_blocks()?;
+                self.request_blocks();
+                return Ok(());
+            }
+            Ok(false) => {}
+            Err(e) => {
+                let hash = msg.block.get_hash();
+                self.send_reject(
+                    peer_id,
+                    InvKind::Block,
+                    &hash,
+                    RejectCode::Invalid,
+                    &e.to_string(),
+                );
+                self.misbehaving(peer_id, 100, &e.to_string());
+                return Err(e);
+            }
+        }
+
+        // a block outside headers sync waits for its parent as an orphan
+        if self
+            .inner
+            .lock()
+            .unwrap()
+            .orphan_blocks
+            .contains(&msg.block.get_hash())
+        {
+            return Ok(());
+        }
+        if !self.has_block(&msg.block.get_prev_hash())? {
+            return self.add_orphan_block(peer_id, msg.block);
+        }
+        let peer = self.get_peer(peer_id);
+        let hash = msg.block.get_hash();
+        if let Err(e) = self.accept_block(msg.block) {
+            self.block_failed(peer_id, &hash, &e);
+            return Err(e);
+        }
+
+        let mut in_transit = self.get_in_transit();
+        if !in_transit.is_empty() {
+            let block_hash = &in_transit[0];
+            if let Some(peer) = &peer {
+                self.send_get_data(peer, InvKind::Block, block_hash)?;
+            }
+            in_transit.remove(0);
+            self.replace_in_transit(in_transit);
+        } else {
+            self.tip_changed()?;
+            match &peer {
+                // the last inv may have been capped, ask for what follows
+                Some(peer) if peer.version < HEADERS_VERSION => self.send_get_blocks(peer)?,
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_inv(&self, peer_id: u64, msg: Invmsg) -> Result<()> {
+        info!("receive inv msg: {:#?}", msg);
+        if msg.items.is_empty() {
+            return Ok(());
+        }
+        if msg.items.len() > MAX_INV_SIZE {
+            self.misbehaving(peer_id, 20, "oversized inv message");
+            return Err(format_err!("Inv message with {} items", msg.items.len()));
+        }
+        let peer = match self.get_peer(peer_id) {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+        self.check_inventory_kinds(peer_id, &msg.items);
+        let blocks = inventory_hashs(&msg.items, InvKind::Block);
+        let txs = inventory_hashs(&msg.items, InvKind::Tx);
+
+        if !blocks.is_empty() && peer.version >= HEADERS_VERSION {
+            // new blocks are fetched through their headers
+            for hash in &blocks {
+                if !self.has_block(hash)? && !self.inner.lock().unwrap().sync.contains(hash) {
+                    self.send_get_headers(&peer)?;
+                    break;
+                }
+            }
+        } else if !blocks.is_empty() {
+            // oldest first, fetched one at a time
+            let mut new_in_transit = Vec::new();
+            for b in blocks {
+                if !self.has_block(&b)? && !self.inner.lock().unwrap().orphan_blocks.contains(&b) {
+                    new_in_transit.push(b);
+                }
+            }
+            if !new_in_transit.is_empty() {
+                // the rest is asked for again once these are in
+                new_in_transit.truncate(MAX_BLOCKS_IN_TRANSIT);
+                let block_hash = new_in_transit.remove(0);
+                self.send_get_data(&peer, InvKind::Block, &block_hash)?;
+                self.replace_in_transit(new_in_transit);
+            }
+        }
+
+        for txid in &txs {
+            if self.get_mempool_tx(txid).is_none()
+                && !self.inner.lock().unwrap().orphan_txs.contains(txid)
+            {
+                self.request_tx(&peer, txid)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// A peer lacks what we asked it for. A transaction may then be asked of
+    /// another peer announcing it, a block of another peer that has it.
+    fn handle_not_found(&self, peer_id: u64, items: Vec<InvItem>) -> Result<()> {
+        info!("receive notfound msg from peer {}: {:?}", peer_id, items);
+        if items.len() > MAX_INV_SIZE {
+            self.misbehaving(peer_id, 20, "oversized notfound message");
+            return Err(format_err!("Notfound message with {} items", items.len()));
+        }
+        self.check_inventory_kinds(peer_id, &items);
+        let mut inner = self.inner.lock().unwrap();
+        for txid in inventory_hashs(&items, InvKind::Tx) {
+            if matches!(inner.tx_requests.get(&txid), Some((peer, _)) if *peer == peer_id) {
+                inner.tx_requests.remove(&txid);
+            }
+        }
+        for hash in inventory_hashs(&items, InvKind::Block) {
+            inner.sync.not_found(peer_id, &hash);
+        }
+        drop(inner);
+        for item in items {
+            self.record_reject(peer_id, item, RejectCode::NotFound, String::new());
+        }
+        self.request_blocks();
+        Ok(())
+    }
+
+    /// A peer turned down a transaction or block we sent it.
+    fn handle_reject(&self, peer_id: u64, msg: Rejectmsg) -> Result<()> {
+        info!(
+            "peer {} rejected {} {}: {} ({})",
+            peer_id, msg.item.kind, msg.item.hash, msg.reason, msg.code
+        );
+        self.record_reject(peer_id, msg.item, msg.code, msg.reason);
+        Ok(())
+    }
+
+    fn handle_get_rejects(&self, peer_id: u64) -> Result<()> {
+        let rejects = self.inner.lock().unwrap().rejects.iter().cloned().collect();
+        match self.get_peer(peer_id) {
+            Some(peer) => peer.send(Message::Rejects(rejects)),
+            None => Ok(()),
+        }
+    }
+
+    /// Keeps a reject or notfound from a peer for getrejects, forgetting the
+    /// oldest past `MAX_REJECTS`.
+    fn record_reject(&self, peer_id: u64, item: InvItem, code: RejectCode, mut reason: String) {
+        let peer = match self.get_peer(peer_id) {
+            Some(peer) if peer.addr.is_empty() => peer.ip,
+            Some(peer) => peer.addr,
+            None => return,
+        };
+        if let Some((end, _)) = reason.char_indices().nth(MAX_REJECT_REASON) {
+            reason.truncate(end);
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.rejects.push_back(RejectEntry {
+            time: self.clock.timestamp(),
+            peer,
+            item,
+            code,
+            reason,
+        });
+        if inner.rejects.len() > MAX_REJECTS {
+            inner.rejects.pop_front();
+        }
+    }
+
+    /// Tells a peer why we turned down the transaction or block `hash` it
+    /// sent, when it speaks REJECT_VERSION.
+    fn send_reject(&self, peer_id: u64, kind: InvKind, hash: &str, code: RejectCode, reason: &str) {
+        let peer = match self.get_peer(peer_id) {
+            Some(peer) if peer.version >= REJECT_VERSION => peer,
+            _ => return,
+        };
+        let item = match InvItem::new(kind, hash) {
+            Ok(item) => item,
+            Err(_) => return,
+        };
+        let data = Rejectmsg {
+            item,
+            code,
+            reason: reason.to_string(),
+        };
+        info!("send reject to peer {}: {:?}", peer_id, data);
+        if let Err(e) = peer.send(Message::Reject(data)) {
+            info!("send reject to peer {} failed: {}", peer_id, e);
+        }
+    }
+
+    /// Scores a peer for inventory of kinds we don't know, those items are
+    /// then ignored.
+    fn check_inventory_kinds(&self, peer_id: u64, items: &[InvItem]) {
+        if let Some(item) = items
+            .iter()
+            .find(|item| matches!(item.kind, InvKind::Unknown(_)))
+        {
+            self.misbehaving(peer_id, 10, &format!("inventory of {}", item.kind));
+        }
+    }
+
+    fn handle_get_headers(&self, peer_id: u64, msg: GetHeadersmsg) -> Result<()> {
+        info!(
+            "receive get headers msg: {} locator hashes",
+            msg.locator.len()
+        );
+        let headers = self
+            .inner
+            .lock()
+            .unwrap()
+            .utxo
+            .blockchain
+            .get_headers_after(&msg.locator, &msg.stop_hash, MAX_HEADERS_RESULTS)?;
+        match self.get_peer(peer_id) {
+            Some(peer) => peer.send(Message::Headers(headers)),
+            None => Ok(()),
+        }
+    }
+
+    /// Stores the headers a peer sent, asks for more when the reply was
+    /// full, and schedules the bodies.
+    fn handle_headers(&self, peer_id: u64, headers: Vec<BlockHeader>) -> Result<()> {
+        info!("receive headers msg: {} headers", headers.len());
+        if headers.len() > MAX_HEADERS_RESULTS {
+            self.misbehaving(peer_id, 20, "oversized headers message");
+            return Err(format_err!(
+                "Headers message with {} entries",
+                headers.len()
+            ));
+        }
+        let last = match headers.last() {
+            Some(last) => last.clone(),
+            None => return Ok(()),
+        };
+
+        let tip_height = self.get_best_height()?;
+        let added = {
+            let mut inner = self.inner.lock().unwrap();
+            let inner = &mut *inner;
+            let blockchain = &inner.utxo.blockchain;
+            inner
+                .sync
+                .add_headers(peer_id, &headers, tip_height, |hash| {
+                    blockchain
+                        .get_block(hash)
+                        .ok()
+                        .map(|block| block.get_height())
+                })
+        };
+        let added = match added {
+            Ok(added) => added,
+            Err(e) => {
+                // headers only come in reply to our locator, so they must connect
+                let howmuch = if e.is::<InvalidHeader>() { 100 } else { 20 };
+                self.misbehaving(peer_id, howmuch, &e.to_string());
+                return Err(e);
+            }
+        };
+
+        let peer = {
+            let mut inner = self.inner.lock().unwrap();
+            match inner.peers.get_mut(&peer_id) {
+                Some(peer) => {
+                    peer.best_height = peer.best_height.max(last.height);
+                    peer.clone()
+                }
+                None => return Ok(()),
+            }
+        };
+        // a full reply we kept nothing of would only come again
+        if headers.len() == MAX_HEADERS_RESULTS && added > 0 {
+            self.send_get_headers(&peer)?;
+        }
+        self.request_blocks();
+        Ok(())
+    }
+
+    /// Rebuilds an announced block from the mempool, asking the peer only for
+    /// the transactions we miss. Blocks that don't extend our tip are
+    /// fetched the usual way.
+    fn handle_cmpct_block(&self, peer_id: u64, compact: CompactBlock) -> Result<()> {
+        let hash = compact.header.hash.clone();
+        info!(
+            "receive compact block msg: {} with {} transactions",
+            hash,
+            compact.tx_count()
+        );
+        if !compact.header.verify()? {
+            self.send_reject(
+                peer_id,
+                InvKind::Block,
+                &hash,
+                RejectCode::Invalid,
+                "invalid proof of work",
+            );
+            self.misbehaving(peer_id, 100, "invalid compact block proof of work");
+            return Err(format_err!("Compact block {} is invalid", hash));
+        }
+        let peer = match self.get_peer(peer_id) {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+        if self.has_block(&hash)?
+            || self
+                .inner
+                .lock()
+                .unwrap()
+                .partial_blocks
+                .contains_key(&hash)
+        {
+            return Ok(());
+        }
+        let prev_hash = &compact.header.prev_block_hash;
+        if !self.has_block(prev_hash)? {
+            return self.send_get_headers(&peer);
+        }
+        if *prev_hash != self.get_tip() {
+            return self.send_get_data(&peer, InvKind::Block, &hash);
+        }
+
+        let partial = PartialBlock::new(
+            &compact,
+            self.inner.lock().unwrap().mempool.transactions(),
+            peer_id,
+        );
+        let partial = match partial {
+            Ok(partial) => partial,
+            Err(e) => {
+                self.misbehaving(peer_id, 100, &e.to_string());
+                return Err(e);
+            }
+        };
+        let missing = partial.missing();
+        if missing.is_empty() {
+            return self.complete_block(&peer, partial);
+        }
+
+        info!(
+            "compact block {} misses {} transactions",
+            hash,
+            missing.len()
+        );
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner
+                .partial_blocks
+                .retain(|_, partial| partial.peer_id != peer_id);
+            inner.partial_blocks.insert(hash.clone(), partial);
+        }
+        let data = GetBlockTxnmsg {
+            block_hash: hash,
+            indexes: missing,
+        };
+        self.send_to(&peer, &Message::GetBlockTxn(data))
+    }
+
+    fn handle_get_block_txn(&self, peer_id: u64, msg: GetBlockTxnmsg) -> Result<()> {
+        info!(
+            "receive get block txn msg: {} {} transactions",
+            msg.block_hash,
+            msg.indexes.len()
+        );
+        let block = self.get_block(&msg.block_hash)?;
+        let mut transactions = Vec::new();
+        for index in msg.indexes {
+            match block.get_transaction().get(index) {
+                Some(tx) => transactions.push(tx.clone()),
+                None => {
+                    self.misbehaving(peer_id, 100, "getblocktxn index out of range");
+                    return Err(format_err!(
+                        "Block {} has no transaction {}",
+                        msg.block_hash,
+                        index
+                    ));
+                }
+            }
+        }
+        let data = BlockTxnmsg {
+            block_hash: msg.block_hash,
+            transactions,
+        };
+        match self.get_peer(peer_id) {
+            Some(peer) => peer.send(Message::BlockTxn(data)),
+            None => Ok(()),
+        }
+    }
+
+    fn handle_block_txn(&self, peer_id: u64, msg: BlockTxnmsg) -> Result<()> {
+        info!(
+            "receive block txn msg: {} {} transactions",
+            msg.block_hash,
+            msg.transactions.len()
+        );
+        let partial = {
+            let mut inner = self.inner.lock().unwrap();
+            match inner.partial_blocks.get(&msg.block_hash) {
+                Some(partial) if partial.peer_id == peer_id => {
+                    inner.partial_blocks.remove(&msg.block_hash)
+                }
+                _ => None,
+            }
+        };
+        let mut partial = match partial {
+            Some(partial) => partial,
+            None => {
+                debug!("unrequested block txn {}", msg.block_hash);
+                return Ok(());
+            }
+        };
+        if let Err(e) = partial.fill(msg.transactions) {
+            self.misbehaving(peer_id, 100, &e.to_string());
+            return Err(e);
+        }
+        match self.get_peer(peer_id) {
+            Some(peer) => self.complete_block(&peer, partial),
+            None => Ok(()),
+        }
+    }
+
+    /// Processes a rebuilt block like a full one, or fetches it whole when a
+    /// short id matched the wrong transaction.
+    fn complete_block(&self, peer: &Peer, partial: PartialBlock) -> Result<()> {
+        let hash = partial.header.hash.clone();
+        let block = match partial.block() {
+            Some(block) => block,
+            None => return Err(format_err!("Block {} is incomplete", hash)),
+        };
+        if !block.verify()? {
+            info!("compact block {} doesn't match, fetch it whole", hash);
+            return self.send_get_data(peer, InvKind::Block, &hash);
+        }
+        let data = Blockmsg {
+            addr_from: peer.addr.clone(),
+            block,
+        };
+        self.handle_block(peer.id, data)
+    }
+
+    /// Announces the blocks after our common ancestor with the peer, at most
+    /// `MAX_BLOCKS_INV`; the peer asks again once it has fetched them.
+    fn handle_get_blocks(&self, peer_id: u64, msg: GetBlocksmsg) -> Result<()> {
+        info!("receive get blocks msg: {:#?}", msg);
+        let peer = match self.get_peer(peer_id) {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+        let block_hashs = self.get_hashs_after(&msg.locator, &msg.stop_hash);
+        if block_hashs.is_empty() {
+            return Ok(());
+        }
+        self.send_inv(&peer, InvKind::Block, block_hashs)?;
+        Ok(())
+    }
+
+    /// Sends the blocks and transactions asked for, then a notfound listing
+    /// those we don't have.
+    fn handle_get_data(&self, peer_id: u64, msg: GetDatamsg) -> Result<()> {
+        info!("receive get data msg: {:#?}", msg);
+        if msg.items.len() > MAX_INV_SIZE {
+            self.misbehaving(peer_id, 20, "oversized getdata message");
+            return Err(format_err!(
+                "Getdata message with {} items",
+                msg.items.len()
+            ));
+        }
+        let peer = match self.get_peer(peer_id) {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+        self.check_inventory_kinds(peer_id, &msg.items);
+
+        let mut not_found = Vec::new();
+        for item in &msg.items {
+            let hash = item.hash.to_hex();
+            match item.kind {
+                InvKind::Block => match self.get_block(&hash) {
+                    Ok(block) => self.send_block(&peer, &block)?,
+                    Err(_) => not_found.push(*item),
+                },
+                // an orphan asks for parents we may not have either
+                InvKind::Tx => match self.get_mempool_tx(&hash) {
+                    Some(tx) => self.send_tx(&peer, &tx)?,
+                    None => not_found.push(*item),
+                },
+                InvKind::Unknown(_) => {}
+            }
+        }
+        if not_found.is_empty() {
+            return Ok(());
+        }
+        info!("send notfound to peer {}: {:?}", peer_id, not_found);
+        peer.send(Message::NotFound(not_found))
+    }
+
+    fn handle_tx(&self, peer_id: u64, msg: Txmsg) -> Result<()> {
+        info!("receive tx msg: {} {}", msg.addr_from, &msg.transaction.id);
+        self.inner
+            .lock()
+            .unwrap()
+            .tx_requests
+            .remove(&msg.transaction.id);
+        if let Err(e) = self.accept_mempool(msg.transaction.clone()) {
+            if let Some(MissingInputs(parents)) = e.downcast_ref::<MissingInputs>() {
+                let parents = parents.clone();
+                return self.add_orphan_tx(peer_id, msg.transaction, parents);
+            }
+            info!("reject tx {}: {}", &msg.transaction.id, e);
+            if let Some(code) = reject_code(&e) {
+                let txid = &msg.transaction.id;
+                self.send_reject(peer_id, InvKind::Tx, txid, code, &e.to_string());
+            }
+            if e.is::<InvalidTransaction>() {
+                self.misbehaving(peer_id, 10, &e.to_string());
+            }
+            return Ok(());
+        }
+
+        // every node passes accepted transactions on, miners also mine them
+        self.relay_inventory(InvKind::Tx, &msg.transaction.id, Some(peer_id));
+        let children = self
+            .inner
+            .lock()
+            .unwrap()
+            .orphan_txs
+            .take_children(&msg.transaction.id);
+        self.process_orphan_txs(children);
+        self.update_template(false)
+    }
+
+    /// A block from our miner gets the checks of a peer block, then is
+    /// announced to every peer.
+    fn handle_mined_block(&self, block: Block) -> Result<()> {
+        if !block.verify()? {
+            return Err(format_err!("Mined block {} is invalid", block.get_hash()));
+        }
+        if block.get_prev_hash() != self.get_tip() {
+            info!("drop stale mined block {}", block.get_hash());
+            return Ok(());
+        }
+        self.accept_block(block)?;
+        self.tip_changed()
+    }
+
+    fn handle_message(&self, peer_id: u64, msg: Message) -> Result<()> {
+        if msg.is_admin() && !self.is_local(peer_id) {
+            self.misbehaving(peer_id, BAN_SCORE, "admin command from a remote peer");
+            return Err(format_err!(
+                "Refuse {} request from peer {}",
+                msg.command(),
+                peer_id
+            ));
+        }
+
+        match &msg {
+            Message::Inv(data) => {
+                self.mark_known(peer_id, &inventory_hashs(&data.items, InvKind::Block));
+                self.mark_known(peer_id, &inventory_hashs(&data.items, InvKind::Tx));
+            }
+            Message::Tx(data) => {
+                self.mark_known(peer_id, std::slice::from_ref(&data.transaction.id))
+            }
+            Message::Block(data) => self.mark_known(peer_id, &[data.block.get_hash()]),
+            Message::CmpctBlock(data) => {
+                self.mark_known(peer_id, std::slice::from_ref(&data.header.hash))
+            }
+            Message::Headers(headers) => {
+                let hashs: Vec<String> = headers.iter().map(|h| h.hash.clone()).collect();
+                self.mark_known(peer_id, &hashs)
+            }
+            _ => {}
+        }
+
+        match msg {
+            Message::Addr(data) => self.handle_addr(peer_id, data)?,
+            Message::GetAddr => self.handle_get_addr(peer_id)?,
+            Message::Block(data) => {
+                if let Some(peer) = self.inner.lock().unwrap().peers.get_mut(&peer_id) {
+                    peer.best_height = peer.best_height.max(data.block.get_height());
+                }
+                self.handle_block(peer_id, data)?
+            }
+            Message::CmpctBlock(data) => {
+                if let Some(peer) = self.inner.lock().unwrap().peers.get_mut(&peer_id) {
+                    peer.best_height = peer.best_height.max(data.header.height);
+                }
+                self.handle_cmpct_block(peer_id, data)?
+            }
+            Message::GetBlockTxn(data) => self.handle_get_block_txn(peer_id, data)?,
+            Message::BlockTxn(data) => self.handle_block_txn(peer_id, data)?,
+            Message::Inv(data) => self.handle_inv(peer_id, data)?,
+            Message::GetHeaders(data) => self.handle_get_headers(peer_id, data)?,
+            Message::Headers(data) => self.handle_headers(peer_id, data)?,
+            Message::GetBlock(data) => self.handle_get_blocks(peer_id, data)?,
+            Message::GetData(data) => self.handle_get_data(peer_id, data)?,
+            Message::NotFound(data) => self.handle_not_found(peer_id, data)?,
+            Message::Reject(data) => self.handle_reject(peer_id, data)?,
+            Message::Tx(data) => self.handle_tx(peer_id, data)?,
+            Message::Version(data) => self.handle_version(peer_id, data)?,
+            Message::Verack => self.handle_verack(peer_id)?,
+            Message::Ping(nonce) => self.handle_ping(peer_id, nonce)?,
+            Message::Pong(nonce) => self.handle_pong(peer_id, nonce)?,
+            Message::SaveMempool => self.save_mempool()?,
+            Message::Stop => self.handle_stop(peer_id)?,
+            Message::GetPeerInfo => self.handle_get_peer_info(peer_id)?,
+            Message::SetBan(data) => self.set_ban(data)?,
+            Message::ListBanned => self.handle_list_banned(peer_id)?,
+            Message::ClearBanned => self.inner.lock().unwrap().banman.clear()?,
+            Message::GetRejects => self.handle_get_rejects(peer_id)?,
+            Message::PeerInfo(_) | Message::Banned(_) | Message::Rejects(_) => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// Hex hashes of the `kind` items of an inventory.
+fn inventory_hashs(items: &[InvItem], kind: InvKind) -> Vec<String> {
+    items
+        .iter()
+        .filter(|item| item.kind == kind)
+        .map(|item| item.hash.to_hex())
+        .collect()
+}
+
+fn user_agent() -> String {
+    format!("/blockchain_rust:{}/", env!("CARGO_PKG_VERSION"))
+}
+
+/// Resolves on SIGTERM, never where there is no such signal.
+async fn terminated() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+                return;
+            }
+            Err(e) => error!("listen for SIGTERM failed: {}", e),
+        }
     }
+    std::future::pending::<()>().await
 }
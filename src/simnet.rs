@@ -0,0 +1,849 @@
+use anyhow::format_err;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::sync::mpsc;
+
+use super::*;
+use crate::{
+    block::Block,
+    noise::Identity,
+    peer::{Direction, Peer, SessionState},
+    protocol::*,
+    server::{Event, NodeConfig, Server},
+    transaction::{TXInput, TXOutput, Transaction, SEQUENCE_FINAL},
+    transport::{Clock, Transport},
+    utxoset::UTXOSet,
+    wallets::Wallet,
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env, fs,
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+// delay of every message unless a link is given another
+const DEFAULT_LATENCY: Duration = Duration::from_millis(50);
+// how often the peer timers of every node run in `run_for`
+const TICK_INTERVAL: Duration = Duration::from_secs(5);
+// deliveries `settle` makes before deciding the network never goes quiet
+const MAX_STEPS: usize = 100_000;
+
+// keeps the data directories of networks in one test process apart
+static NEXT_NETWORK: AtomicU64 = AtomicU64::new(0);
+
+/// Servers of one process linked in memory. Messages travel with a latency
+/// on a virtual clock and are delivered one at a time in arrival order, so a
+/// scenario plays out the same on every run. Links can be cut, lose
+/// messages or slow down, and blocks are mined on demand.
+pub struct SimNetwork {
+    clock: Clock,
+    nodes: Vec<SimNode>,
+    links: BTreeMap<u64, Link>,
+    next_link: u64,
+    // messages on their way, by arrival time then sending order
+    in_flight: BTreeMap<(Instant, u64), Delivery>,
+    next_seq: u64,
+    // dials made by the servers, (node, address)
+    dials: Arc<Mutex<Vec<(usize, String)>>>,
+    genesis: Block,
+    // wallet the genesis coinbase pays
+    funder: Wallet,
+    dir: PathBuf,
+    rng: StdRng,
+    drop_rate: f64,
+    // pairs of nodes that can't reach each other, lowest first
+    cut: HashSet<(usize, usize)>,
+    next_tick: Instant,
+    // commands handed to the servers: (from, to, command)
+    delivered: Vec<(usize, usize, &'static str)>,
+}
+
+pub struct SimNode {
+    pub server: Server,
+    pub addr: String,
+    pub ip: String,
+}
+
+/// A session between two nodes, one end held by each.
+struct Link {
+    ends: [LinkEnd; 2],
+    latency: Duration,
+}
+
+struct LinkEnd {
+    node: usize,
+    peer: Peer,
+    // what the node sends on this session
+    outbox: mpsc::Receiver<Message>,
+    // handshake progress of what the node receives
+    session: SessionState,
+    // arrival of the last message sent from here, none overtakes it
+    last_arrival: Instant,
+}
+
+struct Delivery {
+    link: u64,
+    // end of the link receiving it
+    to: usize,
+    msg: Message,
+}
+
+/// Hands the dials of node `node` to the network.
+struct SimTransport {
+    node: usize,
+    dials: Arc<Mutex<Vec<(usize, String)>>>,
+}
+
+impl Transport for SimTransport {
+    fn dial(&self, _server: &Server, addr: &str) {
+        self.dials
+            .lock()
+            .unwrap()
+            .push((self.node, addr.to_string()));
+    }
+}
+
+impl SimNetwork {
+    /// An empty network whose message losses follow `seed`.
+    pub fn new(seed: u64) -> Result<SimNetwork> {
+        let dir = env::temp_dir().join(format!(
+            "blockchain_rust_sim_{}_{}",
+            process::id(),
+            NEXT_NETWORK.fetch_add(1, Ordering::SeqCst)
+        ));
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        let funder = Wallet::new();
+        let cbtx = Transaction::new_coinbase(funder.get_address(), String::from("genesis"))?;
+        let clock = Clock::new_virtual();
+        Ok(SimNetwork {
+            next_tick: clock.now() + TICK_INTERVAL,
+            clock,
+            nodes: Vec::new(),
+            links: BTreeMap::new(),
+            next_link: 0,
+            in_flight: BTreeMap::new(),
+            next_seq: 0,
+            dials: Arc::new(Mutex::new(Vec::new())),
+            genesis: Block::new_genesis_block(cbtx),
+            funder,
+            dir,
+            rng: StdRng::seed_from_u64(seed),
+            drop_rate: 0.0,
+            cut: HashSet::new(),
+            delivered: Vec::new(),
+        })
+    }
+
+    /// Adds a node on the shared genesis block, mining to an address of its
+    /// own. Returns its index.
+    pub fn add_node(&mut self) -> Result<usize> {
+        let index = self.nodes.len();
+        let dir = self.dir.join(format!("node{}", index));
+        fs::create_dir_all(&dir)?;
+        let dir = dir
+            .to_str()
+            .ok_or_else(|| format_err!("Bad data directory {:?}", dir))?;
+        let utxo = UTXOSet {
+            blockchain: Blockchain::create_with_genesis(dir, self.genesis.clone())?,
+        };
+        utxo.reindex()?;
+
+        let addr = format!("node{}:3000", index);
+        let mut config = NodeConfig::new("3000");
+        config.bind = addr.clone();
+        config.advertise = addr.clone();
+        config.seednode.clear();
+        config.data_dir = dir.to_string();
+        let transport = SimTransport {
+            node: index,
+            dials: Arc::clone(&self.dials),
+        };
+        let server = Server::with_transport(
+            config,
+            &Wallet::new().get_address(),
+            utxo,
+            Identity::generate(),
+            Arc::new(transport),
+            self.clock.clone(),
+        );
+        self.nodes.push(SimNode {
+            server,
+            addr,
+            ip: format!("10.0.0.{}", index + 1),
+        });
+        Ok(index)
+    }
+
+    /// Has node `from` dial node `to`, the handshake runs on `settle`.
+    pub fn connect(&mut self, from: usize, to: usize) -> Result<()> {
+        let addr = self.nodes[to].addr.clone();
+        self.nodes[from].server.connect(&addr, Vec::new())?;
+        self.pump();
+        Ok(())
+    }
+
+    /// Closes every session between `a` and `b`.
+    pub fn disconnect(&mut self, a: usize, b: usize) {
+        for link in self.links.values() {
+            if link.joins(a, b) {
+                link.ends[0].peer.disconnect();
+            }
+        }
+        self.pump();
+    }
+
+    /// Cuts the nodes of `side` off the others: their messages are lost and
+    /// dials across fail, while sessions stay open.
+    pub fn partition(&mut self, side: &[usize]) {
+        for &a in side {
+            for b in 0..self.nodes.len() {
+                if !side.contains(&b) {
+                    self.cut.insert((a.min(b), a.max(b)));
+                }
+            }
+        }
+    }
+
+    pub fn heal(&mut self) {
+        self.cut.clear();
+    }
+
+    /// Share of the messages lost on every link, drawn from the seed.
+    pub fn set_drop_rate(&mut self, rate: f64) {
+        self.drop_rate = rate;
+    }
+
+    /// Delay of the messages between `a` and `b` sent from now on.
+    pub fn set_latency(&mut self, a: usize, b: usize, latency: Duration) {
+        for link in self.links.values_mut() {
+            if link.joins(a, b) {
+                link.latency = latency;
+            }
+        }
+    }
+
+    /// Has node `from` send `msg` on its session with `to`, as a peer
+    /// running other code could.
+    pub fn send(&mut self, from: usize, to: usize, msg: Message) -> Result<()> {
+        let end = self
+            .end_towards(from, to)
+            .ok_or_else(|| format_err!("Node {} has no session with {}", from, to))?;
+        end.peer.send(msg)?;
+        self.pump();
+        Ok(())
+    }
+
+    /// Mines a block on the tip of `node` with what its mempool offers, and
+    /// submits it as its miner would.
+    pub fn mine(&mut self, node: usize) -> Result<Block> {
+        let template = self.nodes[node].server.block_template()?;
+        let mut block = Block::new_unsolved(
+            template.transactions,
+            template.prev_block_hash,
+            template.height,
+        );
+        block.proof_of_work()?;
+        self.nodes[node]
+            .server
+            .handle_event(Event::Mined(block.clone()));
+        self.pump();
+        Ok(block)
+    }
+
+    /// Delivers messages until none is in flight, moving the clock to each
+    /// arrival. Peer timers don't run.
+    pub fn settle(&mut self) -> Result<()> {
+        for _ in 0..MAX_STEPS {
+            match self.in_flight.pop_first() {
+                Some(((at, _), delivery)) => {
+                    self.advance_to(at);
+                    self.deliver(delivery);
+                    self.pump();
+                }
+                None => return Ok(()),
+            }
+        }
+        Err(format_err!(
+            "Network still busy after {} deliveries",
+            MAX_STEPS
+        ))
+    }
+
+    /// Lets `duration` of virtual time pass, delivering messages and running
+    /// the peer timers of every node as they come due.
+    pub fn run_for(&mut self, duration: Duration) {
+        let end = self.clock.now() + duration;
+        loop {
+            let next_delivery = self.in_flight.keys().next().map(|(at, _)| *at);
+            match next_delivery {
+                Some(at) if at <= self.next_tick && at <= end => {
+                    let delivery = self.in_flight.pop_first().unwrap().1;
+                    self.advance_to(at);
+                    self.deliver(delivery);
+                }
+                _ if self.next_tick <= end => {
+                    self.advance_to(self.next_tick);
+                    self.next_tick += TICK_INTERVAL;
+                    for node in &self.nodes {
+                        node.server.check_peers();
+                        node.server.connect_outbound();
+                    }
+                }
+                _ => break,
+            }
+            self.pump();
+        }
+        self.advance_to(end);
+    }
+
+    /// A transaction paying `value` of the genesis coinbase to a new
+    /// address, the rest left as fee.
+    pub fn spend_genesis(&self, value: i32) -> Result<Transaction> {
+        let cbtx = &self.genesis.get_transaction()[0];
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: cbtx.id.clone(),
+                vout: 0,
+                signature: Vec::new(),
+                pub_key: self.funder.public_key.clone(),
+                sequence: SEQUENCE_FINAL,
+            }],
+            vout: vec![TXOutput::new(value, Wallet::new().get_address())?],
+            issuance: None,
+        };
+        tx.set_id()?;
+        let key = <&[u8; 32]>::try_from(self.funder.secret_key.as_slice())?;
+        tx.sign(key, HashMap::from([(cbtx.id.clone(), cbtx.clone())]))?;
+        Ok(tx)
+    }
+
+    // --------- getter ---------
+
+    pub fn node(&self, node: usize) -> &Server {
+        &self.nodes[node].server
+    }
+
+    pub fn tip(&self, node: usize) -> String {
+        self.nodes[node].server.get_tip()
+    }
+
+    pub fn height(&self, node: usize) -> i32 {
+        self.nodes[node].server.get_best_height().unwrap()
+    }
+
+    /// Open sessions between `a` and `b`.
+    pub fn links_between(&self, a: usize, b: usize) -> usize {
+        self.links.values().filter(|link| link.joins(a, b)).count()
+    }
+
+    /// Misbehavior score node `node` gives its peer `peer`.
+    pub fn misbehavior(&self, node: usize, peer: usize) -> Option<i32> {
+        let end = self.end_towards(node, peer)?;
+        let peer = self.nodes[node].server.get_peer(end.peer.id)?;
+        Some(peer.misbehavior)
+    }
+
+    /// `command` messages node `from` got through to node `to`.
+    pub fn delivered(&self, from: usize, to: usize, command: &str) -> usize {
+        self.delivered
+            .iter()
+            .filter(|(f, t, c)| *f == from && *t == to && *c == command)
+            .count()
+    }
+
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /* -------------------- inner function -------------------- */
+
+    fn advance_to(&mut self, at: Instant) {
+        let now = self.clock.now();
+        if at > now {
+            self.clock.advance(at - now);
+        }
+    }
+
+    /// The end held by `node` of its first session with `peer`.
+    fn end_towards(&self, node: usize, peer: usize) -> Option<&LinkEnd> {
+        let link = self.links.values().find(|link| link.joins(node, peer))?;
+        link.ends.iter().find(|end| end.node == node)
+    }
+
+    fn is_cut(&self, a: usize, b: usize) -> bool {
+        self.cut.contains(&(a.min(b), a.max(b)))
+    }
+
+    /// Carries out what the servers did since the last call: opens the
+    /// sessions they dialled, tears down those they closed and puts what they
+    /// sent on its way.
+    fn pump(&mut self) {
+        loop {
+            let dials: Vec<(usize, String)> = self.dials.lock().unwrap().drain(..).collect();
+            if dials.is_empty() {
+                break;
+            }
+            for (from, addr) in dials {
+                self.open_link(from, &addr);
+            }
+        }
+        self.close_links();
+        self.send_outboxes();
+    }
+
+    fn open_link(&mut self, from: usize, addr: &str) {
+        let to = match self.nodes.iter().position(|node| node.addr == addr) {
+            Some(to) if !self.is_cut(from, to) => to,
+            _ => {
+                let e = format_err!("{} is unreachable", addr);
+                self.nodes[from].server.dial_failed(addr, &e);
+                return;
+            }
+        };
+        if !self.nodes[to].server.accepts_inbound(&self.nodes[from].ip) {
+            let e = format_err!("{} refused the connection", addr);
+            self.nodes[from].server.dial_failed(addr, &e);
+            return;
+        }
+
+        let now = self.clock.now();
+        let (outbound, outbound_box) = self.nodes[from].server.register_peer(
+            addr,
+            Direction::Outbound,
+            &self.nodes[to].ip,
+            None,
+        );
+        let (inbound, inbound_box) =
+            self.nodes[to]
+                .server
+                .register_peer("", Direction::Inbound, &self.nodes[from].ip, None);
+        let outbound_id = outbound.id;
+        let end = |node, peer, outbox| LinkEnd {
+            node,
+            peer,
+            outbox,
+            session: SessionState::default(),
+            last_arrival: now,
+        };
+        self.links.insert(
+            self.next_link,
+            Link {
+                ends: [
+                    end(from, outbound, outbound_box),
+                    end(to, inbound, inbound_box),
+                ],
+                latency: DEFAULT_LATENCY,
+            },
+        );
+        self.next_link += 1;
+        self.nodes[from]
+            .server
+            .handle_event(Event::Connected(outbound_id));
+    }
+
+    /// Ends the sessions either side disconnected, on both sides.
+    fn close_links(&mut self) {
+        let closed: Vec<u64> = self
+            .links
+            .iter()
+            .filter(|(_, link)| link.ends.iter().any(|end| end.peer.is_disconnected()))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in closed {
+            let link = self.links.remove(&id).unwrap();
+            for end in &link.ends {
+                end.peer.disconnect();
+                self.nodes[end.node]
+                    .server
+                    .handle_event(Event::Disconnected(end.peer.id));
+            }
+        }
+        self.in_flight
+            .retain(|_, delivery| self.links.contains_key(&delivery.link));
+    }
+
+    fn send_outboxes(&mut self) {
+        let now = self.clock.now();
+        for (id, link) in self.links.iter_mut() {
+            let cut = self.cut.contains(&(
+                link.ends[0].node.min(link.ends[1].node),
+                link.ends[0].node.max(link.ends[1].node),
+            ));
+            for from in 0..2 {
+                let end = &mut link.ends[from];
+                while let Ok(msg) = end.outbox.try_recv() {
+                    if cut || self.rng.gen_bool(self.drop_rate) {
+                        debug!("sim drop {} from node {}", msg.command(), end.node);
+                        continue;
+                    }
+                    let at = (now + link.latency).max(end.last_arrival);
+                    end.last_arrival = at;
+                    self.in_flight.insert(
+                        (at, self.next_seq),
+                        Delivery {
+                            link: *id,
+                            to: 1 - from,
+                            msg,
+                        },
+                    );
+                    self.next_seq += 1;
+                }
+            }
+        }
+    }
+
+    /// Hands a message to the receiving server as its session reader would.
+    fn deliver(&mut self, delivery: Delivery) {
+        let link = match self.links.get_mut(&delivery.link) {
+            Some(link) => link,
+            None => return,
+        };
+        let from = link.ends[1 - delivery.to].node;
+        let end = &mut link.ends[delivery.to];
+        if let Err(e) = end.session.receive(&delivery.msg) {
+            info!("peer {} disconnect: {}", end.peer.id, e);
+            end.peer.disconnect();
+            return;
+        }
+        let server = &self.nodes[end.node].server;
+        let len = encode_message(&delivery.msg).map_or(0, |data| data.len());
+        if !server.rate_limit(&end.peer, &delivery.msg, len) {
+            return;
+        }
+        self.delivered
+            .push((from, end.node, delivery.msg.command()));
+        server.touch_peer(end.peer.id);
+        server.handle_event(Event::Message(end.peer.id, delivery.msg));
+    }
+}
+
+impl Link {
+    fn joins(&self, a: usize, b: usize) -> bool {
+        let (x, y) = (self.ends[0].node, self.ends[1].node);
+        (x == a && y == b) || (x == b && y == a)
+    }
+}
+
+impl Drop for SimNetwork {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::SUBSIDY;
+
+    fn network(nodes: usize, seed: u64) -> SimNetwork {
+        let mut sim = SimNetwork::new(seed).unwrap();
+        for _ in 0..nodes {
+            sim.add_node().unwrap();
+        }
+        sim
+    }
+
+    fn assert_converged(sim: &SimNetwork, nodes: usize, height: i32) {
+        for node in 0..nodes {
+            assert_eq!(sim.height(node), height, "height of node {}", node);
+            assert_eq!(sim.tip(node), sim.tip(0), "tip of node {}", node);
+        }
+    }
+
+    #[test]
+    fn block_crosses_a_line_of_nodes() {
+        let mut sim = network(3, 1);
+        sim.connect(0, 1).unwrap();
+        sim.connect(1, 2).unwrap();
+        sim.settle().unwrap();
+        sim.set_latency(1, 2, Duration::from_millis(400));
+
+        let start = sim.now();
+        let block = sim.mine(0).unwrap();
+        sim.settle().unwrap();
+
+        assert_converged(&sim, 3, 1);
+        assert_eq!(sim.tip(2), block.get_hash());
+        // a compact block each hop, the last one slow
+        assert!(sim.now() - start >= DEFAULT_LATENCY + Duration::from_millis(400));
+    }
+
+    #[test]
+    fn partitioned_sides_reorg_to_the_longer_chain() {
+        let mut sim = network(4, 2);
+        sim.connect(0, 1).unwrap();
+        sim.connect(1, 2).unwrap();
+        sim.connect(2, 3).unwrap();
+        sim.settle().unwrap();
+
+        sim.partition(&[0, 1]);
+        sim.mine(0).unwrap();
+        sim.settle().unwrap();
+        sim.mine(3).unwrap();
+        sim.settle().unwrap();
+        sim.mine(3).unwrap();
+        sim.settle().unwrap();
+        assert_eq!(sim.height(1), 1);
+        assert_eq!(sim.height(2), 2);
+        assert_ne!(sim.tip(1), sim.tip(2));
+
+        // the next block leads the short side to fetch the other branch;
+        // what it asks of a peer on its own branch waits for the timeout
+        sim.heal();
+        sim.mine(3).unwrap();
+        sim.run_for(Duration::from_secs(90));
+        assert_converged(&sim, 4, 3);
+    }
+
+    #[test]
+    fn reorged_out_transaction_returns_to_the_mempool() {
+        let mut sim = network(3, 12);
+        sim.connect(0, 1).unwrap();
+        sim.connect(1, 2).unwrap();
+        sim.settle().unwrap();
+
+        sim.partition(&[0, 1]);
+        let msg = Message::Tx(Txmsg {
+            addr_from: String::new(),
+            transaction: sim.spend_genesis(SUBSIDY - 1).unwrap(),
+        });
+        sim.send(1, 0, msg).unwrap();
+        sim.settle().unwrap();
+        let confirmed = sim.mine(0).unwrap();
+        sim.settle().unwrap();
+        assert_eq!(confirmed.get_transaction().len(), 2);
+        assert_eq!(sim.node(1).block_template().unwrap().transactions.len(), 1);
+
+        sim.mine(2).unwrap();
+        sim.settle().unwrap();
+        sim.mine(2).unwrap();
+        sim.settle().unwrap();
+        sim.heal();
+        sim.mine(2).unwrap();
+        sim.run_for(Duration::from_secs(90));
+        assert_converged(&sim, 3, 3);
+
+        // back in the mempool of the nodes that had it confirmed
+        for node in 0..2 {
+            let template = sim.node(node).block_template().unwrap();
+            assert_eq!(template.transactions.len(), 2, "template of node {}", node);
+            assert_eq!(
+                template.transactions[1].id,
+                confirmed.get_transaction()[1].id
+            );
+        }
+    }
+
+    #[test]
+    fn competing_miners_settle_on_one_chain() {
+        let mut sim = network(3, 3);
+        sim.connect(0, 1).unwrap();
+        sim.connect(1, 2).unwrap();
+        sim.settle().unwrap();
+
+        // both solve a block at height 1 before hearing of the other
+        let first = sim.mine(0).unwrap();
+        let second = sim.mine(2).unwrap();
+        sim.settle().unwrap();
+        assert_eq!(sim.tip(0), first.get_hash());
+        assert_eq!(sim.tip(2), second.get_hash());
+        // the middle node keeps the block that reached it first
+        assert_eq!(sim.tip(1), first.get_hash());
+
+        sim.mine(2).unwrap();
+        sim.settle().unwrap();
+        assert_converged(&sim, 3, 2);
+    }
+
+    #[test]
+    fn lost_block_is_fetched_with_the_next_one() {
+        let mut sim = network(2, 4);
+        sim.connect(0, 1).unwrap();
+        sim.settle().unwrap();
+
+        sim.set_drop_rate(1.0);
+        sim.mine(0).unwrap();
+        sim.settle().unwrap();
+        assert_eq!(sim.height(1), 0);
+
+        sim.set_drop_rate(0.0);
+        sim.mine(0).unwrap();
+        sim.settle().unwrap();
+        assert_converged(&sim, 2, 2);
+    }
+
+    #[test]
+    fn unreachable_peer_times_out_on_the_virtual_clock() {
+        let mut sim = network(2, 5);
+        sim.connect(0, 1).unwrap();
+        sim.settle().unwrap();
+        assert_eq!(sim.links_between(0, 1), 1);
+
+        sim.partition(&[0]);
+        // a ping goes unanswered after a minute of silence
+        sim.run_for(Duration::from_secs(60));
+        assert_eq!(sim.links_between(0, 1), 1);
+        sim.run_for(Duration::from_secs(60));
+        assert_eq!(sim.links_between(0, 1), 0);
+    }
+
+    #[test]
+    fn flood_over_budget_is_dropped_and_scored() {
+        let mut sim = network(2, 6);
+        sim.connect(0, 1).unwrap();
+        sim.settle().unwrap();
+
+        // the getheaders burst is 20, the rest of the flood is dropped unread
+        for _ in 0..30 {
+            let msg = Message::GetHeaders(GetHeadersmsg {
+                locator: vec![sim.tip(0)],
+                stop_hash: String::new(),
+            });
+            sim.send(0, 1, msg).unwrap();
+        }
+        sim.settle().unwrap();
+        assert_eq!(sim.misbehavior(1, 0), Some(10));
+        assert_eq!(sim.links_between(0, 1), 1);
+    }
+
+    #[test]
+    fn missing_data_gets_notfound_and_unknown_kinds_score() {
+        let mut sim = network(2, 7);
+        sim.connect(0, 1).unwrap();
+        sim.settle().unwrap();
+
+        let missing = InvItem {
+            kind: InvKind::Tx,
+            hash: Hash256([7; 32]),
+        };
+        let unknown = InvItem {
+            kind: InvKind::Unknown(9),
+            hash: Hash256([9; 32]),
+        };
+        let msg = Message::GetData(GetDatamsg {
+            addr_from: String::new(),
+            items: vec![missing, unknown],
+        });
+        sim.send(0, 1, msg).unwrap();
+        sim.settle().unwrap();
+
+        assert_eq!(sim.delivered(1, 0, "notfound"), 1);
+        assert_eq!(sim.misbehavior(1, 0), Some(10));
+        assert_eq!(sim.links_between(0, 1), 1);
+    }
+
+    #[test]
+    fn block_claiming_the_wrong_height_gets_the_sender_banned() {
+        let mut sim = network(2, 10);
+        sim.connect(0, 1).unwrap();
+        sim.settle().unwrap();
+
+        let cbtx =
+            Transaction::new_coinbase(Wallet::new().get_address(), String::from("tall")).unwrap();
+        let block = Block::new_block(vec![cbtx], sim.tip(1), 5).unwrap();
+        let msg = Message::Block(Blockmsg {
+            addr_from: String::new(),
+            block,
+        });
+        sim.send(0, 1, msg).unwrap();
+        sim.settle().unwrap();
+
+        assert_eq!(sim.height(1), 0);
+        assert_eq!(sim.links_between(0, 1), 0);
+        assert!(!sim.node(1).accepts_inbound(&sim.nodes[0].ip));
+    }
+
+    #[test]
+    fn remote_admin_command_gets_the_sender_banned() {
+        let mut sim = network(2, 9);
+        sim.connect(0, 1).unwrap();
+        sim.settle().unwrap();
+
+        sim.send(0, 1, Message::ClearBanned).unwrap();
+        sim.settle().unwrap();
+
+        assert_eq!(sim.links_between(0, 1), 0);
+        assert!(!sim.node(1).accepts_inbound(&sim.nodes[0].ip));
+    }
+
+    #[test]
+    fn getblocks_is_answered_to_the_peer_asking() {
+        let mut sim = network(3, 8);
+        let genesis = sim.tip(1);
+        sim.mine(1).unwrap();
+        sim.connect(0, 1).unwrap();
+        sim.settle().unwrap();
+        let before = sim.delivered(1, 0, "inv");
+
+        let msg = Message::GetBlock(GetBlocksmsg {
+            addr_from: sim.nodes[2].addr.clone(),
+            locator: vec![genesis],
+            stop_hash: String::new(),
+        });
+        sim.send(0, 1, msg).unwrap();
+        sim.settle().unwrap();
+
+        assert_eq!(sim.delivered(1, 0, "inv"), before + 1);
+        assert_eq!(sim.links_between(1, 2), 0);
+    }
+
+    #[test]
+    fn getdata_is_answered_to_the_peer_asking() {
+        let mut sim = network(3, 7);
+        sim.connect(0, 1).unwrap();
+        sim.settle().unwrap();
+
+        let msg = Message::GetData(GetDatamsg {
+            addr_from: sim.nodes[2].addr.clone(),
+            items: vec![InvItem::new(InvKind::Block, &sim.tip(1)).unwrap()],
+        });
+        sim.send(0, 1, msg).unwrap();
+        sim.settle().unwrap();
+
+        assert_eq!(sim.delivered(1, 0, "block"), 1);
+        assert_eq!(sim.links_between(1, 2), 0);
+    }
+
+    #[test]
+    fn invalid_tx_gets_a_reject() {
+        let mut sim = network(2, 7);
+        sim.connect(0, 1).unwrap();
+        sim.settle().unwrap();
+
+        let cbtx =
+            Transaction::new_coinbase(Wallet::new().get_address(), String::from("relay")).unwrap();
+        let msg = Message::Tx(Txmsg {
+            addr_from: String::new(),
+            transaction: cbtx,
+        });
+        sim.send(0, 1, msg).unwrap();
+        sim.settle().unwrap();
+
+        assert_eq!(sim.delivered(1, 0, "reject"), 1);
+        assert_eq!(sim.misbehavior(1, 0), Some(10));
+    }
+
+    #[test]
+    fn same_seed_loses_the_same_messages() {
+        let run = |seed| {
+            let mut sim = network(3, seed);
+            sim.connect(0, 1).unwrap();
+            sim.connect(1, 2).unwrap();
+            sim.connect(0, 2).unwrap();
+            sim.settle().unwrap();
+            sim.set_drop_rate(0.5);
+            sim.mine(0).unwrap();
+            sim.settle().unwrap();
+            (0..3).map(|node| sim.height(node)).collect::<Vec<i32>>()
+        };
+        assert_eq!(run(7), run(7));
+    }
+}
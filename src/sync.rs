@@ -0,0 +1,517 @@
+use anyhow::format_err;
+
+use super::*;
+use crate::{
+    block::{Block, BlockHeader},
+    protocol::MAX_HEADERS_RESULTS,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    time::{Duration, Instant},
+};
+
+// blocks requested from one peer at a time
+const MAX_BLOCKS_IN_FLIGHT: usize = 16;
+// how far along the header chain bodies are fetched ahead of the next one
+// to connect
+const BLOCK_DOWNLOAD_WINDOW: usize = 1024;
+// a peer that doesn't deliver a requested block in time is dropped and the
+// block asked from someone else
+const BLOCK_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+// headers off the best chain kept from one peer, one full headers reply;
+// what it sends beyond is ignored
+const MAX_FORK_HEADERS_PER_PEER: usize = MAX_HEADERS_RESULTS;
+// a fork whose highest header is this far below the best header is dropped
+const MAX_FORK_DEPTH: i32 = 100;
+
+/// A header or block that breaks the header chain rules, as opposed to one
+/// we can't place yet.
+#[derive(Debug)]
+pub struct InvalidHeader(pub String);
+
+impl fmt::Display for InvalidHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidHeader {}
+
+/// Headers-first download state: the validated headers past our tip, the
+/// block bodies asked from peers and those waiting to be connected in order.
+pub struct BlockSync {
+    headers: HashMap<String, BlockHeader>,
+    // header hash -> peers that sent it, the first one charged when it's a
+    // fork
+    senders: HashMap<String, Vec<u64>>,
+    // hashes of the best header chain past our blocks, lowest first
+    chain: Vec<String>,
+    on_chain: HashSet<String>,
+    // hashes of the headers off the best chain
+    forks: HashSet<String>,
+    // block hash -> peer it was asked from and when
+    in_flight: HashMap<String, (u64, Instant)>,
+    // block hash -> peer that sent it and the block
+    downloaded: HashMap<String, (u64, Block)>,
+    // block hash -> peers that answered notfound for it
+    lacking: HashMap<String, HashSet<u64>>,
+}
+
+impl BlockSync {
+    pub fn new() -> BlockSync {
+        BlockSync {
+            headers: HashMap::new(),
+            senders: HashMap::new(),
+            chain: Vec::new(),
+            on_chain: HashSet::new(),
+            forks: HashSet::new(),
+            in_flight: HashMap::new(),
+            downloaded: HashMap::new(),
+            lacking: HashMap::new(),
+        }
+    }
+
+    /// Validates and stores the `headers` sent by `peer_id`, each of which
+    /// has to build on a header we hold or a block of ours; `block_height`
+    /// looks up the latter. Only a header above `tip_height`, the height of
+    /// our tip, and the best one so far starts a new chain to download.
+    /// Returns how many new ones were kept.
+    pub fn add_headers<F>(
+        &mut self,
+        peer_id: u64,
+        headers: &[BlockHeader],
+        tip_height: i32,
+        block_height: F,
+    ) -> Result<usize>
+    where
+        F: Fn(&str) -> Option<i32>,
+    {
+        let mut forks = self
+            .forks
+            .iter()
+            .filter(|hash| self.senders[*hash].first() == Some(&peer_id))
+            .count();
+        let mut added = Vec::new();
+        for header in headers {
+            if let Some(peers) = self.senders.get_mut(&header.hash) {
+                if !peers.contains(&peer_id) {
+                    peers.push(peer_id);
+                }
+                continue;
+            }
+            if block_height(&header.hash).is_some() {
+                continue;
+            }
+            if !header.verify()? {
+                return Err(InvalidHeader(format!(
+                    "Header {} has an invalid proof of work",
+                    header.hash
+                ))
+                .into());
+            }
+            let parent_height = match self.headers.get(&header.prev_block_hash) {
+                Some(parent) => parent.height,
+                None => match block_height(&header.prev_block_hash) {
+                    Some(height) => height,
+                    None => {
+                        return Err(format_err!(
+                            "Header {} doesn't connect to our chain",
+                            header.hash
+                        ))
+                    }
+                },
+            };
+            if header.height != parent_height + 1 {
+                return Err(InvalidHeader(format!(
+                    "Header {} has height {} after {}",
+                    header.hash, header.height, parent_height
+                ))
+                .into());
+            }
+
+            let best = header.height > self.best_height().max(tip_height);
+            if !best && forks >= MAX_FORK_HEADERS_PER_PEER {
+                debug!(
+                    "peer {} has too many fork headers, skip {}",
+                    peer_id, header.hash
+                );
+                continue;
+            }
+            self.headers.insert(header.hash.clone(), header.clone());
+            self.senders.insert(header.hash.clone(), vec![peer_id]);
+            if best {
+                self.switch_chain(&header.hash);
+            } else {
+                self.forks.insert(header.hash.clone());
+                forks += 1;
+            }
+            added.push(header.hash.clone());
+        }
+        self.drop_stale_forks(tip_height);
+        Ok(added
+            .iter()
+            .filter(|hash| self.headers.contains_key(*hash))
+            .count())
+    }
+
+    /// Picks the next blocks of the header chain for `peer_id` to send, no
+    /// higher than `peer_height` and within the download window.
+    pub fn request(&mut self, peer_id: u64, peer_height: i32, now: Instant) -> Vec<String> {
+        let busy = self
+            .in_flight
+            .values()
+            .filter(|(peer, _)| *peer == peer_id)
+            .count();
+        let mut picked = Vec::new();
+        for hash in self.chain.iter().take(BLOCK_DOWNLOAD_WINDOW) {
+            if busy + picked.len() >= MAX_BLOCKS_IN_FLIGHT
+                || self.headers[hash].height > peer_height
+            {
+                break;
+            }
+            let lacking = matches!(self.lacking.get(hash), Some(peers) if peers.contains(&peer_id));
+            if !self.in_flight.contains_key(hash) && !self.downloaded.contains_key(hash) && !lacking
+            {
+                picked.push(hash.clone());
+            }
+        }
+        for hash in &picked {
+            self.in_flight.insert(hash.clone(), (peer_id, now));
+        }
+        picked
+    }
+
+    /// Takes a block downloaded from `peer_id`. Returns false when it isn't
+    /// one we are syncing, fails when it doesn't match its header.
+    pub fn block_received(&mut self, peer_id: u64, block: Block) -> Result<bool> {
+        let hash = block.get_hash();
+        let header = match self.headers.get(&hash) {
+            Some(header) => header,
+            None => return Ok(false),
+        };
+        if block.get_height() != header.height || block.get_prev_hash() != header.prev_block_hash {
+            return Err(InvalidHeader(format!("Block {} doesn't match its header", hash)).into());
+        }
+        self.in_flight.remove(&hash);
+        self.downloaded.insert(hash, (peer_id, block));
+        Ok(true)
+    }
+
+    /// The next block to connect, once its body is here, and the peer that
+    /// sent it.
+    pub fn next_block(&mut self) -> Option<(u64, Block)> {
+        let hash = self.chain.first()?;
+        let block = self.downloaded.remove(hash)?;
+        self.headers.remove(hash);
+        self.senders.remove(hash);
+        self.lacking.remove(hash);
+        self.on_chain.remove(hash);
+        self.chain.remove(0);
+        if self.chain.is_empty() {
+            // what's left are forks below the chain we just finished
+            self.clear_headers();
+        }
+        Some(block)
+    }
+
+    /// Drops the headers building on `hash`, a block that failed to connect.
+    pub fn drop_descendants(&mut self, hash: &str) {
+        let mut headers: Vec<&BlockHeader> = self.headers.values().collect();
+        headers.sort_unstable_by_key(|header| header.height);
+        let mut dropped = HashSet::from([hash.to_string()]);
+        for header in headers {
+            if dropped.contains(&header.prev_block_hash) {
+                dropped.insert(header.hash.clone());
+            }
+        }
+        self.remove_headers(&dropped);
+    }
+
+    /// `peer_id` doesn't have the block `hash`: frees its request so another
+    /// peer is asked, and doesn't ask `peer_id` again.
+    pub fn not_found(&mut self, peer_id: u64, hash: &str) {
+        if !self.headers.contains_key(hash) {
+            return;
+        }
+        if matches!(self.in_flight.get(hash), Some((peer, _)) if *peer == peer_id) {
+            self.in_flight.remove(hash);
+        }
+        self.lacking
+            .entry(hash.to_string())
+            .or_default()
+            .insert(peer_id);
+    }
+
+    /// Releases requests that took too long and returns the peers that
+    /// stalled them.
+    pub fn timed_out(&mut self, now: Instant) -> Vec<u64> {
+        let mut peers = Vec::new();
+        self.in_flight.retain(|_, (peer, sent)| {
+            if now.duration_since(*sent) < BLOCK_DOWNLOAD_TIMEOUT {
+                return true;
+            }
+            if !peers.contains(peer) {
+                peers.push(*peer);
+            }
+            false
+        });
+        peers
+    }
+
+    /// Releases the requests of a disconnected peer and drops the headers
+    /// only it sent, unless another peer sent one building on them.
+    pub fn peer_gone(&mut self, peer_id: u64) {
+        self.in_flight.retain(|_, (peer, _)| *peer != peer_id);
+        for peers in self.senders.values_mut() {
+            peers.retain(|peer| *peer != peer_id);
+        }
+        let mut backed = HashSet::new();
+        for (hash, peers) in &self.senders {
+            if peers.is_empty() {
+                continue;
+            }
+            let mut hash = hash.clone();
+            while self.headers.contains_key(&hash) && backed.insert(hash.clone()) {
+                hash = self.headers[&hash].prev_block_hash.clone();
+            }
+        }
+        let dropped: HashSet<String> = self
+            .headers
+            .keys()
+            .filter(|hash| !backed.contains(*hash))
+            .cloned()
+            .collect();
+        self.remove_headers(&dropped);
+    }
+
+    // --------- getter ---------
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.headers.contains_key(hash)
+    }
+
+    /// Hash of the highest header we hold.
+    pub fn best_header(&self) -> Option<String> {
+        self.chain.last().cloned()
+    }
+
+    pub fn is_syncing(&self) -> bool {
+        !self.chain.is_empty()
+    }
+
+    /* -------------------- inner function -------------------- */
+
+    fn best_height(&self) -> i32 {
+        match self.chain.last() {
+            Some(hash) => self.headers[hash].height,
+            None => -1,
+        }
+    }
+
+    /// Makes the chain end at `hash`, a header higher than the current best:
+    /// follows it back to the chain or our blocks, moves what it leaves
+    /// behind to the forks and drops the bodies no longer on it.
+    fn switch_chain(&mut self, hash: &str) {
+        let mut branch = Vec::new();
+        let mut hash = hash.to_string();
+        while !self.on_chain.contains(&hash) {
+            match self.headers.get(&hash) {
+                Some(header) => {
+                    branch.push(hash);
+                    hash = header.prev_block_hash.clone();
+                }
+                None => break,
+            }
+        }
+
+        let keep = match self.chain.iter().rposition(|h| *h == hash) {
+            Some(pos) => pos + 1,
+            None => 0,
+        };
+        if keep < self.chain.len() {
+            for hash in self.chain.split_off(keep) {
+                self.on_chain.remove(&hash);
+                self.forks.insert(hash);
+            }
+            let on_chain = &self.on_chain;
+            self.downloaded.retain(|hash, _| on_chain.contains(hash));
+        }
+        for hash in branch.into_iter().rev() {
+            self.forks.remove(&hash);
+            self.on_chain.insert(hash.clone());
+            self.chain.push(hash);
+        }
+    }
+
+    /// Drops the forks whose highest header is `MAX_FORK_DEPTH` below the
+    /// best one or our tip at `tip_height`.
+    fn drop_stale_forks(&mut self, tip_height: i32) {
+        let best = self.best_height().max(tip_height);
+        let mut forks: Vec<(i32, String)> = self
+            .forks
+            .iter()
+            .map(|hash| (self.headers[hash].height, hash.clone()))
+            .collect();
+        // highest first, so a header is kept when any of its children is
+        forks.sort_unstable_by(|a, b| b.cmp(a));
+        let mut needed: HashSet<String> = HashSet::new();
+        for (height, hash) in forks {
+            if height + MAX_FORK_DEPTH > best || needed.contains(&hash) {
+                needed.insert(self.headers[&hash].prev_block_hash.clone());
+                continue;
+            }
+            self.forks.remove(&hash);
+            self.headers.remove(&hash);
+            self.senders.remove(&hash);
+            self.lacking.remove(&hash);
+        }
+    }
+
+    /// Forgets the `dropped` headers with their requests and bodies. What
+    /// they leave of the chain is the part below them, as a header only
+    /// goes together with its ancestors.
+    fn remove_headers(&mut self, dropped: &HashSet<String>) {
+        if dropped.is_empty() {
+            return;
+        }
+        self.headers.retain(|hash, _| !dropped.contains(hash));
+        self.senders.retain(|hash, _| !dropped.contains(hash));
+        self.lacking.retain(|hash, _| !dropped.contains(hash));
+        self.chain.retain(|hash| !dropped.contains(hash));
+        self.on_chain.retain(|hash| !dropped.contains(hash));
+        self.forks.retain(|hash| !dropped.contains(hash));
+        self.in_flight.retain(|hash, _| !dropped.contains(hash));
+        self.downloaded.retain(|hash, _| !dropped.contains(hash));
+        if self.chain.is_empty() {
+            self.clear_headers();
+        }
+    }
+
+    /// Forgets the forks once no chain is left to download.
+    fn clear_headers(&mut self) {
+        self.headers.clear();
+        self.senders.clear();
+        self.lacking.clear();
+        self.forks.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{transaction::Transaction, wallets::Wallet};
+
+    fn block(prev: &str, height: i32) -> Block {
+        let cbtx =
+            Transaction::new_coinbase(Wallet::new().get_address(), String::from("sync")).unwrap();
+        Block::new_block(vec![cbtx], prev.to_string(), height).unwrap()
+    }
+
+    fn header(prev: &str, height: i32) -> BlockHeader {
+        block(prev, height).header().unwrap()
+    }
+
+    // height of our tip in the tests
+    const TIP: i32 = 10 + MAX_FORK_DEPTH;
+
+    /// Our blocks the headers build on.
+    fn block_height(hash: &str) -> Option<i32> {
+        match hash {
+            "old" => Some(10),
+            "recent" => Some(20),
+            "tip" => Some(TIP),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn header_has_to_be_above_our_tip() {
+        let old = header("old", 11);
+        let mut sync = BlockSync::new();
+        sync.add_headers(1, std::slice::from_ref(&old), TIP, block_height)
+            .unwrap();
+        assert!(sync.contains(&old.hash));
+        assert!(!sync.is_syncing());
+
+        let next = header("tip", TIP + 1);
+        sync.add_headers(1, std::slice::from_ref(&next), TIP, block_height)
+            .unwrap();
+        assert_eq!(sync.best_header(), Some(next.hash));
+    }
+
+    #[test]
+    fn forks_falling_behind_are_dropped() {
+        let old = header("old", 11);
+        let recent = header("recent", 21);
+        let best = header("tip", TIP + 1);
+
+        let mut sync = BlockSync::new();
+        sync.add_headers(1, std::slice::from_ref(&old), TIP, block_height)
+            .unwrap();
+        let added = sync
+            .add_headers(2, &[best.clone(), recent.clone()], TIP, block_height)
+            .unwrap();
+
+        assert_eq!(added, 2);
+        assert_eq!(sync.best_header(), Some(best.hash));
+        assert!(sync.contains(&recent.hash));
+        assert!(!sync.contains(&old.hash));
+    }
+
+    #[test]
+    fn gone_peer_headers_are_dropped() {
+        let first = header("tip", TIP + 1);
+        let second = header(&first.hash, TIP + 2);
+        let mut sync = BlockSync::new();
+        sync.add_headers(1, &[first.clone(), second.clone()], TIP, block_height)
+            .unwrap();
+        sync.add_headers(2, std::slice::from_ref(&first), TIP, block_height)
+            .unwrap();
+
+        sync.peer_gone(1);
+        assert_eq!(sync.best_header(), Some(first.hash.clone()));
+        assert!(!sync.contains(&second.hash));
+        sync.peer_gone(2);
+        assert!(!sync.is_syncing());
+        assert!(!sync.contains(&first.hash));
+    }
+
+    #[test]
+    fn failed_block_takes_its_descendants_along() {
+        let first = block("tip", TIP + 1);
+        let second = header(&first.get_hash(), TIP + 2);
+        let mut sync = BlockSync::new();
+        sync.add_headers(
+            1,
+            &[first.header().unwrap(), second.clone()],
+            TIP,
+            block_height,
+        )
+        .unwrap();
+        assert!(sync.block_received(2, first.clone()).unwrap());
+
+        let (peer_id, next) = sync.next_block().unwrap();
+        assert_eq!((peer_id, next.get_hash()), (2, first.get_hash()));
+        sync.drop_descendants(&first.get_hash());
+        assert!(!sync.is_syncing());
+        assert!(!sync.contains(&second.hash));
+    }
+
+    #[test]
+    fn not_found_block_is_asked_elsewhere() {
+        let first = header("tip", TIP + 1);
+        let mut sync = BlockSync::new();
+        sync.add_headers(1, std::slice::from_ref(&first), TIP, block_height)
+            .unwrap();
+        let now = Instant::now();
+        assert_eq!(
+            sync.request(1, TIP + 1, now),
+            std::slice::from_ref(&first.hash)
+        );
+
+        sync.not_found(1, &first.hash);
+        assert!(sync.request(1, TIP + 1, now).is_empty());
+        assert_eq!(sync.request(2, TIP + 1, now), [first.hash]);
+    }
+}
@@ -10,11 +10,14 @@ use sha256;
 use super::*;
 use crate::{
     utxoset::UTXOSet,
-    wallets::{hash_pub_key, Wallets},
+    wallets::{hash_pub_key, Wallet, Wallets},
 };
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-const SUBSIDY: i32 = 10;
+pub const SUBSIDY: i32 = 10;
+pub const SEQUENCE_FINAL: u32 = 0xffffffff;
+// inputs with a sequence below SEQUENCE_FINAL - 1 opt in to replace-by-fee
+pub const SEQUENCE_RBF: u32 = 0xfffffffd;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct TXInput {
@@ -22,17 +25,31 @@ pub struct TXInput {
     pub vout: i32,
     pub signature: Vec<u8>,
     pub pub_key: Vec<u8>,
+    pub sequence: u32,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct TXOutput {
     pub value: i32,
     pub pub_key_hash: Vec<u8>,
+    // empty for the native coin
+    pub asset_id: String,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct TXOutputs {
     pub outputs: Vec<TXOutput>,
+    // position of each output in its transaction's vout
+    pub indexes: Vec<i32>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AssetIssuance {
+    pub asset_id: String,
+    pub name: String,
+    pub amount: i32,
+    pub reissuable: bool,
+    pub issuer_pub_key: Vec<u8>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -40,6 +57,7 @@ pub struct Transaction {
     pub id: String,
     pub vin: Vec<TXInput>,
     pub vout: Vec<TXOutput>,
+    pub issuance: Option<AssetIssuance>,
 }
 
 impl TXInput {
@@ -62,16 +80,49 @@ impl TXOutput {
         Ok(())
     }
 
+    pub fn is_native(&self) -> bool {
+        self.asset_id.is_empty()
+    }
+
     pub fn new(value: i32, address: String) -> Result<Self> {
+        TXOutput::new_asset(value, String::new(), address)
+    }
+
+    pub fn new_asset(value: i32, asset_id: String, address: String) -> Result<Self> {
         let mut tx_output = TXOutput {
             value,
             pub_key_hash: Vec::new(),
+            asset_id,
         };
         tx_output.lock(&address)?;
         Ok(tx_output)
     }
 }
 
+impl TXOutputs {
+    pub fn get(&self, vout: i32) -> Option<&TXOutput> {
+        self.indexes
+            .iter()
+            .position(|index| *index == vout)
+            .map(|pos| &self.outputs[pos])
+    }
+}
+
+impl AssetIssuance {
+    /// The id of a freshly issued asset is derived from the first outpoint the
+    /// issuance transaction spends, so it can never be minted twice.
+    pub fn asset_id_for(txid: &str, vout: i32) -> String {
+        sha256::digest(format!("{}:{}", txid, vout))
+    }
+
+    pub fn is_original(&self, tx: &Transaction) -> bool {
+        match tx.vin.first() {
+            Some(vin) => self.asset_id == AssetIssuance::asset_id_for(&vin.txid, vin.vout),
+            None => false,
+        }
+    }
+}
+
 impl Transaction {
     pub fn sign(
         &mut self,
@@ -119,9 +170,23 @@ impl Transaction {
             return Ok(true);
         }
 
+        // an outpoint spent twice would count its value twice
+        if self.has_duplicate_inputs() {
+            return Ok(false);
+        }
         for vin in &self.vin {
-            if prev_TXs.get(&vin.txid).unwrap().id.is_empty() {
-                return Err(format_err!("ERROR: Previous transaction is not correct"));
+            match prev_TXs.get(&vin.txid) {
+                Some(prev_tx) if !prev_tx.id.is_empty() => {
+                    if vin.vout < 0 || vin.vout as usize >= prev_tx.vout.len() {
+                        return Ok(false);
+                    }
+                    // the signature only proves the key in the input, which
+                    // has to be the one the output is locked to
+                    if !vin.use_key(&prev_tx.vout[vin.vout as usize].pub_key_hash) {
+                        return Ok(false);
+                    }
+                }
+                _ => return Err(format_err!("ERROR: Previous transaction is not correct")),
             }
         }
 
@@ -153,7 +218,76 @@ impl Transaction {
             }
         }
 
-        Ok(true)
+        Ok(self.is_balanced(&prev_TXs))
+    }
+
+    /// Sums of every asset spent by the inputs, keyed by asset id. None when
+    /// a value is negative or a sum overflows.
+    pub fn input_sums(
+        &self,
+        prev_TXs: &HashMap<String, Transaction>,
+    ) -> Option<HashMap<String, i32>> {
+        let outputs = self.vin.iter().filter_map(|vin| {
+            prev_TXs
+                .get(&vin.txid)
+                .and_then(|prev_tx| prev_tx.vout.get(vin.vout as usize))
+        });
+        sum_by_asset(outputs)
+    }
+
+    /// Sums of every asset paid by the outputs, none when a value is negative
+    /// or a sum overflows.
+    pub fn output_sums(&self) -> Option<HashMap<String, i32>> {
+        sum_by_asset(self.vout.iter())
+    }
+
+    /// Native coins the inputs pay beyond the outputs, none when the sums are
+    /// out of range.
+    pub fn fee(&self, prev_TXs: &HashMap<String, Transaction>) -> Option<i32> {
+        let native_in = self.input_sums(prev_TXs)?.get("").cloned().unwrap_or(0);
+        let native_out = self.output_sums()?.get("").cloned().unwrap_or(0);
+        native_in.checked_sub(native_out)
+    }
+
+    /// Whether two inputs spend the same outpoint.
+    pub fn has_duplicate_inputs(&self) -> bool {
+        let mut seen = HashSet::new();
+        !self
+            .vin
+            .iter()
+            .all(|vin| seen.insert((vin.txid.as_str(), vin.vout)))
+    }
+
+    /// Native coin outputs may not exceed the inputs, and every other asset
+    /// must balance exactly once the issued amount is added to the inputs.
+    pub fn is_balanced(&self, prev_TXs: &HashMap<String, Transaction>) -> bool {
+        let (mut inputs, outputs) = match (self.input_sums(prev_TXs), self.output_sums()) {
+            (Some(inputs), Some(outputs)) => (inputs, outputs),
+            _ => return false,
+        };
+        if let Some(issuance) = &self.issuance {
+            if issuance.asset_id.is_empty() || issuance.amount <= 0 {
+                return false;
+            }
+            let issued = inputs.entry(issuance.asset_id.clone()).or_insert(0);
+            *issued = match issued.checked_add(issuance.amount) {
+                Some(issued) => issued,
+                None => return false,
+            };
+        }
+
+        let native_in = inputs.remove("").unwrap_or(0);
+        let native_out = outputs.get("").cloned().unwrap_or(0);
+        if native_out > native_in {
+            return false;
+        }
+
+        for (asset_id, amount) in &outputs {
+            if !asset_id.is_empty() && inputs.remove(asset_id) != Some(*amount) {
+                return false;
+            }
+        }
+        inputs.values().all(|v| *v == 0)
     }
 
     pub fn trimmed_copy(&self) -> Self {
@@ -166,6 +300,7 @@ impl Transaction {
                 vout: v.vout.clone(),
                 signature: Vec::new(),
                 pub_key: Vec::new(),
+                sequence: v.sequence,
             })
         }
 
@@ -173,6 +308,7 @@ impl Transaction {
             vout.push(TXOutput {
                 value: v.value,
                 pub_key_hash: v.pub_key_hash.clone(),
+                asset_id: v.asset_id.clone(),
             })
         }
 
@@ -180,12 +316,20 @@ impl Transaction {
             id: self.id.clone(),
             vin,
             vout,
+            issuance: self.issuance.clone(),
         }
     }
 
-    pub fn new_UTXO(from: &str, to: &str, amount: i32, utxo: &UTXOSet) -> Result<Transaction> {
+    pub fn new_UTXO(
+        from: &str,
+        to: &str,
+        asset_id: &str,
+        amount: i32,
+        fee: i32,
+        rbf: bool,
+        utxo: &UTXOSet,
+    ) -> Result<Transaction> {
         info!("new UTXO Transaction from: {} to: {}", from, to);
-        let mut vin = Vec::new();
 
         let wallets = Wallets::new()?;
         let wallet = match wallets.get_wallet(from) {
@@ -193,11 +337,212 @@ impl Transaction {
             None => return Err(format_err!("Wallet not found")),
         };
 
+        let (mut vin, mut vout) = if asset_id.is_empty() {
+            Transaction::spend(wallet, from, "", amount + fee, utxo)?
+        } else {
+            let (mut vin, mut vout) = Transaction::spend(wallet, from, asset_id, amount, utxo)?;
+            if fee > 0 {
+                let (fee_vin, fee_vout) = Transaction::spend(wallet, from, "", fee, utxo)?;
+                vin.extend(fee_vin);
+                vout.extend(fee_vout);
+            }
+            (vin, vout)
+        };
+        vout.insert(
+            0,
+            TXOutput::new_asset(amount, asset_id.to_string(), String::from(to))?,
+        );
+
+        if rbf {
+            for input in &mut vin {
+                input.sequence = SEQUENCE_RBF;
+            }
+        }
+
+        Transaction::build_signed(wallet, vin, vout, None, utxo)
+    }
+
+    /// Builds a replacement for the pending `orig` paying `fee` in total.
+    /// A cancel sends everything back to the sender, otherwise the extra fee
+    /// is taken from the sender's native change output.
+    pub fn new_replacement(
+        orig: &Transaction,
+        fee: Option<i32>,
+        cancel: bool,
+        utxo: &UTXOSet,
+    ) -> Result<Transaction> {
+        info!("new replacement Transaction for: {}", orig.id);
+
+        if !orig.signals_rbf() {
+            return Err(format_err!(
+                "Transaction {} does not signal replace-by-fee",
+                orig.id
+            ));
+        }
+
+        let wallets = Wallets::new()?;
+        let (from, wallet) = match wallets.find_by_pub_key(&orig.vin[0].pub_key) {
+            Some(found) => found,
+            None => return Err(format_err!("Wallet not found")),
+        };
         let pub_key_hash = hash_pub_key(&wallet.public_key);
 
-        let acc_uo = utxo.find_spendable_outputs(&pub_key_hash, amount)?;
+        let prev_TXs = utxo.blockchain.get_prev_TXs(orig)?;
+        let (mut inputs, old_fee) = match (orig.input_sums(&prev_TXs), orig.fee(&prev_TXs)) {
+            (Some(inputs), Some(fee)) => (inputs, fee),
+            _ => {
+                return Err(format_err!(
+                    "Transaction {} amounts are out of range",
+                    orig.id
+                ))
+            }
+        };
+        let fee = fee.unwrap_or(old_fee + 1);
+        if fee <= old_fee {
+            return Err(format_err!(
+                "New fee {} must be higher than the current fee {}",
+                fee,
+                old_fee
+            ));
+        }
+
+        let mut vout = Vec::new();
+        let mut issuance = orig.issuance.clone();
+        if cancel {
+            issuance = None;
+            let native = inputs.remove("").unwrap_or(0) - fee;
+            if native < 1 {
+                return Err(format_err!("Inputs can't cover a fee of {}", fee));
+            }
+            vout.push(TXOutput::new(native, from.clone())?);
+            for (asset_id, amount) in inputs {
+                vout.push(TXOutput::new_asset(amount, asset_id, from.clone())?);
+            }
+        } else {
+            vout = orig.vout.clone();
+            let change = vout
+                .iter_mut()
+                .rev()
+                .find(|out| out.is_native() && out.is_locked_with_key(&pub_key_hash));
+            match change {
+                Some(out) if out.value - (fee - old_fee) >= 1 => out.value -= fee - old_fee,
+                _ => {
+                    return Err(format_err!(
+                        "Transaction {} has no change output to pay a fee of {}",
+                        orig.id,
+                        fee
+                    ))
+                }
+            }
+        }
+
+        let vin = orig
+            .vin
+            .iter()
+            .map(|input| TXInput {
+                txid: input.txid.clone(),
+                vout: input.vout,
+                signature: Vec::new(),
+                pub_key: input.pub_key.clone(),
+                sequence: SEQUENCE_RBF,
+            })
+            .collect();
+
+        Transaction::build_signed(wallet, vin, vout, issuance, utxo)
+    }
+
+    pub fn new_issuance(
+        from: &str,
+        name: &str,
+        amount: i32,
+        reissuable: bool,
+        utxo: &UTXOSet,
+    ) -> Result<Transaction> {
+        info!("new issuance Transaction from: {} asset: {}", from, name);
 
-        if acc_uo.0 < amount {
+        let wallets = Wallets::new()?;
+        let wallet = match wallets.get_wallet(from) {
+            Some(wlt) => wlt,
+            None => return Err(format_err!("Wallet not found")),
+        };
+
+        // spend (and return as change) at least one native coin so the asset id is unique
+        let (vin, mut vout) = Transaction::spend(wallet, from, "", 1, utxo)?;
+        let asset_id = AssetIssuance::asset_id_for(&vin[0].txid, vin[0].vout);
+        vout.push(TXOutput::new_asset(1, String::new(), String::from(from))?);
+        vout.insert(
+            0,
+            TXOutput::new_asset(amount, asset_id.clone(), String::from(from))?,
+        );
+
+        let issuance = AssetIssuance {
+            asset_id,
+            name: name.to_string(),
+            amount,
+            reissuable,
+            issuer_pub_key: wallet.public_key.clone(),
+        };
+        Transaction::build_signed(wallet, vin, vout, Some(issuance), utxo)
+    }
+
+    pub fn new_reissuance(
+        from: &str,
+        asset_id: &str,
+        amount: i32,
+        utxo: &UTXOSet,
+    ) -> Result<Transaction> {
+        info!(
+            "new reissuance Transaction from: {} asset: {}",
+            from, asset_id
+        );
+
+        let wallets = Wallets::new()?;
+        let wallet = match wallets.get_wallet(from) {
+            Some(wlt) => wlt,
+            None => return Err(format_err!("Wallet not found")),
+        };
+
+        let original = match utxo.blockchain.find_issuance(asset_id)? {
+            Some(issuance) => issuance,
+            None => return Err(format_err!("Asset {} is not found", asset_id)),
+        };
+        if !original.reissuable {
+            return Err(format_err!("Asset {} has a fixed supply", asset_id));
+        }
+        if original.issuer_pub_key != wallet.public_key {
+            return Err(format_err!(
+                "Wallet {} is not the issuer of {}",
+                from,
+                asset_id
+            ));
+        }
+
+        let (vin, mut vout) = Transaction::spend(wallet, from, "", 1, utxo)?;
+        vout.push(TXOutput::new_asset(1, String::new(), String::from(from))?);
+        vout.insert(
+            0,
+            TXOutput::new_asset(amount, asset_id.to_string(), String::from(from))?,
+        );
+
+        let issuance = AssetIssuance { amount, ..original };
+        Transaction::build_signed(wallet, vin, vout, Some(issuance), utxo)
+    }
+
+    /// Collects inputs of `asset_id` worth at least `amount` from the wallet,
+    /// returning them together with the change output (if any).
+    fn spend(
+        wallet: &Wallet,
+        from: &str,
+        asset_id: &str,
+        amount: i32,
+        utxo: &UTXOSet,
+    ) -> Result<(Vec<TXInput>, Vec<TXOutput>)> {
+        let mut vin = Vec::new();
+        let pub_key_hash = hash_pub_key(&wallet.public_key);
+
+        let acc_uo = utxo.find_spendable_outputs(&pub_key_hash, asset_id, amount)?;
+
+        if acc_uo.0 < amount || acc_uo.1.is_empty() {
             error!("Not Enough balance");
             return Err(format_err!(
                 "Not Enough balance: current balance {}",
@@ -212,27 +557,43 @@ impl Transaction {
                     vout: out,
                     signature: Vec::new(),
                     pub_key: wallet.public_key.clone(),
+                    sequence: SEQUENCE_FINAL,
                 };
                 vin.push(input);
             }
         }
 
-        let mut vout = vec![TXOutput::new(amount, String::from(to))?];
+        let mut vout = Vec::new();
         if acc_uo.0 > amount {
-            vout.push(TXOutput::new(acc_uo.0 - amount, String::from(from))?)
+            vout.push(TXOutput::new_asset(
+                acc_uo.0 - amount,
+                asset_id.to_string(),
+                String::from(from),
+            )?)
         }
 
+        Ok((vin, vout))
+    }
+
+    fn build_signed(
+        wallet: &Wallet,
+        vin: Vec<TXInput>,
+        vout: Vec<TXOutput>,
+        issuance: Option<AssetIssuance>,
+        utxo: &UTXOSet,
+    ) -> Result<Transaction> {
         let mut tx = Transaction {
             id: String::new(),
             vin,
             vout,
+            issuance,
         };
         tx.set_id()?;
 
         utxo.blockchain.sign_transaction(
             &mut tx,
             <&[u8; 32]>::try_from(wallet.secret_key.as_slice())?,
-        );
+        )?;
 
         Ok(tx)
     }
@@ -250,20 +611,152 @@ impl Transaction {
                 vout: -1,
                 signature: Vec::new(),
                 pub_key: Vec::from(data.as_bytes()),
+                sequence: SEQUENCE_FINAL,
             }],
             vout: vec![TXOutput::new(SUBSIDY, to)?],
+            issuance: None,
         };
         tx.set_id()?;
         Ok(tx)
     }
 
+    pub fn signals_rbf(&self) -> bool {
+        self.vin.iter().any(|vin| vin.sequence < SEQUENCE_FINAL - 1)
+    }
+
     pub fn is_coinbase(&self) -> bool {
         self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == -1
     }
 
+    /// The id `set_id` gives the transaction before it is signed, which the
+    /// signatures don't change.
+    pub fn compute_id(&self) -> Result<String> {
+        let mut copy = self.clone();
+        copy.id = String::new();
+        for vin in &mut copy.vin {
+            vin.signature.clear();
+        }
+        Ok(sha256::digest(serialize(&copy)?))
+    }
+
     pub fn set_id(&mut self) -> Result<()> {
         let hash = sha256::digest(serialize(self)?);
         self.id = hash;
         Ok(())
     }
 }
+
+/// Sums `outputs` by asset id, none when a value is negative or a sum
+/// overflows.
+fn sum_by_asset<'a>(outputs: impl Iterator<Item = &'a TXOutput>) -> Option<HashMap<String, i32>> {
+    let mut sums: HashMap<String, i32> = HashMap::new();
+    for out in outputs {
+        if out.value < 0 {
+            return None;
+        }
+        let sum = sums.entry(out.asset_id.clone()).or_insert(0);
+        *sum = sum.checked_add(out.value)?;
+    }
+    Some(sums)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(value: i32) -> TXOutput {
+        TXOutput {
+            value,
+            pub_key_hash: vec![1; 20],
+            asset_id: String::new(),
+        }
+    }
+
+    fn spending(prev: &Transaction, vouts: &[i32], outputs: Vec<TXOutput>) -> Transaction {
+        let vin = vouts
+            .iter()
+            .map(|vout| TXInput {
+                txid: prev.id.clone(),
+                vout: *vout,
+                signature: Vec::new(),
+                pub_key: Vec::new(),
+                sequence: SEQUENCE_FINAL,
+            })
+            .collect();
+        Transaction {
+            id: String::from("spend"),
+            vin,
+            vout: outputs,
+            issuance: None,
+        }
+    }
+
+    #[test]
+    fn overflowing_outputs_are_out_of_range() {
+        let prev = Transaction {
+            id: String::from("prev"),
+            vin: Vec::new(),
+            vout: vec![output(i32::MAX), output(i32::MAX)],
+            issuance: None,
+        };
+        let tx = spending(&prev, &[0], vec![output(i32::MAX), output(2)]);
+        let prev_TXs = HashMap::from([(prev.id.clone(), prev.clone())]);
+
+        assert!(tx.output_sums().is_none());
+        assert!(tx.fee(&prev_TXs).is_none());
+        assert!(!tx.is_balanced(&prev_TXs));
+
+        let tx = spending(&prev, &[0, 1], vec![output(1)]);
+        assert!(tx.input_sums(&prev_TXs).is_none());
+        assert!(!tx.is_balanced(&prev_TXs));
+    }
+
+    #[test]
+    fn spending_an_outpoint_twice_is_invalid() {
+        let prev = Transaction {
+            id: String::from("prev"),
+            vin: Vec::new(),
+            vout: vec![output(5)],
+            issuance: None,
+        };
+        let tx = spending(&prev, &[0, 0], vec![output(10)]);
+        let prev_TXs = HashMap::from([(prev.id.clone(), prev.clone())]);
+
+        assert!(tx.has_duplicate_inputs());
+        assert!(!tx.verify(prev_TXs).unwrap());
+        assert!(!spending(&prev, &[0], vec![output(5)]).has_duplicate_inputs());
+    }
+
+    #[test]
+    fn signing_keeps_the_id() {
+        let prev = Transaction {
+            id: String::from("prev"),
+            vin: Vec::new(),
+            vout: vec![output(5)],
+            issuance: None,
+        };
+        let mut tx = spending(&prev, &[0], vec![output(5)]);
+        tx.id = String::new();
+        tx.set_id().unwrap();
+        tx.vin[0].signature = vec![7; 64];
+        assert_eq!(tx.compute_id().unwrap(), tx.id);
+
+        tx.vout[0].value = 4;
+        assert_ne!(tx.compute_id().unwrap(), tx.id);
+    }
+
+    #[test]
+    fn negative_outputs_are_out_of_range() {
+        let prev = Transaction {
+            id: String::from("prev"),
+            vin: Vec::new(),
+            vout: vec![output(5)],
+            issuance: None,
+        };
+        let tx = spending(&prev, &[0], vec![output(-5), output(10)]);
+        let prev_TXs = HashMap::from([(prev.id.clone(), prev.clone())]);
+
+        assert!(tx.output_sums().is_none());
+        assert!(!tx.is_balanced(&prev_TXs));
+    }
+}
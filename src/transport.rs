@@ -0,0 +1,82 @@
+use chrono::prelude::*;
+
+use super::*;
+use crate::server::Server;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How a server opens sessions to other nodes. Whatever the transport, the
+/// session ends up registered with `Server::register_peer` and its messages
+/// handed to `Server::handle_event`.
+pub trait Transport: Send + Sync {
+    /// Starts opening a session to `addr` in the background. A node that
+    /// can't be reached is reported with `Server::dial_failed`.
+    fn dial(&self, server: &Server, addr: &str);
+}
+
+/// Sessions over TCP sockets, run on the server's async runtime.
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    fn dial(&self, server: &Server, addr: &str) {
+        let server = server.clone();
+        let addr = addr.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = server.dial(&addr).await {
+                server.dial_failed(&addr, &e);
+            }
+        });
+    }
+}
+
+/// Where the node reads the time from: the system clock, or a virtual one
+/// that only moves when told to.
+#[derive(Debug, Clone)]
+pub enum Clock {
+    System,
+    Virtual {
+        now: Arc<Mutex<Instant>>,
+        // when the virtual clock started, as an instant and in unix seconds
+        start: Instant,
+        start_time: i64,
+    },
+}
+
+impl Clock {
+    pub fn new_virtual() -> Clock {
+        let start = Instant::now();
+        Clock::Virtual {
+            now: Arc::new(Mutex::new(start)),
+            start,
+            start_time: Utc::now().timestamp(),
+        }
+    }
+
+    pub fn now(&self) -> Instant {
+        match self {
+            Clock::System => Instant::now(),
+            Clock::Virtual { now, .. } => *now.lock().unwrap(),
+        }
+    }
+
+    /// Unix time in seconds, what persisted times like bans are kept in.
+    pub fn timestamp(&self) -> i64 {
+        match self {
+            Clock::System => Utc::now().timestamp(),
+            Clock::Virtual {
+                now,
+                start,
+                start_time,
+            } => *start_time + now.lock().unwrap().duration_since(*start).as_secs() as i64,
+        }
+    }
+
+    /// Moves a virtual clock forward, the system clock can't be.
+    pub fn advance(&self, by: Duration) {
+        if let Clock::Virtual { now, .. } = self {
+            *now.lock().unwrap() += by;
+        }
+    }
+}
@@ -5,7 +5,10 @@ use bincode::{deserialize, serialize};
 use sled;
 
 use super::*;
-use crate::{block::Block, transaction::TXOutputs};
+use crate::{
+    block::Block,
+    transaction::{TXOutput, TXOutputs},
+};
 use std::{collections::HashMap, fs, path::Path};
 
 pub struct UTXOSet {
@@ -16,12 +19,13 @@ impl UTXOSet {
     pub fn find_spendable_outputs(
         &self,
         pub_key_hash: &[u8],
+        asset_id: &str,
         amount: i32,
     ) -> Result<(i32, HashMap<String, Vec<i32>>)> {
         let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
         let mut accumulated = 0;
 
-        let db = sled::open("./db/utxos")?;
+        let db = sled::open(self.db_path())?;
 
         for kv in db.iter() {
             let (k, v) = kv?;
@@ -29,12 +33,15 @@ impl UTXOSet {
             let outs: TXOutputs = deserialize(&v.to_vec())?;
 
             for index in 0..outs.outputs.len() {
-                if outs.outputs[index].is_locked_with_key(pub_key_hash) && accumulated < amount {
+                if outs.outputs[index].is_locked_with_key(pub_key_hash)
+                    && outs.outputs[index].asset_id == asset_id
+                    && accumulated < amount
+                {
                     accumulated += outs.outputs[index].value;
                     match unspent_outputs.get_mut(&txid) {
-                        Some(v) => v.push(index as i32),
+                        Some(v) => v.push(outs.indexes[index]),
                         None => {
-                            unspent_outputs.insert(txid.clone(), vec![index as i32]);
+                            unspent_outputs.insert(txid.clone(), vec![outs.indexes[index]]);
                         }
                     }
                 }
@@ -47,16 +54,18 @@ impl UTXOSet {
     pub fn find_UTXO(&self, pub_key_hash: &[u8]) -> Result<TXOutputs> {
         let mut utxos = TXOutputs {
             outputs: Vec::new(),
+            indexes: Vec::new(),
         };
-        let db = sled::open("./db/utxos")?;
+        let db = sled::open(self.db_path())?;
 
         for kv in db.iter() {
             let (k, v) = kv?;
             let outs: TXOutputs = deserialize(&v.to_vec())?;
 
-            for out in outs.outputs {
+            for (out, index) in outs.outputs.into_iter().zip(outs.indexes) {
                 if out.is_locked_with_key(pub_key_hash) {
-                    utxos.outputs.push(out.clone())
+                    utxos.outputs.push(out);
+                    utxos.indexes.push(index);
                 }
             }
         }
@@ -64,9 +73,28 @@ impl UTXOSet {
         Ok(utxos)
     }
 
+    pub fn find_output(&self, txid: &str, vout: i32) -> Result<Option<TXOutput>> {
+        let db = sled::open(self.db_path())?;
+        match db.get(txid)? {
+            Some(v) => {
+                let outs: TXOutputs = deserialize(&v)?;
+                Ok(outs.get(vout).cloned())
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_balances(&self, pub_key_hash: &[u8]) -> Result<HashMap<String, i32>> {
+        let mut balances: HashMap<String, i32> = HashMap::new();
+        for out in self.find_UTXO(pub_key_hash)?.outputs {
+            *balances.entry(out.asset_id).or_insert(0) += out.value;
+        }
+        Ok(balances)
+    }
+
     pub fn count_transactions(&self) -> Result<i32> {
         let mut counter = 0;
-        let db = sled::open("data/utxos")?;
+        let db = sled::open(self.db_path())?;
         for kv in db.iter() {
             kv?;
             counter += 1;
@@ -75,33 +103,36 @@ impl UTXOSet {
     }
 
     pub fn reindex(&self) -> Result<()> {
-        if Path::new("./db/utxos").exists() {
-            fs::remove_dir_all("./db/utxos")?;
+        if Path::new(&self.db_path()).exists() {
+            fs::remove_dir_all(self.db_path())?;
         }
-        let db = sled::open("./db/utxos")?;
+        let db = sled::open(self.db_path())?;
 
         let utxos = self.blockchain.find_UTXO();
 
         for (txid, outs) in utxos {
             db.insert(txid.as_bytes(), serialize(&outs)?)?;
         }
+        db.flush()?;
 
         Ok(())
     }
 
     pub fn update(&self, block: &Block) -> Result<()> {
-        let db = sled::open("./db/utxos")?;
+        let db = sled::open(self.db_path())?;
 
         for tx in block.get_transaction() {
             if !tx.is_coinbase() {
                 for vin in &tx.vin {
                     let mut update_outputs = TXOutputs {
                         outputs: Vec::new(),
+                        indexes: Vec::new(),
                     };
                     let outs: TXOutputs = deserialize(&db.get(&vin.txid)?.unwrap().to_vec())?;
                     for out_idx in 0..outs.outputs.len() {
-                        if out_idx != vin.vout as usize {
+                        if outs.indexes[out_idx] != vin.vout {
                             update_outputs.outputs.push(outs.outputs[out_idx].clone());
+                            update_outputs.indexes.push(outs.indexes[out_idx]);
                         }
                     }
 
@@ -115,14 +146,23 @@ impl UTXOSet {
 
             let mut new_outputs = TXOutputs {
                 outputs: Vec::new(),
+                indexes: Vec::new(),
             };
-            for out in &tx.vout {
+            for (index, out) in tx.vout.iter().enumerate() {
                 new_outputs.outputs.push(out.clone());
+                new_outputs.indexes.push(index as i32);
             }
 
             db.insert(tx.id.as_bytes(), serialize(&new_outputs)?)?;
         }
+        db.flush()?;
 
         Ok(())
     }
+
+    /* -------------------- inner function -------------------- */
+
+    fn db_path(&self) -> String {
+        format!("{}/utxos", self.blockchain.get_dir())
+    }
 }
@@ -8,6 +8,7 @@ use sha256;
 use sled;
 
 use super::*;
+use crate::transaction::Transaction;
 use std::collections::HashMap;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -17,7 +18,7 @@ pub struct Wallet {
 }
 
 impl Wallet {
-    fn new() -> Self {
+    pub fn new() -> Self {
         let mut csprng = OsRng;
         let ed25519_key = SigningKey::generate(&mut csprng);
         let secret_key = ed25519_key.to_bytes().to_vec();
@@ -28,7 +29,7 @@ impl Wallet {
         }
     }
 
-    fn get_address(&self) -> String {
+    pub fn get_address(&self) -> String {
         let mut public_key = self.public_key.clone();
         let pub_key_hash = hash_pub_key(&mut public_key);
 
@@ -102,9 +103,35 @@ impl Wallets {
         Ok(())
     }
 
+    /// Remembers a transaction sent from one of the wallets so it can be
+    /// replaced later on.
+    pub fn save_transaction(&self, tx: &Transaction) -> Result<()> {
+        let db = sled::open("./db/wallets")?;
+        let txs = db.open_tree("transactions")?;
+        txs.insert(tx.id.as_bytes(), serialize(tx)?)?;
+        txs.flush()?;
+        Ok(())
+    }
+
+    pub fn get_transaction(&self, txid: &str) -> Result<Option<Transaction>> {
+        let db = sled::open("./db/wallets")?;
+        let txs = db.open_tree("transactions")?;
+        match txs.get(txid)? {
+            Some(data) => Ok(Some(deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
     // --------- getter ---------
 
     pub fn get_wallet(&self, address: &str) -> Option<&Wallet> {
         self.wallets.get(address)
     }
+
+    pub fn find_by_pub_key(&self, pub_key: &[u8]) -> Option<(String, &Wallet)> {
+        self.wallets
+            .iter()
+            .find(|(_, wallet)| wallet.public_key == pub_key)
+            .map(|(address, wallet)| (address.clone(), wallet))
+    }
 }